@@ -3,7 +3,9 @@ extern crate cc;
 use std::path::Path;
 
 fn main() {
-    cc::Build::new()
-        .file(Path::new("src").join("core").join("tsx.c"))
-        .compile("libtsx.a");
+    let tsx = Path::new("src").join("core").join("tsx.c");
+
+    println!("cargo:rerun-if-changed={}", tsx.display());
+
+    cc::Build::new().file(tsx).compile("libtsx.a");
 }
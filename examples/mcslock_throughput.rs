@@ -0,0 +1,63 @@
+//! Compares the throughput of the MCS lock and the spinlock under contention.
+//!
+//! cargo run --release --example mcslock_throughput
+
+use dpdk::core::mcslock::{McsLock, McsNode};
+use dpdk::core::spinlock::SpinLock;
+use std::pin::pin;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DURATION: Duration = Duration::from_millis(500);
+
+// Counts the acquisitions of `nthread` threads within `dur`.
+fn throughput<F>(nthread: usize, dur: Duration, acquire: F) -> u64
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let acquire = Arc::new(acquire);
+    let barrier = Arc::new(Barrier::new(nthread));
+
+    let threads: Vec<_> = (0..nthread)
+        .map(|_| {
+            let acquire = acquire.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+
+                let start = Instant::now();
+                let mut count = 0;
+                while start.elapsed() < dur {
+                    acquire();
+                    count += 1;
+                }
+                count
+            })
+        })
+        .collect();
+
+    threads.into_iter().map(|t| t.join().unwrap()).sum()
+}
+
+fn main() {
+    for &nthread in &[2, 4, 8, 16, 32] {
+        let mcs = Arc::new(McsLock::new(0u64));
+        let mcs_count = throughput(nthread, DURATION, move || {
+            let mut node = pin!(McsNode::new());
+            *mcs.lock(node.as_mut()) += 1;
+        });
+
+        let spin = Arc::new(SpinLock::new(0u64));
+        let spin_count = throughput(nthread, DURATION, move || {
+            *spin.lock() += 1;
+        });
+
+        println!(
+            "{:2} threads: mcslock {:>10} ops/s, spinlock {:>10} ops/s",
+            nthread,
+            mcs_count * 1000 / DURATION.as_millis() as u64,
+            spin_count * 1000 / DURATION.as_millis() as u64,
+        );
+    }
+}
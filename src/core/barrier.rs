@@ -0,0 +1,355 @@
+//! Spin barriers and latches, for the lcores.
+//!
+//! A `std::sync::Barrier` parks its waiters, and the kernel wakes them one
+//! after the other, tens of microseconds apart. The lcores pinned on their own
+//! CPUs can spin instead: a [`SpinBarrier`] releases them within a cache line
+//! transfer, e.g. to start a benchmark on every lcore at once. A [`Latch`]
+//! lets lcores wait for a count of events, e.g. the setup of the others.
+//!
+//! Both have `wait_timeout` variants, the timeout being measured in TSC
+//! cycles, which fail with `ETIMEDOUT` rather than spin forever on a lost
+//! lcore. The barrier keeps the TSC of its last release, so that each lcore
+//! can measure how late it left.
+//!
+//! # Example
+//! ```
+//! use std::sync::Arc;
+//! use dpdk::core::{barrier::SpinBarrier, cycles, lcore};
+//!
+//! let barrier = Arc::new(SpinBarrier::new(2));
+//! let lcores: Vec<_> = (0..2).map(|_| lcore::spawn::<u64>()).collect();
+//!
+//! let tasks: Vec<_> = lcores
+//!     .iter()
+//!     .map(|lc| {
+//!         let barrier = barrier.clone();
+//!         lc.launch(move || {
+//!             barrier.wait();
+//!             // the launch skew, in TSC cycles
+//!             cycles::get_tsc_cycles().saturating_sub(barrier.released_at())
+//!         })
+//!         .unwrap()
+//!     })
+//!     .collect();
+//!
+//! for task in tasks {
+//!     println!("started {} cycles late", task.wait().unwrap());
+//! }
+//! ```
+//!
+//! [`SpinBarrier`]: struct.SpinBarrier.html
+//! [`Latch`]: struct.Latch.html
+
+use super::cycles::get_tsc_cycles;
+use std::fmt;
+use std::io;
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+fn timed_out() -> io::Error {
+    io::Error::from_raw_os_error(libc::ETIMEDOUT)
+}
+
+// The waiters left in the low half of the barrier word.
+const LEFT_MASK: u64 = 0xffff_ffff;
+
+// The generation in the high half of the barrier word, its parity being the
+// sense of the barrier.
+const GENERATION_ONE: u64 = 1 << 32;
+
+/// A sense-reversing spin barrier, reusable once all its waiters left
+pub struct SpinBarrier {
+    n: u32,
+    // the generation and the waiters left, in one word so that a waiter
+    // giving up sees whether the barrier completed meanwhile
+    state: AtomicU64,
+    released_at: AtomicU64,
+}
+
+impl fmt::Debug for SpinBarrier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.load(Ordering::Relaxed);
+
+        f.debug_struct("SpinBarrier")
+            .field("n", &self.n)
+            .field("waiting", &(self.n as u64 - (state & LEFT_MASK)))
+            .finish()
+    }
+}
+
+impl SpinBarrier {
+    /// Construct the barrier releasing its waiters `n` at a time
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0 or does not fit in 32 bits.
+    pub fn new(n: usize) -> SpinBarrier {
+        assert!(n > 0 && n as u64 <= LEFT_MASK, "invalid barrier count {}", n);
+
+        SpinBarrier {
+            n: n as u32,
+            state: AtomicU64::new(n as u64),
+            released_at: AtomicU64::new(0),
+        }
+    }
+
+    // Arrive, returns the generation waited for, or None for the last one
+    // which released the others.
+    fn arrive(&self) -> Option<u64> {
+        let state = self.state.fetch_sub(1, Ordering::AcqRel);
+
+        if state & LEFT_MASK == 1 {
+            self.release(state);
+            None
+        } else {
+            Some(state & !LEFT_MASK)
+        }
+    }
+
+    // Release the waiters of the generation of `state`, by the last one.
+    fn release(&self, state: u64) {
+        let next = (state & !LEFT_MASK).wrapping_add(GENERATION_ONE) | self.n as u64;
+
+        self.released_at.store(get_tsc_cycles(), Ordering::Relaxed);
+        self.state.store(next, Ordering::Release);
+    }
+
+    fn released(&self, generation: u64) -> bool {
+        self.state.load(Ordering::Acquire) & !LEFT_MASK != generation
+    }
+
+    /// Spin until `n` waiters arrived, returns true for the last one
+    pub fn wait(&self) -> bool {
+        match self.arrive() {
+            None => true,
+            Some(generation) => {
+                while !self.released(generation) {
+                    spin_loop();
+                }
+                false
+            }
+        }
+    }
+
+    /// Spin until `n` waiters arrived, or for `cycles` TSC cycles, returns
+    /// true for the last one
+    ///
+    /// A waiter timing out withdraws, the barrier waits for another one. Once
+    /// the last one arrived, it is too late to withdraw: it waits for the
+    /// release instead.
+    pub fn wait_timeout(&self, cycles: u64) -> io::Result<bool> {
+        let start = get_tsc_cycles();
+
+        let generation = match self.arrive() {
+            None => return Ok(true),
+            Some(generation) => generation,
+        };
+
+        while !self.released(generation) {
+            if get_tsc_cycles().wrapping_sub(start) >= cycles {
+                // withdraw, unless the last one arrived meanwhile: counted
+                // in, it is being released
+                let mut state = self.state.load(Ordering::Relaxed);
+                while state & !LEFT_MASK == generation && state & LEFT_MASK != 0 {
+                    match self.state.compare_exchange_weak(
+                        state,
+                        state + 1,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return Err(timed_out()),
+                        Err(s) => state = s,
+                    }
+                }
+
+                while !self.released(generation) {
+                    spin_loop();
+                }
+                break;
+            }
+
+            spin_loop();
+        }
+
+        Ok(false)
+    }
+
+    /// The TSC of the last release, when the last waiter arrived
+    pub fn released_at(&self) -> u64 {
+        self.released_at.load(Ordering::Relaxed)
+    }
+}
+
+/// A single use countdown latch
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+/// use dpdk::core::barrier::Latch;
+///
+/// let ready = Latch::new(4);
+///
+/// thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| {
+///             // set up the queues
+///             ready.count_down();
+///         });
+///     }
+///
+///     ready.wait();
+///     assert_eq!(ready.count(), 0);
+/// });
+/// ```
+#[derive(Debug)]
+pub struct Latch {
+    count: AtomicUsize,
+}
+
+impl Latch {
+    /// Construct the latch opening after `n` count downs
+    pub const fn new(n: usize) -> Latch {
+        Latch {
+            count: AtomicUsize::new(n),
+        }
+    }
+
+    /// Count down once, opening the latch the `n`th time
+    ///
+    /// # Panics
+    ///
+    /// Panics if the latch is already open.
+    pub fn count_down(&self) {
+        let count = self.count.fetch_sub(1, Ordering::Release);
+        assert!(count > 0, "latch counted down below 0");
+    }
+
+    /// The count downs left
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Test if the latch is open
+    pub fn try_wait(&self) -> bool {
+        self.count.load(Ordering::Acquire) == 0
+    }
+
+    /// Spin until the latch is open
+    pub fn wait(&self) {
+        while !self.try_wait() {
+            spin_loop();
+        }
+    }
+
+    /// Spin until the latch is open, or for `cycles` TSC cycles
+    pub fn wait_timeout(&self, cycles: u64) -> io::Result<()> {
+        let start = get_tsc_cycles();
+
+        while !self.try_wait() {
+            if get_tsc_cycles().wrapping_sub(start) >= cycles {
+                return Err(timed_out());
+            }
+            spin_loop();
+        }
+
+        Ok(())
+    }
+
+    /// Count down once, and spin until the latch is open
+    pub fn arrive_and_wait(&self) {
+        self.count_down();
+        self.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn barrier_rounds() {
+        const NWORKER: usize = 3;
+        const NROUND: usize = 20;
+
+        let barrier = SpinBarrier::new(NWORKER);
+        let arrived = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..NWORKER {
+                s.spawn(|| {
+                    for round in 1..=NROUND {
+                        arrived.fetch_add(1, Ordering::Relaxed);
+                        if barrier.wait() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // nobody left before everyone arrived
+                        assert!(arrived.load(Ordering::Relaxed) >= round * NWORKER);
+                        barrier.wait();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(leaders.load(Ordering::Relaxed), NROUND);
+        assert!(barrier.released_at() > 0);
+    }
+
+    #[test]
+    fn barrier_timeout() {
+        let barrier = SpinBarrier::new(2);
+
+        let e = barrier.wait_timeout(1000).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ETIMEDOUT));
+
+        // the waiter withdrew, the barrier still waits for 2
+        thread::scope(|s| {
+            let other = s.spawn(|| barrier.wait_timeout(u64::MAX).unwrap());
+            let leader = barrier.wait();
+            assert!(leader != other.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn barrier_timeout_race() {
+        let barrier = SpinBarrier::new(2);
+
+        thread::scope(|s| {
+            // long enough to see it arrive, short enough to time out below
+            let waiter = s.spawn(|| barrier.wait_timeout(1 << 27));
+            while barrier.state.load(Ordering::Relaxed) & LEFT_MASK != 1 {
+                thread::yield_now();
+            }
+
+            // the last one arrived, the waiter times out before the release
+            let state = barrier.state.fetch_sub(1, Ordering::AcqRel);
+            thread::sleep(Duration::from_millis(300));
+            barrier.release(state);
+
+            assert!(!waiter.join().unwrap().unwrap());
+        });
+
+        assert_eq!(barrier.state.load(Ordering::Relaxed) & LEFT_MASK, 2);
+    }
+
+    #[test]
+    fn latch_count_down() {
+        let latch = Latch::new(2);
+
+        assert!(!latch.try_wait());
+        let e = latch.wait_timeout(1000).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ETIMEDOUT));
+
+        thread::scope(|s| {
+            s.spawn(|| latch.arrive_and_wait());
+            latch.count_down();
+            latch.wait_timeout(u64::MAX).unwrap();
+        });
+
+        assert_eq!(latch.count(), 0);
+        assert!(latch.try_wait());
+    }
+}
@@ -1,4 +1,7 @@
 use std::arch::x86_64::_rdtsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 #[inline]
 pub fn get_tsc_cycles() -> u64 {
@@ -10,8 +13,33 @@ pub fn get_timer_cycles() -> u64 {
     get_tsc_cycles()
 }
 
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
 
-// TODO get timer hz
+/// The number of timer cycles per second.
+///
+/// It is estimated on the first call, which sleeps for 10ms.
+pub fn get_timer_hz() -> u64 {
+    let hz = TIMER_HZ.load(Ordering::Relaxed);
+    if hz != 0 {
+        return hz;
+    }
+
+    let hz = estimate_tsc_hz();
+    TIMER_HZ.store(hz, Ordering::Relaxed);
+    hz
+}
+
+fn estimate_tsc_hz() -> u64 {
+    let start = Instant::now();
+    let t1 = get_tsc_cycles();
+
+    thread::sleep(Duration::from_millis(10));
+
+    let t2 = get_tsc_cycles();
+    let ns = start.elapsed().as_nanos();
+
+    ((t2 - t1) as u128 * 1_000_000_000 / ns) as u64
+}
 
 // TODO rte delay us
 
@@ -31,4 +59,11 @@ mod tests {
         let t2 = get_tsc_cycles();
         assert!(t1 < t2);
     }
+
+    #[test]
+    fn timer_hz() {
+        let hz = get_timer_hz();
+        assert!(hz > 100_000_000);
+        assert_eq!(get_timer_hz(), hz);
+    }
 }
@@ -0,0 +1,1037 @@
+//! A thread-per-core async executor.
+//!
+//! An [`Executor`] runs futures on the thread calling [`block_on`], typically
+//! a lcore, without ever moving them to another thread. Tasks are spawned with
+//! [`spawn_local`] and do not need to be `Send`.
+//!
+//! The executor drives:
+//!
+//! - timers, on the timer cycles counter: [`sleep`], [`sleep_until`],
+//! - file descriptor readiness, with epoll: [`Async`],
+//! - wakeups from other threads, with an eventfd: a [`Waker`] may be sent to
+//!   another lcore, and a [`Handle`] spawns `Send` tasks from anywhere.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use dpdk::core::executor::{self, Executor};
+//! use dpdk::core::lcore;
+//!
+//! let lc = lcore::spawn::<u32>();
+//!
+//! let res = lc.launch(|| {
+//!     let ex = Executor::new().unwrap();
+//!
+//!     ex.block_on(async {
+//!         let a = executor::spawn_local(async { 1 });
+//!         let b = executor::spawn_local(async {
+//!             executor::sleep(Duration::from_millis(1)).await;
+//!             2
+//!         });
+//!
+//!         a.await + b.await
+//!     })
+//! })
+//! .unwrap()
+//! .wait()
+//! .unwrap();
+//!
+//! assert_eq!(res, 3);
+//! ```
+//!
+//! [`Executor`]: struct.Executor.html
+//! [`block_on`]: struct.Executor.html#method.block_on
+//! [`spawn_local`]: fn.spawn_local.html
+//! [`sleep`]: fn.sleep.html
+//! [`sleep_until`]: fn.sleep_until.html
+//! [`Async`]: struct.Async.html
+//! [`Handle`]: struct.Handle.html
+//! [`Waker`]: https://doc.rust-lang.org/std/task/struct.Waker.html
+
+use crate::core::cycles::{get_timer_cycles, get_timer_hz};
+use crate::core::{close, cvt, read, write};
+use std::io;
+use std::mem;
+use std::cmp::{self, Reverse};
+use std::pin::Pin;
+use std::future::{self, Future};
+use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use std::collections::{BinaryHeap, HashMap};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering, fence};
+use std::task::{Context, Poll, Wake, Waker};
+
+// The id of the future passed to `block_on`.
+const MAIN: usize = usize::MAX;
+
+// The epoll token of the wakeup eventfd.
+const WAKE_TOKEN: u64 = u64::MAX;
+
+const READ: u32 = 1;
+const WRITE: u32 = 2;
+
+const MAX_EVENTS: usize = 64;
+
+type LocalTask = Pin<Box<dyn Future<Output = ()>>>;
+type SendTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Inner>>> = const { RefCell::new(None) };
+}
+
+fn current() -> Rc<Inner> {
+    CURRENT
+        .with(|c| c.borrow().clone())
+        .expect("not called within Executor::block_on")
+}
+
+/// A single-threaded executor.
+///
+/// It is neither `Send` nor `Sync`: create one on the lcore which runs it.
+pub struct Executor {
+    inner: Rc<Inner>,
+}
+
+impl Executor {
+    /// Creates an executor, with its epoll instance and wakeup eventfd.
+    pub fn new() -> io::Result<Executor> {
+        let epfd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let efd = match cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) }) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = close(epfd);
+                return Err(e);
+            }
+        };
+
+        // the shared state owns the eventfd from now on
+        let shared = Arc::new(Shared {
+            ready: Mutex::new(Vec::new()),
+            injected: Mutex::new(Vec::new()),
+            efd,
+            parked: AtomicBool::new(false),
+        });
+
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: WAKE_TOKEN,
+        };
+        if let Err(e) = cvt(unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, efd, &mut ev) }) {
+            let _ = close(epfd);
+            return Err(e);
+        }
+
+        Ok(Executor {
+            inner: Rc::new(Inner {
+                shared,
+                epfd,
+                busy_poll: Cell::new(false),
+                tasks: RefCell::new(Vec::new()),
+                free: RefCell::new(Vec::new()),
+                timers: RefCell::new(BinaryHeap::new()),
+                timer_seq: Cell::new(0),
+                sources: RefCell::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Never sleeps in the kernel when idle, but keeps polling the reactor.
+    ///
+    /// It trades a busy CPU for the wakeup latency, as a dataplane lcore does.
+    pub fn busy_poll(self, enable: bool) -> Executor {
+        self.inner.busy_poll.set(enable);
+        self
+    }
+
+    /// A handle to wake up and spawn tasks on this executor from any thread.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            shared: self.inner.shared.clone(),
+        }
+    }
+
+    /// Runs `fut` to completion on the current thread, along with the tasks
+    /// spawned meanwhile.
+    ///
+    /// The tasks still pending when `fut` completes are kept, and resumed by
+    /// the next `block_on`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called within another `block_on` on the same thread.
+    pub fn block_on<F: Future>(&self, fut: F) -> F::Output {
+        let _enter = Enter::new(&self.inner);
+
+        let mut fut = Box::pin(fut);
+        let main = Arc::new(TaskWaker {
+            id: MAIN,
+            queued: AtomicBool::new(true),
+            shared: self.inner.shared.clone(),
+        });
+        let waker = Waker::from(main.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if main.queued.swap(false, Ordering::AcqRel) {
+                if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+                    return res;
+                }
+            }
+
+            self.inner.run_ready();
+            self.inner.fire_timers();
+
+            if main.queued.load(Ordering::Acquire) || self.inner.shared.has_work() {
+                self.inner.react(0);
+            } else {
+                self.inner.park();
+            }
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // the futures may deregister their sources while dropped
+        let tasks = mem::take(&mut *self.inner.tasks.borrow_mut());
+        drop(tasks);
+    }
+}
+
+// Marks the executor as the current one of the thread.
+struct Enter;
+
+impl Enter {
+    fn new(inner: &Rc<Inner>) -> Enter {
+        CURRENT.with(|c| {
+            let mut c = c.borrow_mut();
+            assert!(c.is_none(), "Executor::block_on called within Executor::block_on");
+            *c = Some(inner.clone());
+        });
+        Enter
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|c| c.borrow_mut().take());
+    }
+}
+
+// The state reachable from other threads.
+struct Shared {
+    ready: Mutex<Vec<usize>>,       // ids of the woken tasks
+    injected: Mutex<Vec<SendTask>>, // tasks spawned via a `Handle`
+    efd: RawFd,                     // wakes up the parked executor
+    parked: AtomicBool,             // the executor waits in `epoll_wait`
+}
+
+impl Shared {
+    fn has_work(&self) -> bool {
+        !self.ready.lock().unwrap().is_empty() || !self.injected.lock().unwrap().is_empty()
+    }
+
+    // Wakes up the executor if it is parked, to be called after queueing work.
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::SeqCst) {
+            let _ = write(self.efd, &1u64.to_ne_bytes());
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let _ = close(self.efd);
+    }
+}
+
+struct TaskWaker {
+    id: usize,
+    queued: AtomicBool, // already in the ready list
+    shared: Arc<Shared>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.shared.ready.lock().unwrap().push(self.id);
+            self.shared.notify();
+        }
+    }
+}
+
+struct Task {
+    future: Option<LocalTask>, // taken while being polled
+    waker: Arc<TaskWaker>,
+}
+
+struct Timer {
+    deadline: u64,
+    seq: u64, // keeps the timers of the same deadline in order
+    waker: TimerWaker,
+}
+
+// The waker of the last poll of a `Sleep`, taken when it fires or is dropped.
+type TimerWaker = Arc<Mutex<Option<Waker>>>;
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        (self.deadline, self.seq) == (other.deadline, other.seq)
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Timer) -> cmp::Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+// A file descriptor registered in the reactor.
+struct Source {
+    fd: RawFd,
+    ready: Cell<u32>, // READ and WRITE readiness seen since the last EAGAIN
+    reader: RefCell<Option<Waker>>,
+    writer: RefCell<Option<Waker>>,
+}
+
+struct Inner {
+    shared: Arc<Shared>,
+    epfd: RawFd,
+    busy_poll: Cell<bool>,
+    tasks: RefCell<Vec<Option<Task>>>,
+    free: RefCell<Vec<usize>>, // free slots in `tasks`
+    timers: RefCell<BinaryHeap<Reverse<Timer>>>,
+    timer_seq: Cell<u64>,
+    sources: RefCell<HashMap<RawFd, Rc<Source>>>,
+}
+
+impl Inner {
+    fn spawn(&self, future: LocalTask) {
+        let mut tasks = self.tasks.borrow_mut();
+
+        let id = self.free.borrow_mut().pop().unwrap_or_else(|| {
+            tasks.push(None);
+            tasks.len() - 1
+        });
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            shared: self.shared.clone(),
+        });
+        tasks[id] = Some(Task {
+            future: Some(future),
+            waker: waker.clone(),
+        });
+        drop(tasks);
+
+        waker.wake();
+    }
+
+    // Polls the tasks woken so far, once each.
+    fn run_ready(&self) {
+        let injected = mem::take(&mut *self.shared.injected.lock().unwrap());
+        for future in injected {
+            self.spawn(future);
+        }
+
+        let ready = mem::take(&mut *self.shared.ready.lock().unwrap());
+        for id in ready {
+            if id == MAIN {
+                continue;
+            }
+
+            let (future, waker) = match self.tasks.borrow_mut().get_mut(id) {
+                Some(Some(task)) => (task.future.take(), task.waker.clone()),
+                _ => continue,
+            };
+            let mut future = match future {
+                Some(future) => future,
+                None => continue,
+            };
+
+            waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(waker);
+            let mut cx = Context::from_waker(&waker);
+
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(()) => {
+                    self.tasks.borrow_mut()[id] = None;
+                    self.free.borrow_mut().push(id);
+                }
+                Poll::Pending => {
+                    if let Some(task) = self.tasks.borrow_mut()[id].as_mut() {
+                        task.future = Some(future);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_timer(&self, deadline: u64, waker: TimerWaker) {
+        let seq = self.timer_seq.get();
+        self.timer_seq.set(seq + 1);
+
+        self.timers.borrow_mut().push(Reverse(Timer { deadline, seq, waker }));
+    }
+
+    fn fire_timers(&self) {
+        let now = get_timer_cycles();
+
+        loop {
+            let timer = {
+                let mut timers = self.timers.borrow_mut();
+                match timers.peek() {
+                    Some(Reverse(t)) if t.deadline <= now => timers.pop().unwrap().0,
+                    _ => return,
+                }
+            };
+            let waker = timer.waker.lock().unwrap().take();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    // The deadline of the next timer, dropping the cancelled ones first.
+    fn next_deadline(&self) -> Option<u64> {
+        let mut timers = self.timers.borrow_mut();
+
+        while let Some(Reverse(t)) = timers.peek() {
+            if t.waker.lock().unwrap().is_some() {
+                return Some(t.deadline);
+            }
+            timers.pop();
+        }
+
+        None
+    }
+
+    // Waits for an event, until the next timer at most.
+    fn park(&self) {
+        let timeout = if self.busy_poll.get() {
+            0
+        } else {
+            match self.next_deadline() {
+                Some(deadline) => cycles_to_ms(deadline.saturating_sub(get_timer_cycles())),
+                None => -1,
+            }
+        };
+
+        self.shared.parked.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+
+        // a waker may have run before `parked` was set
+        let timeout = if self.shared.has_work() { 0 } else { timeout };
+        self.react(timeout);
+
+        self.shared.parked.store(false, Ordering::SeqCst);
+    }
+
+    // Collects the I/O events, waiting `timeout` milliseconds at most.
+    fn react(&self, timeout: i32) {
+        let mut events: [libc::epoll_event; MAX_EVENTS] = unsafe { mem::zeroed() };
+
+        let n = match cvt(unsafe {
+            libc::epoll_wait(self.epfd, events.as_mut_ptr(), MAX_EVENTS as i32, timeout)
+        }) {
+            Ok(n) => n as usize,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => panic!("epoll_wait failed: {}", e),
+        };
+
+        for ev in &events[..n] {
+            let (flags, token) = (ev.events, ev.u64);
+
+            if token == WAKE_TOKEN {
+                let mut buf = [0u8; 8];
+                let _ = read(self.shared.efd, &mut buf);
+                continue;
+            }
+
+            let source = match self.sources.borrow().get(&(token as RawFd)) {
+                Some(source) => source.clone(),
+                None => continue,
+            };
+
+            let err = (libc::EPOLLHUP | libc::EPOLLERR) as u32;
+            let mut ready = source.ready.get();
+            if flags & (libc::EPOLLIN as u32 | libc::EPOLLRDHUP as u32 | err) != 0 {
+                ready |= READ;
+                if let Some(waker) = source.reader.borrow_mut().take() {
+                    waker.wake();
+                }
+            }
+            if flags & (libc::EPOLLOUT as u32 | err) != 0 {
+                ready |= WRITE;
+                if let Some(waker) = source.writer.borrow_mut().take() {
+                    waker.wake();
+                }
+            }
+            source.ready.set(ready);
+        }
+    }
+
+    fn register(&self, fd: RawFd) -> io::Result<Rc<Source>> {
+        unsafe {
+            let flags = cvt(libc::fcntl(fd, libc::F_GETFL))?;
+            cvt(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+        }
+
+        let mut ev = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLRDHUP | libc::EPOLLET) as u32,
+            u64: fd as u64,
+        };
+        cvt(unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd, &mut ev) })?;
+
+        let source = Rc::new(Source {
+            fd,
+            ready: Cell::new(0),
+            reader: RefCell::new(None),
+            writer: RefCell::new(None),
+        });
+        self.sources.borrow_mut().insert(fd, source.clone());
+
+        Ok(source)
+    }
+
+    fn deregister(&self, fd: RawFd) {
+        self.sources.borrow_mut().remove(&fd);
+        unsafe {
+            libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut());
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let _ = close(self.epfd);
+    }
+}
+
+fn cycles_to_ms(cycles: u64) -> i32 {
+    let hz = get_timer_hz() as u128;
+    let ms = (cycles as u128 * 1000).div_ceil(hz);
+
+    cmp::min(ms, i32::MAX as u128) as i32
+}
+
+/// Spawns a task on the current executor.
+///
+/// The task starts once the spawner yields, its result is got by awaiting
+/// the returned [`JoinHandle`]. Dropping the handle detaches the task.
+///
+/// # Panics
+///
+/// Panics if not called within [`Executor::block_on`].
+///
+/// # Examples
+///
+/// ```
+/// use std::rc::Rc;
+/// use std::cell::Cell;
+/// use dpdk::core::executor::{self, Executor};
+///
+/// let ex = Executor::new().unwrap();
+///
+/// let sum = ex.block_on(async {
+///     let count = Rc::new(Cell::new(0));
+///     let tasks: Vec<_> = (0..4)
+///         .map(|i| {
+///             let count = count.clone();
+///             executor::spawn_local(async move {
+///                 count.set(count.get() + 1);
+///                 i
+///             })
+///         })
+///         .collect();
+///
+///     let mut sum = 0;
+///     for task in tasks {
+///         sum += task.await;
+///     }
+///     assert_eq!(count.get(), 4);
+///     sum
+/// });
+///
+/// assert_eq!(sum, 6);
+/// ```
+///
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`Executor::block_on`]: struct.Executor.html#method.block_on
+pub fn spawn_local<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let state = Rc::new(RefCell::new(JoinState {
+        result: None,
+        waker: None,
+    }));
+    let join = state.clone();
+
+    current().spawn(Box::pin(async move {
+        let res = fut.await;
+
+        let mut join = join.borrow_mut();
+        join.result = Some(res);
+        if let Some(waker) = join.waker.take() {
+            waker.wake();
+        }
+    }));
+
+    JoinHandle { state }
+}
+
+struct JoinState<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Awaits the result of a task spawned by [`spawn_local`].
+///
+/// [`spawn_local`]: fn.spawn_local.html
+pub struct JoinHandle<T> {
+    state: Rc<RefCell<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Test if the task has finished
+    pub fn is_finished(&self) -> bool {
+        self.state.borrow().result.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut state = self.state.borrow_mut();
+
+        match state.result.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A `Send` handle to an [`Executor`].
+///
+/// [`Executor`]: struct.Executor.html
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    /// Spawns a task on the executor from any thread, waking it up if needed.
+    ///
+    /// The task is dropped without being run if the executor is.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::thread;
+    /// use std::sync::mpsc;
+    /// use dpdk::core::executor::Executor;
+    ///
+    /// let ex = Executor::new().unwrap();
+    /// let handle = ex.handle();
+    /// let (tx, rx) = mpsc::channel();
+    ///
+    /// let t = thread::spawn(move || {
+    ///     handle.spawn(async move { tx.send(42).unwrap() });
+    /// });
+    ///
+    /// ex.block_on(async {
+    ///     while let Err(_) = rx.try_recv() {
+    ///         dpdk::core::executor::yield_now().await;
+    ///     }
+    /// });
+    /// t.join().unwrap();
+    /// ```
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, fut: F) {
+        self.shared.injected.lock().unwrap().push(Box::pin(fut));
+        self.shared.notify();
+    }
+}
+
+/// Waits until the timer cycles counter reaches `deadline`.
+///
+/// When parked, the executor wakes up with a millisecond granularity, see
+/// [`Executor::busy_poll`] for a finer one.
+///
+/// [`Executor::busy_poll`]: struct.Executor.html#method.busy_poll
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        waker: None,
+    }
+}
+
+/// Waits for `dur`.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use dpdk::core::executor::{self, Executor};
+///
+/// let ex = Executor::new().unwrap();
+/// let start = Instant::now();
+///
+/// ex.block_on(executor::sleep(Duration::from_millis(5)));
+///
+/// assert!(start.elapsed() >= Duration::from_millis(4));
+/// ```
+pub fn sleep(dur: Duration) -> Sleep {
+    let cycles = dur.as_nanos() * get_timer_hz() as u128 / 1_000_000_000;
+
+    sleep_until(get_timer_cycles().saturating_add(cycles as u64))
+}
+
+/// The future returned by [`sleep`] and [`sleep_until`].
+///
+/// [`sleep`]: fn.sleep.html
+/// [`sleep_until`]: fn.sleep_until.html
+pub struct Sleep {
+    deadline: u64,
+    waker: Option<TimerWaker>, // shared with the timer once registered
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if get_timer_cycles() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match self.waker {
+            // only the waker of the last poll is woken
+            Some(ref waker) => {
+                let mut waker = waker.lock().unwrap();
+                if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                    *waker = Some(cx.waker().clone());
+                }
+            }
+            None => {
+                let waker = Arc::new(Mutex::new(Some(cx.waker().clone())));
+                current().add_timer(self.deadline, waker.clone());
+                self.waker = Some(waker);
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    // Cancels the timer, which no longer holds the task.
+    fn drop(&mut self) {
+        if let Some(ref waker) = self.waker {
+            waker.lock().unwrap().take();
+        }
+    }
+}
+
+/// Lets the other ready tasks run before resuming.
+pub async fn yield_now() {
+    let mut yielded = false;
+
+    future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// A file descriptor registered in the reactor of the current executor.
+///
+/// The descriptor is switched to non-blocking mode, and is not closed on drop.
+///
+/// # Examples
+///
+/// ```
+/// use dpdk::core::executor::{self, Async, Executor};
+///
+/// let mut fds = [0; 2];
+/// unsafe { libc::pipe(fds.as_mut_ptr()) };
+///
+/// let ex = Executor::new().unwrap();
+///
+/// let msg = ex.block_on(async {
+///     let rx = Async::new(fds[0]).unwrap();
+///     let tx = Async::new(fds[1]).unwrap();
+///
+///     let reader = executor::spawn_local(async move {
+///         let mut buf = [0u8; 5];
+///         let n = rx.read(&mut buf).await.unwrap();
+///         buf[..n].to_vec()
+///     });
+///
+///     tx.write(b"hello").await.unwrap();
+///     reader.await
+/// });
+///
+/// assert_eq!(msg, b"hello");
+/// # unsafe { libc::close(fds[0]); libc::close(fds[1]); }
+/// ```
+pub struct Async {
+    source: Rc<Source>,
+    inner: Weak<Inner>,
+}
+
+impl Async {
+    /// Registers `fd`.
+    ///
+    /// # Errors
+    ///
+    /// Yields `EEXIST` if `fd` is already registered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called within [`Executor::block_on`].
+    ///
+    /// [`Executor::block_on`]: struct.Executor.html#method.block_on
+    pub fn new(fd: RawFd) -> io::Result<Async> {
+        let inner = current();
+        let source = inner.register(fd)?;
+
+        Ok(Async {
+            source,
+            inner: Rc::downgrade(&inner),
+        })
+    }
+
+    /// Waits until the descriptor is readable.
+    pub async fn readable(&self) {
+        self.ready(READ).await
+    }
+
+    /// Waits until the descriptor is writable.
+    pub async fn writable(&self) {
+        self.ready(WRITE).await
+    }
+
+    /// Reads into `buf` once the descriptor is readable.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            self.readable().await;
+
+            match read(self.source.fd, buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.clear(READ),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+
+    /// Writes `buf` once the descriptor is writable.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            self.writable().await;
+
+            match write(self.source.fd, buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.clear(WRITE),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+
+    // Forgets a readiness, until epoll reports it again.
+    fn clear(&self, interest: u32) {
+        self.source.ready.set(self.source.ready.get() & !interest);
+    }
+
+    async fn ready(&self, interest: u32) {
+        future::poll_fn(|cx| {
+            if self.source.ready.get() & interest != 0 {
+                return Poll::Ready(());
+            }
+
+            let slot = if interest == READ {
+                &self.source.reader
+            } else {
+                &self.source.writer
+            };
+            *slot.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl AsRawFd for Async {
+    fn as_raw_fd(&self) -> RawFd {
+        self.source.fd
+    }
+}
+
+impl Drop for Async {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.deregister(self.source.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::lcore;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn executor_timers_order() {
+        let ex = Executor::new().unwrap();
+        let order = Rc::new(RefCell::new(Vec::new()));
+
+        ex.block_on(async {
+            let tasks: Vec<_> = [3u64, 1, 2]
+                .iter()
+                .map(|&ms| {
+                    let order = order.clone();
+                    spawn_local(async move {
+                        sleep(Duration::from_millis(ms)).await;
+                        order.borrow_mut().push(ms);
+                    })
+                })
+                .collect();
+
+            for task in tasks {
+                task.await;
+            }
+        });
+
+        assert_eq!(*order.borrow(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn executor_sleep_wakers() {
+        struct Count(AtomicUsize);
+
+        impl Wake for Count {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let ex = Executor::new().unwrap();
+        let first = Arc::new(Count(AtomicUsize::new(0)));
+        let last = Arc::new(Count(AtomicUsize::new(0)));
+
+        ex.block_on(async {
+            let mut timer = Box::pin(sleep(Duration::from_millis(1)));
+            for count in [&first, &last] {
+                let waker = Waker::from(count.clone());
+                assert!(timer.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+            }
+
+            // a dropped sleep lets its waker go at once
+            let mut dropped = Box::pin(sleep(Duration::from_secs(60)));
+            let waker = Waker::from(first.clone());
+            assert!(dropped.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+            drop((dropped, waker));
+            assert_eq!(Arc::strong_count(&first), 1);
+
+            sleep(Duration::from_millis(5)).await;
+        });
+
+        assert_eq!(first.0.load(Ordering::Relaxed), 0);
+        assert_eq!(last.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn executor_remote_wakeup() {
+        let lc = lcore::spawn::<u32>();
+
+        let res = lc
+            .launch(|| {
+                let ex = Executor::new().unwrap();
+
+                ex.block_on(async {
+                    // parks until another thread wakes the task up
+                    let mut woken = false;
+                    future::poll_fn(|cx| {
+                        if woken {
+                            return Poll::Ready(());
+                        }
+                        woken = true;
+
+                        let waker = cx.waker().clone();
+                        thread::spawn(move || {
+                            thread::sleep(Duration::from_millis(10));
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    })
+                    .await;
+
+                    lcore::lcore_id().is_some() as u32
+                })
+            })
+            .unwrap()
+            .wait()
+            .unwrap();
+
+        assert_eq!(res, 1);
+    }
+
+    #[test]
+    fn executor_pending_io_dropped() {
+        let mut fds = [0; 2];
+        unsafe { libc::pipe(fds.as_mut_ptr()) };
+
+        let ex = Executor::new().unwrap();
+        let start = Instant::now();
+
+        ex.block_on(async {
+            let rx = Async::new(fds[0]).unwrap();
+            assert_eq!(Async::new(fds[0]).err().unwrap().raw_os_error(), Some(libc::EEXIST));
+
+            // never readable, left pending in the executor
+            spawn_local(async move {
+                let mut buf = [0u8; 1];
+                let _ = rx.read(&mut buf).await;
+            });
+
+            sleep(Duration::from_millis(2)).await;
+        });
+
+        assert!(start.elapsed() >= Duration::from_millis(1));
+        drop(ex);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//! A spin-then-park lock, for the control threads.
+//!
+//! A `SpinLock` waiter burns its CPU until the holder releases the lock, even
+//! when the holder was descheduled, which is fine for the lcores pinned on
+//! their own CPUs, not for the control threads sharing theirs. A futex lock
+//! spins for a bounded number of TSC cycles, then sleeps in `FUTEX_WAIT` until
+//! the holder wakes it on release.
+//!
+//! The lock word is 0 when unlocked, 1 when locked, and 2 when locked with
+//! sleeping waiters, so that an uncontended release does no system call.
+//!
+//! # Example
+//! ```
+//! use dpdk::core::futexlock::FutexLock;
+//!
+//! let lk = FutexLock::new(0);
+//!
+//! *lk.lock() += 1;
+//!
+//! assert_eq!(*lk.lock(), 1);
+//! ```
+//!
+//! The `RawFutexLock` implements `RawLock`, so the code generic over it can
+//! take either lock:
+//!
+//! ```
+//! use dpdk::core::futexlock::RawFutexLock;
+//! use dpdk::core::lock::RawLock;
+//!
+//! let lk = RawFutexLock::with_spin_cycles(0);
+//!
+//! lk.lock();
+//! assert!(!lk.try_lock());
+//! unsafe { lk.unlock() };
+//! ```
+
+use super::cycles::get_tsc_cycles;
+use libc::{syscall, SYS_futex, FUTEX_PRIVATE_FLAG, FUTEX_WAIT, FUTEX_WAKE};
+use std::fmt;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread::panicking;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// locked, and a waiter may be sleeping
+const PARKED: u32 = 2;
+
+/// The TSC cycles a waiter spins before sleeping, by default
+pub const DEFAULT_SPIN_CYCLES: u64 = 20_000;
+
+fn futex_wait(word: &AtomicU32, val: u32) {
+    // returns at once if the word changed meanwhile, or on a signal
+    unsafe {
+        syscall(
+            SYS_futex,
+            word.as_ptr(),
+            FUTEX_WAIT | FUTEX_PRIVATE_FLAG,
+            val,
+            ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+fn futex_wake(word: &AtomicU32, n: i32) {
+    unsafe {
+        syscall(SYS_futex, word.as_ptr(), FUTEX_WAKE | FUTEX_PRIVATE_FLAG, n);
+    }
+}
+
+/// The raw futex lock type
+pub struct RawFutexLock {
+    state: AtomicU32,
+    spin_cycles: u64,
+}
+
+impl Default for RawFutexLock {
+    /// Construct the futex lock with unlocked state
+    fn default() -> Self {
+        RawFutexLock::new()
+    }
+}
+
+impl Drop for RawFutexLock {
+    fn drop(&mut self) {
+        if self.is_locked() && !panicking() {
+            panic!("futexlock still locked");
+        }
+    }
+}
+
+impl RawFutexLock {
+    /// Construct the futex lock with unlocked state
+    pub const fn new() -> Self {
+        RawFutexLock::with_spin_cycles(DEFAULT_SPIN_CYCLES)
+    }
+
+    /// Construct the futex lock with unlocked state, its waiters spinning
+    /// for `cycles` TSC cycles before sleeping
+    pub const fn with_spin_cycles(cycles: u64) -> Self {
+        RawFutexLock {
+            state: AtomicU32::new(UNLOCKED),
+            spin_cycles: cycles,
+        }
+    }
+
+    /// The TSC cycles a waiter spins before sleeping
+    pub fn spin_cycles(&self) -> u64 {
+        self.spin_cycles
+    }
+
+    /// Take the futex lock. Spin, then sleep until the lock is held.
+    pub fn lock(&self) {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+
+        self.lock_contended();
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        let start = get_tsc_cycles();
+
+        // spin while the holder may release it soon, and nobody sleeps
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+
+            if state == UNLOCKED
+                && self
+                    .state
+                    .compare_exchange_weak(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+
+            if state == PARKED || get_tsc_cycles().wrapping_sub(start) >= self.spin_cycles {
+                break;
+            }
+
+            spin_loop();
+        }
+
+        // taken as PARKED, not knowing whether others still sleep
+        while self.state.swap(PARKED, Ordering::Acquire) != UNLOCKED {
+            futex_wait(&self.state, PARKED);
+        }
+    }
+
+    /// Release the futex lock, waking a sleeping waiter
+    pub fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == PARKED {
+            futex_wake(&self.state, 1);
+        }
+    }
+
+    /// Try to take the futex lock
+    pub fn trylock(&self) -> bool {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Acquire) != UNLOCKED
+    }
+}
+
+/// A futex lock protecting a `T`
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use dpdk::core::futexlock::FutexLock;
+///
+/// let lk = Arc::new(FutexLock::new(0));
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let lk = lk.clone();
+///         thread::spawn(move || *lk.lock() += 1)
+///     })
+///     .collect();
+///
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+///
+/// assert_eq!(*lk.lock(), 4);
+/// ```
+pub struct FutexLock<T: ?Sized> {
+    raw: RawFutexLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for FutexLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for FutexLock<T> {}
+
+impl<T> FutexLock<T> {
+    /// Construct the futex lock with unlocked state
+    pub const fn new(data: T) -> Self {
+        FutexLock {
+            raw: RawFutexLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Construct the futex lock with unlocked state, its waiters spinning
+    /// for `cycles` TSC cycles before sleeping
+    pub const fn with_spin_cycles(data: T, cycles: u64) -> Self {
+        FutexLock {
+            raw: RawFutexLock::with_spin_cycles(cycles),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the futex lock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> FutexLock<T> {
+    /// Take the futex lock, released when the guard is dropped
+    pub fn lock(&self) -> FutexLockGuard<'_, T> {
+        self.raw.lock();
+        FutexLockGuard::new(self)
+    }
+
+    /// Try to take the futex lock
+    pub fn try_lock(&self) -> Option<FutexLockGuard<'_, T>> {
+        if self.raw.trylock() {
+            Some(FutexLockGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw futex lock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &RawFutexLock {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for FutexLock<T> {
+    fn default() -> Self {
+        FutexLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for FutexLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("FutexLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("FutexLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// The RAII guard of a [`FutexLock`], releasing it on drop.
+///
+/// [`FutexLock`]: struct.FutexLock.html
+pub struct FutexLockGuard<'a, T: ?Sized> {
+    lock: &'a FutexLock<T>,
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for FutexLockGuard<'_, T> {}
+
+impl<'a, T: ?Sized> FutexLockGuard<'a, T> {
+    fn new(lock: &'a FutexLock<T>) -> Self {
+        FutexLockGuard {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for FutexLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for FutexLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for FutexLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    #[should_panic]
+    fn futexlock_drop_when_locked() {
+        let lk = RawFutexLock::default();
+
+        lk.lock();
+    }
+
+    #[test]
+    fn futexlock_trylock() {
+        let lk = RawFutexLock::default();
+        assert!(!lk.is_locked());
+
+        assert!(lk.trylock());
+        assert!(lk.is_locked());
+        assert!(!lk.trylock());
+
+        lk.unlock();
+        assert!(!lk.is_locked());
+    }
+
+    #[test]
+    fn futexlock_park() {
+        const NWORKER: usize = 4;
+        const NITER: usize = 50;
+
+        // a short spin, so that the waiters sleep while the holder does
+        let lk = Arc::new(FutexLock::with_spin_cycles(0, 1000));
+
+        let threads: Vec<_> = (0..NWORKER)
+            .map(|_| {
+                let lk = lk.clone();
+                thread::spawn(move || {
+                    for _ in 0..NITER {
+                        let mut v = lk.lock();
+                        *v += 1;
+                        thread::sleep(Duration::from_micros(100));
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert!(!lk.is_locked());
+        assert_eq!(*lk.lock(), NWORKER * NITER);
+    }
+}
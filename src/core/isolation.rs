@@ -0,0 +1,302 @@
+//! Isolated-CPU sanity checks for pinned dataplane lcores.
+//!
+//! A dataplane lcore busy polling a device should own its CPU:
+//!
+//! - the CPU should be isolated from the scheduler (`isolcpus=` on the kernel
+//!   command line, or `/sys/devices/system/cpu/isolated`),
+//! - the CPU should run tickless (`nohz_full=`, or
+//!   `/sys/devices/system/cpu/nohz_full`),
+//! - no other dataplane lcore should be pinned on the same CPU, nor on its SMT
+//!   siblings (`cpu<N>/topology/thread_siblings_list`).
+//!
+//! The checks are run by [`Builder::spawn`] for lcores configured with
+//! [`Builder::isolation_check`], they can also be run by hand against any
+//! [`CpuTopology`]:
+//!
+//! ```
+//! use dpdk::core::isolation::{self, CpuTopology, Issue};
+//!
+//! let topo = CpuTopology::parse("isolcpus=2,3 nohz_full=2-3", "", "").smt(&[2, 3]);
+//!
+//! let issues = isolation::check(&[3], &topo, &[(0, vec![2])]);
+//! assert_eq!(issues, [Issue::SmtSibling { cpu: 3, sibling: 2, lcore: 0 }]);
+//! ```
+//!
+//! [`Builder::spawn`]: ../lcore/struct.Builder.html#method.spawn
+//! [`Builder::isolation_check`]: ../lcore/struct.Builder.html#method.isolation_check
+//! [`CpuTopology`]: struct.CpuTopology.html
+
+use super::SYSFS_CPU_ROOT;
+use std::io;
+use std::fs;
+use std::fmt;
+use std::path::Path;
+use std::collections::{BTreeSet, HashMap};
+
+/// The default kernel command line path.
+pub const PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// What to do when a dataplane lcore fails the checks.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IsolationCheck {
+    /// Log every issue as a warning and spawn anyway
+    Warn,
+    /// Refuse to spawn the lcore
+    Fail,
+}
+
+/// A problem found by [`check`].
+///
+/// [`check`]: fn.check.html
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Issue {
+    /// The lcore has no cpu affinity
+    NotPinned,
+    /// The CPU is not isolated from the scheduler
+    NotIsolated(usize),
+    /// The CPU is not running tickless
+    NotNohzFull(usize),
+    /// The CPU is already used by another dataplane lcore
+    DuplicatePin {
+        /// the CPU
+        cpu: usize,
+        /// the lcore id of the other dataplane lcore
+        lcore: usize,
+    },
+    /// An SMT sibling of the CPU is used by another dataplane lcore
+    SmtSibling {
+        /// the CPU
+        cpu: usize,
+        /// the sibling of `cpu` the other lcore is pinned on
+        sibling: usize,
+        /// the lcore id of the other dataplane lcore
+        lcore: usize,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Issue::NotPinned => write!(fmt, "dataplane lcore has no cpu affinity"),
+            Issue::NotIsolated(cpu) => write!(fmt, "cpu {} is not isolated", cpu),
+            Issue::NotNohzFull(cpu) => write!(fmt, "cpu {} is not nohz_full", cpu),
+            Issue::DuplicatePin { cpu, lcore } => {
+                write!(fmt, "cpu {} is already used by lcore {}", cpu, lcore)
+            }
+            Issue::SmtSibling { cpu, sibling, lcore } => write!(
+                fmt,
+                "cpu {} shares a core with cpu {} used by lcore {}",
+                cpu, sibling, lcore
+            ),
+        }
+    }
+}
+
+/// The isolation settings and SMT topology of the CPUs.
+#[derive(Clone, Debug, Default)]
+pub struct CpuTopology {
+    isolated: BTreeSet<usize>,
+    nohz_full: BTreeSet<usize>,
+    siblings: HashMap<usize, Vec<usize>>,
+}
+
+impl CpuTopology {
+    /// Reads the topology of the running system.
+    pub fn read() -> io::Result<CpuTopology> {
+        CpuTopology::read_from(Path::new(PROC_CMDLINE), Path::new(SYSFS_CPU_ROOT))
+    }
+
+    /// Reads the topology from a kernel command line file and a sysfs cpu
+    /// root standing for `/sys/devices/system/cpu`.
+    ///
+    /// The `isolated` and `nohz_full` sysfs files, as well as the per-cpu
+    /// topology, are optional.
+    pub fn read_from(cmdline: &Path, sys_cpu_root: &Path) -> io::Result<CpuTopology> {
+        let cmdline = fs::read_to_string(cmdline)?;
+        let isolated = fs::read_to_string(sys_cpu_root.join("isolated")).unwrap_or_default();
+        let nohz_full = fs::read_to_string(sys_cpu_root.join("nohz_full")).unwrap_or_default();
+
+        let mut topo = CpuTopology::parse(&cmdline, &isolated, &nohz_full);
+
+        if let Ok(entries) = fs::read_dir(sys_cpu_root) {
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let is_cpu = name
+                    .to_str()
+                    .and_then(|n| n.strip_prefix("cpu"))
+                    .is_some_and(|n| n.parse::<usize>().is_ok());
+                if !is_cpu {
+                    continue;
+                }
+
+                let path = entry.path().join("topology").join("thread_siblings_list");
+                if let Some(siblings) = fs::read_to_string(path).ok().and_then(|s| parse_cpulist(&s)) {
+                    topo = topo.smt(&siblings);
+                }
+            }
+        }
+
+        Ok(topo)
+    }
+
+    /// Builds the topology from the contents of the kernel command line and
+    /// of the sysfs `isolated` and `nohz_full` files.
+    pub fn parse(cmdline: &str, isolated: &str, nohz_full: &str) -> CpuTopology {
+        let mut topo = CpuTopology::default();
+
+        for param in cmdline.split_whitespace() {
+            if let Some(value) = param.strip_prefix("isolcpus=") {
+                // isolcpus=[flag,...,]cpulist, the flags are not digits
+                let list = value
+                    .split(',')
+                    .skip_while(|s| !s.starts_with(|c: char| c.is_ascii_digit()))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                topo.isolated.extend(parse_cpulist(&list).unwrap_or_default());
+            } else if let Some(value) = param.strip_prefix("nohz_full=") {
+                topo.nohz_full.extend(parse_cpulist(value).unwrap_or_default());
+            }
+        }
+
+        topo.isolated.extend(parse_cpulist(isolated).unwrap_or_default());
+        topo.nohz_full.extend(parse_cpulist(nohz_full).unwrap_or_default());
+
+        topo
+    }
+
+    /// Declares `siblings` as the hardware threads of one core.
+    pub fn smt(mut self, siblings: &[usize]) -> Self {
+        for &cpu in siblings {
+            self.siblings.insert(cpu, siblings.to_vec());
+        }
+        self
+    }
+
+    /// Test if `cpu` is isolated from the scheduler
+    pub fn is_isolated(&self, cpu: usize) -> bool {
+        self.isolated.contains(&cpu)
+    }
+
+    /// Test if `cpu` runs tickless
+    pub fn is_nohz_full(&self, cpu: usize) -> bool {
+        self.nohz_full.contains(&cpu)
+    }
+
+    /// The SMT siblings of `cpu`, itself excluded
+    pub fn siblings(&self, cpu: usize) -> Vec<usize> {
+        self.siblings
+            .get(&cpu)
+            .map(|s| s.iter().cloned().filter(|&c| c != cpu).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Checks that a dataplane lcore pinned on `cpus` owns them, given the cpus of
+/// the other dataplane lcores as `(lcore id, cpus)` pairs.
+pub fn check(cpus: &[usize], topo: &CpuTopology, pinned: &[(usize, Vec<usize>)]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    if cpus.is_empty() {
+        issues.push(Issue::NotPinned);
+    }
+
+    for &cpu in cpus {
+        if !topo.is_isolated(cpu) {
+            issues.push(Issue::NotIsolated(cpu));
+        }
+        if !topo.is_nohz_full(cpu) {
+            issues.push(Issue::NotNohzFull(cpu));
+        }
+
+        for &(lcore, ref used) in pinned {
+            if used.contains(&cpu) {
+                issues.push(Issue::DuplicatePin { cpu, lcore });
+            }
+            for sibling in topo.siblings(cpu) {
+                if used.contains(&sibling) {
+                    issues.push(Issue::SmtSibling { cpu, sibling, lcore });
+                }
+            }
+        }
+    }
+
+    issues
+}
+
+/// Parses a cpu list such as `0-3,8,10-11`.
+///
+/// Returns `None` if the list is malformed.
+pub fn parse_cpulist(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let lo: usize = bounds.next()?.parse().ok()?;
+        let hi: usize = match bounds.next() {
+            Some(hi) => hi.parse().ok()?,
+            None => lo,
+        };
+        if lo > hi {
+            return None;
+        }
+        cpus.extend(lo..=hi);
+    }
+
+    Some(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::temp_tree;
+    use std::path::PathBuf;
+
+    fn fake_tree(name: &str) -> PathBuf {
+        let root = temp_tree(&format!("isolation-{}", name));
+        let cpu = root.join("cpu");
+
+        for (i, siblings) in ["0,4", "1,5", "2,6", "3,7", "0,4", "1,5", "2,6", "3,7"].iter().enumerate() {
+            let dir = cpu.join(format!("cpu{}", i)).join("topology");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("thread_siblings_list"), format!("{}\n", siblings)).unwrap();
+        }
+
+        fs::write(cpu.join("isolated"), "6-7\n").unwrap();
+        fs::write(cpu.join("nohz_full"), "\n").unwrap();
+        fs::write(root.join("cmdline"), "ro quiet isolcpus=domain,managed_irq,2-3 nohz_full=2,3,6-7\n").unwrap();
+
+        root
+    }
+
+    #[test]
+    fn cpulist() {
+        assert_eq!(parse_cpulist("0-3,8,10-11\n"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpulist(""), Some(vec![]));
+        assert_eq!(parse_cpulist("3-1"), None);
+        assert_eq!(parse_cpulist("a"), None);
+    }
+
+    #[test]
+    fn isolation_topology() {
+        let root = fake_tree("topology");
+        let topo = CpuTopology::read_from(&root.join("cmdline"), &root.join("cpu")).unwrap();
+
+        assert!(!topo.is_isolated(1));
+        assert!(topo.is_isolated(2) && topo.is_isolated(3));
+        assert!(topo.is_isolated(6) && topo.is_isolated(7));
+        assert!(topo.is_nohz_full(2) && topo.is_nohz_full(7));
+        assert!(!topo.is_nohz_full(1));
+        assert_eq!(topo.siblings(2), [6]);
+        assert_eq!(topo.siblings(6), [2]);
+
+        assert_eq!(check(&[2], &topo, &[]), []);
+        assert_eq!(check(&[], &topo, &[]), [Issue::NotPinned]);
+        assert_eq!(check(&[1], &topo, &[]), [Issue::NotIsolated(1), Issue::NotNohzFull(1)]);
+        assert_eq!(check(&[3], &topo, &[(1, vec![2]), (4, vec![3])]),
+                   [Issue::DuplicatePin { cpu: 3, lcore: 4 }]);
+        assert_eq!(check(&[6], &topo, &[(1, vec![2])]),
+                   [Issue::SmtSibling { cpu: 6, sibling: 2, lcore: 1 }]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
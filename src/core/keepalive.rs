@@ -0,0 +1,408 @@
+//! A keepalive watchdog for lcores.
+//!
+//! Every monitored lcore marks itself alive periodically with [`mark_alive`],
+//! which costs a `rdtsc` and a store. A monitor checks the marks against a
+//! deadline measured in TSC cycles:
+//!
+//! - an lcore not seen for more than the `missing` deadline is reported as
+//!   [`State::Missing`],
+//! - an lcore not seen for more than the `dead` deadline is reported as
+//!   [`State::Dead`].
+//!
+//! Each transition to `Missing` or `Dead` is reported once through the
+//! callbacks. An lcore marking itself alive again goes back to `Alive`.
+//!
+//! An lcore about to idle on purpose can [`mark_sleep`] to be ignored until
+//! its next [`mark_alive`].
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use dpdk::core::{keepalive, lcore};
+//!
+//! let ka = Arc::new(
+//!     keepalive::KeepAlive::new(1 << 30, 1 << 32)
+//!         .on_missing(|id| println!("lcore {} is missing", id))
+//!         .on_dead(|id| println!("lcore {} is dead", id)),
+//! );
+//! let monitor = keepalive::Monitor::spawn(ka.clone(), Duration::from_millis(10)).unwrap();
+//!
+//! let lc = lcore::spawn::<()>();
+//! ka.register(lc.lcore_id());
+//!
+//! let worker = ka.clone();
+//! lc.launch(move || {
+//!     for _ in 0..1000 {
+//!         worker.mark_alive();
+//!         // poll some queues
+//!     }
+//! })
+//! .unwrap()
+//! .wait()
+//! .unwrap();
+//!
+//! ka.dump(&mut std::io::stdout()).unwrap();
+//! drop(monitor);
+//! ```
+//!
+//! [`mark_alive`]: struct.KeepAlive.html#method.mark_alive
+//! [`mark_sleep`]: struct.KeepAlive.html#method.mark_sleep
+//! [`State::Missing`]: enum.State.html#variant.Missing
+//! [`State::Dead`]: enum.State.html#variant.Dead
+
+use super::cycles::get_tsc_cycles;
+use super::lcore::{self, MAX_LCORE};
+use std::io;
+use std::fmt;
+use std::array;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+/// The state of a monitored lcore, as last seen by the monitor.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum State {
+    /// Not registered
+    Unused = 0,
+    /// Marked alive within the `missing` deadline
+    Alive,
+    /// Not marked alive within the `missing` deadline
+    Missing,
+    /// Not marked alive within the `dead` deadline
+    Dead,
+    /// Marked asleep, not checked until marked alive again
+    Dozing,
+}
+
+static STATE_NAMES: [&str; 5] = ["UNUSED", "ALIVE", "MISSING", "DEAD", "DOZING"];
+
+impl fmt::Display for State {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(STATE_NAMES[*self as usize])
+    }
+}
+
+impl From<u8> for State {
+    fn from(s: u8) -> Self {
+        match s {
+            0 => State::Unused,
+            1 => State::Alive,
+            2 => State::Missing,
+            3 => State::Dead,
+            4 => State::Dozing,
+            _ => panic!("Unknown keepalive state"),
+        }
+    }
+}
+
+// Written by the lcore, read by the monitor. One cache line per lcore so that
+// marking alive doesn't bounce the lines of the other lcores.
+#[repr(align(64))]
+struct Slot {
+    registered: AtomicBool,
+    asleep: AtomicBool,
+    last_alive: AtomicU64, // tsc of the last mark
+    state: AtomicU8,       // as last seen by the monitor
+}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            registered: AtomicBool::new(false),
+            asleep: AtomicBool::new(false),
+            last_alive: AtomicU64::new(0),
+            state: AtomicU8::new(State::Unused as u8),
+        }
+    }
+}
+
+type Callback = Box<dyn Fn(usize) + Send + Sync>;
+
+/// The keepalive configuration and per-lcore marks.
+pub struct KeepAlive {
+    missing: u64,
+    dead: u64,
+    on_missing: Option<Callback>,
+    on_dead: Option<Callback>,
+    slots: [Slot; MAX_LCORE],
+}
+
+impl KeepAlive {
+    /// Constructs a keepalive reporting lcores not marked alive for `missing`
+    /// cycles as missing, and for `dead` cycles as dead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dead` is smaller than `missing`.
+    pub fn new(missing: u64, dead: u64) -> KeepAlive {
+        assert!(missing <= dead, "dead deadline shorter than missing deadline");
+
+        KeepAlive {
+            missing,
+            dead,
+            on_missing: None,
+            on_dead: None,
+            slots: array::from_fn(|_| Slot::new()),
+        }
+    }
+
+    /// Sets the callback invoked by the monitor with the id of an lcore
+    /// becoming missing.
+    pub fn on_missing<F: Fn(usize) + Send + Sync + 'static>(mut self, f: F) -> KeepAlive {
+        self.on_missing = Some(Box::new(f));
+        self
+    }
+
+    /// Sets the callback invoked by the monitor with the id of an lcore
+    /// becoming dead.
+    pub fn on_dead<F: Fn(usize) + Send + Sync + 'static>(mut self, f: F) -> KeepAlive {
+        self.on_dead = Some(Box::new(f));
+        self
+    }
+
+    /// Starts monitoring the lcore `id`, as if it was just marked alive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not smaller than `MAX_LCORE`.
+    pub fn register(&self, id: usize) {
+        let slot = &self.slots[id];
+
+        slot.last_alive.store(get_tsc_cycles(), Ordering::Relaxed);
+        slot.asleep.store(false, Ordering::Relaxed);
+        slot.state.store(State::Alive as u8, Ordering::Relaxed);
+        slot.registered.store(true, Ordering::Release);
+    }
+
+    /// Stops monitoring the lcore `id`.
+    pub fn unregister(&self, id: usize) {
+        let slot = &self.slots[id];
+
+        slot.registered.store(false, Ordering::Release);
+        slot.state.store(State::Unused as u8, Ordering::Relaxed);
+    }
+
+    /// Marks the calling lcore alive.
+    ///
+    /// Does nothing if the caller is not an lcore.
+    #[inline]
+    pub fn mark_alive(&self) {
+        if let Some(id) = lcore::lcore_id() {
+            let slot = &self.slots[id];
+
+            slot.last_alive.store(get_tsc_cycles(), Ordering::Relaxed);
+            if slot.asleep.load(Ordering::Relaxed) {
+                slot.asleep.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Marks the calling lcore asleep, it is not checked until it marks
+    /// itself alive again.
+    pub fn mark_sleep(&self) {
+        if let Some(id) = lcore::lcore_id() {
+            self.slots[id].asleep.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The state of the lcore `id` as last seen by the monitor.
+    pub fn state(&self, id: usize) -> State {
+        State::from(self.slots[id].state.load(Ordering::Relaxed))
+    }
+
+    /// Checks the marks of every registered lcore and invokes the callbacks of
+    /// the ones which became missing or dead since the previous check.
+    ///
+    /// This is what a [`Monitor`] calls periodically.
+    ///
+    /// [`Monitor`]: struct.Monitor.html
+    pub fn check(&self) {
+        self.check_at(get_tsc_cycles());
+    }
+
+    fn check_at(&self, now: u64) {
+        for (id, slot) in self.slots.iter().enumerate() {
+            if !slot.registered.load(Ordering::Acquire) {
+                continue;
+            }
+
+            let elapsed = now.saturating_sub(slot.last_alive.load(Ordering::Relaxed));
+            let state = if slot.asleep.load(Ordering::Relaxed) {
+                State::Dozing
+            } else if elapsed > self.dead {
+                State::Dead
+            } else if elapsed > self.missing {
+                State::Missing
+            } else {
+                State::Alive
+            };
+
+            // an lcore unregistered meanwhile stays unused
+            let prev = slot.state.load(Ordering::Relaxed);
+            if prev == state as u8
+                || prev == State::Unused as u8
+                || slot
+                    .state
+                    .compare_exchange(prev, state as u8, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_err()
+            {
+                continue;
+            }
+
+            let callback = match state {
+                State::Missing => &self.on_missing,
+                State::Dead => &self.on_dead,
+                _ => &None,
+            };
+            if let Some(f) = callback {
+                f(id);
+            }
+        }
+    }
+
+    /// Dumps the last known state of every registered lcore, one per line:
+    ///
+    /// ```text
+    /// lcore <id>: <state>, last alive <cycles> cycles ago
+    /// ```
+    pub fn dump(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        let now = get_tsc_cycles();
+
+        for (id, slot) in self.slots.iter().enumerate() {
+            if !slot.registered.load(Ordering::Acquire) {
+                continue;
+            }
+
+            let last_alive = slot.last_alive.load(Ordering::Relaxed);
+            writeln!(
+                w,
+                "lcore {}: {}, last alive {} cycles ago",
+                id,
+                State::from(slot.state.load(Ordering::Relaxed)),
+                now.saturating_sub(last_alive)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A thread checking a [`KeepAlive`] periodically, stopped when dropped.
+///
+/// [`KeepAlive`]: struct.KeepAlive.html
+pub struct Monitor {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Monitor {
+    /// Spawns a monitor thread calling [`KeepAlive::check`] every `period`.
+    ///
+    /// [`KeepAlive::check`]: struct.KeepAlive.html#method.check
+    pub fn spawn(ka: Arc<KeepAlive>, period: Duration) -> io::Result<Monitor> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let their_stop = stop.clone();
+
+        let thread = thread::Builder::new()
+            .name("keepalive".into())
+            .spawn(move || {
+                while !their_stop.load(Ordering::Relaxed) {
+                    ka.check();
+                    thread::park_timeout(period);
+                }
+            })?;
+
+        Ok(Monitor {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(t) = self.thread.take() {
+            t.thread().unpark();
+            let _ = t.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn keepalive_transitions() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let missing = events.clone();
+        let dead = events.clone();
+
+        let ka = KeepAlive::new(100, 200)
+            .on_missing(move |id| missing.lock().unwrap().push(("missing", id)))
+            .on_dead(move |id| dead.lock().unwrap().push(("dead", id)));
+
+        assert_eq!(ka.state(3), State::Unused);
+        ka.register(3);
+        let t0 = ka.slots[3].last_alive.load(Ordering::Relaxed);
+
+        ka.check_at(t0 + 50);
+        assert_eq!(ka.state(3), State::Alive);
+
+        ka.check_at(t0 + 150);
+        ka.check_at(t0 + 160);
+        assert_eq!(ka.state(3), State::Missing);
+
+        ka.check_at(t0 + 250);
+        assert_eq!(ka.state(3), State::Dead);
+
+        ka.slots[3].last_alive.store(t0 + 300, Ordering::Relaxed);
+        ka.check_at(t0 + 310);
+        assert_eq!(ka.state(3), State::Alive);
+
+        ka.slots[3].asleep.store(true, Ordering::Relaxed);
+        ka.check_at(t0 + 1000);
+        assert_eq!(ka.state(3), State::Dozing);
+
+        assert_eq!(*events.lock().unwrap(), [("missing", 3), ("dead", 3)]);
+
+        let mut out = Vec::new();
+        ka.dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("lcore 3: DOZING, last alive "));
+        assert_eq!(out.lines().count(), 1);
+    }
+
+    #[test]
+    fn keepalive_stuck_lcore() {
+        let dead = Arc::new(AtomicBool::new(false));
+        let flag = dead.clone();
+
+        let ka = Arc::new(KeepAlive::new(1000, 2000).on_dead(move |_| flag.store(true, Ordering::Relaxed)));
+        let monitor = Monitor::spawn(ka.clone(), Duration::from_millis(1)).unwrap();
+
+        let lc = lcore::spawn::<()>();
+        ka.register(lc.lcore_id());
+
+        let worker = ka.clone();
+        let stuck = dead.clone();
+        let wait = lc
+            .launch(move || {
+                worker.mark_alive();
+                // never marks alive again until reported
+                while !stuck.load(Ordering::Relaxed) {}
+            })
+            .unwrap();
+
+        wait.wait().unwrap();
+        assert_eq!(ka.state(lc.lcore_id()), State::Dead);
+        drop(monitor);
+    }
+}
@@ -14,7 +14,7 @@
 //! ```
 //!
 //! In this example, the spawned lcore runs in a endless loop receiving command
-//! and executing.
+//! and executing, until the returned handle is dropped.
 //!
 //! The parent thread can [`launch`] specific tasks and [`Wait::wait`] for result:
 //!
@@ -34,6 +34,28 @@
 //! The [`launch`] method return a [`Wait`] containing the result produced by
 //! the task or a failure.
 //!
+//! ## Queued lcore
+//!
+//! A [`LCore`] holds a single pending task and [`launch`] fails with `EBUSY`
+//! until it is waited. When several threads feed the same lcore, spawn a
+//! [`QueuedLCore`] instead, each submitted task gets its own [`Completion`]:
+//!
+//! ```
+//! use dpdk::core::lcore;
+//!
+//! let lc = lcore::Builder::new().spawn_queued::<i32>(64).unwrap();
+//!
+//! let task = lc.submit(|| 42).unwrap();
+//! assert_eq!(task.wait().unwrap(), 42);
+//! ```
+//!
+//! ## Cancellation
+//!
+//! A long-running task launched with [`launch_cancellable`] is passed a
+//! [`CancelToken`] to poll, cancelled by [`Wait::cancel`], or by
+//! [`request_stop_all`] which [`install_stop_signals`] ties to `SIGINT` and
+//! `SIGTERM`.
+//!
 //! ## Configuring lcore
 //!
 //! A new lcore can be configured before it is spawned via the [`Builder`] type,
@@ -54,17 +76,34 @@
 //! [`Wait`]: struct.Wait.html
 //! [`Wait::wait`]: struct.Wait.html#method.wait
 //! [`Builder`]: struct.Builder.html
+//! [`LCore`]: struct.LCore.html
+//! [`QueuedLCore`]: struct.QueuedLCore.html
+//! [`Completion`]: struct.Completion.html
+//! [`launch_cancellable`]: struct.LCore.html#method.launch_cancellable
+//! [`CancelToken`]: struct.CancelToken.html
+//! [`Wait::cancel`]: struct.Wait.html#method.cancel
+//! [`request_stop_all`]: fn.request_stop_all.html
+//! [`install_stop_signals`]: fn.install_stop_signals.html
 
-use crate::core::{cvt, read_r, write_r, thread};
+use crate::core::log;
+use crate::core::isolation::{self, IsolationCheck};
+use crate::core::spinlock::SpinLock;
+use crate::core::{close, cvt, cvt_r, read_r, write_r, thread};
 use std::io;
+use std::fmt;
 use std::mem;
 use std::panic;
 use std::result;
+use std::process;
 use std::any::Any;
-use std::sync::Arc;
-use std::cell::UnsafeCell;
+use std::hint::spin_loop;
+use std::ffi::CString;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::VecDeque;
 use std::os::unix::io::RawFd;
-use std::sync::atomic::{AtomicUsize, Ordering, fence, spin_loop_hint};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering, fence};
 
 /// A specialized `Result` type for lcore.
 ///
@@ -101,12 +140,7 @@ pub type Result<T> = result::Result<T, Box<dyn Any + Send + 'static>>;
 /// [`launch`]: struct.LCore.html#method.launch
 /// [`wait`]: struct.Wait.html#method.wait
 pub struct LCore<R> {
-    thread: thread::Thread,  // the native thread
-    send_efd: RawFd,         // communication eventfd with master
-    ack_efd: RawFd,          // communication eventfd with master
-    state: Arc<AtomicUsize>, // thread state
-    func: FuncPacket<R>,     // function to call
-    packet: Packet<R>,       // return value of function
+    ctx: Arc<Context<Mailbox<R>>>,
 }
 
 unsafe impl<R> Send for LCore<R> {}
@@ -115,25 +149,72 @@ unsafe impl<R> Sync for LCore<R> {}
 impl<R> LCore<R> {
     /// Launch a task and returns an `io::Result`.
     pub fn launch<'a, F: FnOnce() -> R + 'static>(&'a self, f: F) -> io::Result<Wait<'a, R>> {
-        if self.state.load(Ordering::Relaxed) != State::WAIT as usize {
+        self.post(Box::new(f), None)
+    }
+
+    /// Launch a task which is passed a [`CancelToken`], and returns an
+    /// `io::Result`.
+    ///
+    /// The task should poll the token and return early once it is cancelled,
+    /// either by [`Wait::cancel`] or by [`request_stop_all`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use dpdk::core::lcore;
+    ///
+    /// let lc = lcore::spawn::<u64>();
+    ///
+    /// let task = lc.launch_cancellable(|token| {
+    ///     let mut polls = 0;
+    ///     while !token.is_cancelled() {
+    ///         polls += 1;
+    ///     }
+    ///     polls
+    /// })
+    /// .unwrap();
+    ///
+    /// task.cancel();
+    /// assert!(task.outcome().unwrap().is_cancelled());
+    /// ```
+    ///
+    /// [`CancelToken`]: struct.CancelToken.html
+    /// [`Wait::cancel`]: struct.Wait.html#method.cancel
+    /// [`request_stop_all`]: fn.request_stop_all.html
+    pub fn launch_cancellable<'a, F>(&'a self, f: F) -> io::Result<Wait<'a, R>>
+    where
+        F: FnOnce(&CancelToken) -> R + 'static,
+    {
+        let token = CancelToken::new();
+        let task = token.clone();
+
+        self.post(Box::new(move || f(&task)), Some(token))
+    }
+
+    fn post(&self, f: Func<R>, token: Option<CancelToken>) -> io::Result<Wait<'_, R>> {
+        let mailbox = &self.ctx.chan;
+
+        if mailbox.state.load(Ordering::Relaxed) != State::Wait as usize {
             return Err(io::Error::from_raw_os_error(libc::EBUSY));
         }
 
         unsafe {
-            (*self.func.0.get()).replace(Box::new(f));
+            (*mailbox.func.get()).replace(f);
+            *mailbox.token.get() = token.clone();
         }
 
         // send message
         let mut dummy = [1u8, 0, 0, 0, 0, 0, 0, 0];
-        write_r(self.send_efd, &dummy)
+        write_r(mailbox.send_efd, &dummy)
             .expect("cannot write on eventfd with slave");
 
         // wait ack
-        read_r(self.ack_efd, &mut dummy)
+        read_r(mailbox.ack_efd, &mut dummy)
             .expect("cannot read on eventfd with slave");
 
         Ok(Wait {
-            lcore: self
+            lcore: self,
+            token,
         })
     }
 
@@ -149,31 +230,349 @@ impl<R> LCore<R> {
     /// assert_ne!(lc0.id(), lc1.id());
     /// ```
     pub fn id(&self) -> u64 {
-        self.thread.id()
+        self.ctx.thread.load(Ordering::Relaxed)
+    }
+
+    /// The lcore id, unique among the alive lcores
+    pub fn lcore_id(&self) -> usize {
+        self.ctx.id.0
+    }
+}
+
+impl<R> Drop for LCore<R> {
+    /// Stops the lcore once its current task, if any, returns.
+    fn drop(&mut self) {
+        self.ctx.request_stop();
+        self.ctx.chan.wake();
     }
 }
 
 /// The continuation of `launch`ed task.
 pub struct Wait<'a, T> {
     lcore: &'a LCore<T>,
+    token: Option<CancelToken>,
 }
 
 impl<'a, T> Wait<'a, T> {
+    /// Requests the task to stop, through the [`CancelToken`] it was passed.
+    ///
+    /// Tasks spawned with [`launch`] ignore it.
+    ///
+    /// [`CancelToken`]: struct.CancelToken.html
+    /// [`launch`]: struct.LCore.html#method.launch
+    pub fn cancel(&self) {
+        if let Some(ref token) = self.token {
+            token.cancel();
+        }
+    }
+
+    /// Wait for lcore task to complete, and tell whether it was cancelled
+    /// by the time it returned.
+    pub fn outcome(&self) -> Result<Outcome<T>> {
+        let (res, cancelled) = self.finish();
+        let res = res?;
+
+        Ok(if cancelled {
+            Outcome::Cancelled(res)
+        } else {
+            Outcome::Completed(res)
+        })
+    }
+
     /// Wait for lcore task to complete
     pub fn wait(&self) -> Result<T> {
-        if self.lcore.state.load(Ordering::Relaxed) == State::WAIT as usize {
-            return Err(Box::new("lcore in WAIT state"));
+        self.finish().0
+    }
+
+    // Waits for the result, and whether the lcore saw the task cancelled.
+    fn finish(&self) -> (Result<T>, bool) {
+        let mailbox = &self.lcore.ctx.chan;
+
+        if mailbox.state.load(Ordering::Relaxed) == State::Wait as usize {
+            return (Err(Box::new("lcore in WAIT state")), false);
+        }
+
+        while mailbox.state.load(Ordering::Relaxed) == State::Running as usize {
+            spin_loop();
+        }
+
+        fence(Ordering::Acquire);
+        mailbox.state.store(State::Wait as usize, Ordering::Relaxed);
+
+        unsafe {
+            (*mailbox.packet.get())
+                .take()
+                .unwrap()
+        }
+    }
+}
+
+/// How a task launched with [`launch_cancellable`] ended.
+///
+/// [`launch_cancellable`]: struct.LCore.html#method.launch_cancellable
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Outcome<T> {
+    /// The task returned without being cancelled
+    Completed(T),
+    /// The task was cancelled before it returned, what it returned is kept
+    Cancelled(T),
+}
+
+impl<T> Outcome<T> {
+    /// Test if the task was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        matches!(*self, Outcome::Cancelled(_))
+    }
+
+    /// The value returned by the task
+    pub fn into_inner(self) -> T {
+        match self {
+            Outcome::Completed(res) | Outcome::Cancelled(res) => res,
         }
+    }
+}
+
+/// A cooperative cancellation flag polled by a running task.
+///
+/// A token is cancelled by [`cancel`], by [`Wait::cancel`] for the task it
+/// was passed to, or by [`request_stop_all`] for every token created before
+/// the call. Polling it costs two relaxed atomic loads.
+///
+/// # Examples
+///
+/// ```
+/// use dpdk::core::lcore::CancelToken;
+///
+/// let token = CancelToken::new();
+/// let clone = token.clone();
+/// assert!(!clone.is_cancelled());
+///
+/// token.cancel();
+/// assert!(clone.is_cancelled());
+/// ```
+///
+/// [`cancel`]: struct.CancelToken.html#method.cancel
+/// [`Wait::cancel`]: struct.Wait.html#method.cancel
+/// [`request_stop_all`]: fn.request_stop_all.html
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    epoch: u64, // the stop epoch when the token was created
+}
+
+static STOP_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+impl CancelToken {
+    /// Creates a token which is not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            epoch: STOP_EPOCH.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Cancels the token and all its clones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Test if the token was cancelled
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || STOP_EPOCH.load(Ordering::Relaxed) != self.epoch
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken::new()
+    }
+}
+
+/// Cancels every [`CancelToken`] created so far.
+///
+/// The tokens created afterwards are not affected, so that the application
+/// may launch its cleanup tasks.
+///
+/// # Examples
+///
+/// ```
+/// use dpdk::core::lcore::{self, CancelToken};
+///
+/// let token = CancelToken::new();
+///
+/// lcore::request_stop_all();
+///
+/// assert!(token.is_cancelled());
+/// assert!(!CancelToken::new().is_cancelled());
+/// ```
+///
+/// [`CancelToken`]: struct.CancelToken.html
+pub fn request_stop_all() {
+    STOP_EPOCH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Installs a `SIGINT` and `SIGTERM` handler calling [`request_stop_all`].
+///
+/// It replaces any previous handler of these signals.
+///
+/// # Examples
+///
+/// ```
+/// use dpdk::core::lcore::{self, CancelToken};
+///
+/// lcore::install_stop_signals().unwrap();
+///
+/// let token = CancelToken::new();
+/// unsafe { libc::raise(libc::SIGTERM) };
+/// assert!(token.is_cancelled());
+/// ```
+///
+/// [`request_stop_all`]: fn.request_stop_all.html
+pub fn install_stop_signals() -> io::Result<()> {
+    extern "C" fn on_signal(_: libc::c_int) {
+        // async-signal-safe, a lock-free atomic
+        request_stop_all();
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        for &sig in &[libc::SIGINT, libc::SIGTERM] {
+            cvt(libc::sigaction(sig, &action, std::ptr::null_mut()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A logical core serving a bounded queue of tasks.
+///
+/// Tasks can be [`submit`]ted from several threads without coordination, each
+/// submission returns its own [`Completion`] to [`wait`] on.
+///
+/// When the queue is full, [`submit`] blocks until a slot is released while
+/// [`try_submit`] rejects the task.
+///
+/// [`submit`]: struct.QueuedLCore.html#method.submit
+/// [`try_submit`]: struct.QueuedLCore.html#method.try_submit
+/// [`Completion`]: struct.Completion.html
+/// [`wait`]: struct.Completion.html#method.wait
+pub struct QueuedLCore<R> {
+    ctx: Arc<Context<TaskQueue<R>>>,
+}
+
+impl<R> QueuedLCore<R> {
+    /// Submits a task, blocking while the queue is full.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use dpdk::core::lcore;
+    ///
+    /// let lc = lcore::Builder::new().spawn_queued::<i32>(1).unwrap();
+    ///
+    /// let tasks: Vec<_> = (0..4).map(|i| lc.submit(move || i * i).unwrap()).collect();
+    ///
+    /// let res: Vec<_> = tasks.into_iter().map(|t| t.wait().unwrap()).collect();
+    /// assert_eq!(res, [0, 1, 4, 9]);
+    /// ```
+    pub fn submit<F: FnOnce() -> R + Send + 'static>(&self, f: F) -> io::Result<Completion<R>> {
+        self.ctx.chan.push(Box::new(f), true)
+    }
+
+    /// Submits a task, or fails with `EAGAIN` if the queue is full.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io;
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use dpdk::core::lcore;
+    ///
+    /// let lc = lcore::Builder::new().spawn_queued::<()>(1).unwrap();
+    ///
+    /// let go = Arc::new(AtomicBool::new(false));
+    /// let flag = go.clone();
+    /// let running = lc.submit(move || while !flag.load(Ordering::Acquire) {}).unwrap();
+    /// while !lc.is_empty() {}
+    ///
+    /// let queued = lc.try_submit(|| ()).unwrap();
+    /// let err = lc.try_submit(|| ()).err().unwrap();
+    /// assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    ///
+    /// go.store(true, Ordering::Release);
+    /// running.wait().unwrap();
+    /// queued.wait().unwrap();
+    /// ```
+    pub fn try_submit<F: FnOnce() -> R + Send + 'static>(&self, f: F) -> io::Result<Completion<R>> {
+        self.ctx.chan.push(Box::new(f), false)
+    }
+
+    /// The maximum number of pending tasks
+    pub fn capacity(&self) -> usize {
+        self.ctx.chan.capacity
+    }
+
+    /// The number of pending tasks, excluding the one being executed
+    pub fn len(&self) -> usize {
+        self.ctx.chan.jobs.lock().len()
+    }
+
+    /// Test if no task is pending
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The native thread id
+    pub fn id(&self) -> u64 {
+        self.ctx.thread.load(Ordering::Relaxed)
+    }
+
+    /// The lcore id, unique among the alive lcores
+    pub fn lcore_id(&self) -> usize {
+        self.ctx.id.0
+    }
+}
+
+impl<R> Drop for QueuedLCore<R> {
+    /// Stops the lcore once the pending tasks are done.
+    fn drop(&mut self) {
+        self.ctx.request_stop();
+        self.ctx.chan.wake();
+    }
+}
+
+/// The completion handle of a task submitted to a [`QueuedLCore`].
+///
+/// [`QueuedLCore`]: struct.QueuedLCore.html
+pub struct Completion<T> {
+    slot: Arc<Slot<T>>,
+}
+
+unsafe impl<T: Send> Send for Completion<T> {}
+unsafe impl<T: Send> Sync for Completion<T> {}
+
+impl<T> Completion<T> {
+    /// Test if the task has finished
+    pub fn is_finished(&self) -> bool {
+        self.slot.done.load(Ordering::Acquire)
+    }
 
-        while self.lcore.state.load(Ordering::Relaxed) == State::RUNNING as usize {
-            spin_loop_hint();
+    /// Wait for the task to complete
+    pub fn wait(self) -> Result<T> {
+        while !self.slot.done.load(Ordering::Relaxed) {
+            spin_loop();
         }
 
         fence(Ordering::Acquire);
-        self.lcore.state.store(State::WAIT as usize, Ordering::Relaxed);
 
         unsafe {
-            (*self.lcore.packet.0.get())
+            (*self.slot.result.get())
                 .take()
                 .unwrap()
         }
@@ -186,10 +585,12 @@ impl<'a, T> Wait<'a, T> {
 ///
 /// Methods can be chained on it in order to configured it.
 ///
-/// The two configuratoins available are:
+/// The configuratoins available are:
 ///
 /// - [`name`]: specifies an associated name for the lcore
 /// - [`affinity`]: specifies the cpu cores which a lcore runs on
+/// - [`on_panic`]: specifies what happens when a task panics
+/// - [`isolation_check`]: checks that a dataplane lcore owns its cpus
 ///
 /// The [`spawn`] method will take ownership of the builder and create an
 /// `io::Result`.
@@ -213,11 +614,15 @@ impl<'a, T> Wait<'a, T> {
 ///
 /// [`name`]: struct.Builder.html#method.name
 /// [`affinity`]: struct.Builder.html#method.affinity
+/// [`on_panic`]: struct.Builder.html#method.on_panic
+/// [`isolation_check`]: struct.Builder.html#method.isolation_check
 /// [`spawn`]: struct.Builder.html#method.spawn
 /// [`lcore::spawn`]: fn.spawn.html
 pub struct Builder {
     name: Option<String>,            // thread's name. Guaranteed to be UTF-8
     cpuset: Option<libc::cpu_set_t>, // cpu set which the thread affinity to
+    on_panic: Option<PanicPolicy>,   // what to do when a task panics
+    isolation: Option<IsolationCheck>, // whether it is a dataplane lcore
 }
 
 impl Builder {
@@ -239,6 +644,8 @@ impl Builder {
         Builder {
             name: None,
             cpuset: None,
+            on_panic: None,
+            isolation: None,
         }
     }
 
@@ -319,21 +726,89 @@ impl Builder {
         self
     }
 
+    /// Sets the [`PanicPolicy`] applied when a task of the new lcore panics.
+    ///
+    /// Without a policy the panic payload is only returned to the waiter.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use dpdk::core::lcore::{self, PanicAction, PanicPolicy};
+    ///
+    /// let lc = lcore::Builder::new()
+    ///     .name("lcore1".into())
+    ///     .on_panic(PanicPolicy::new().action(PanicAction::Respawn))
+    ///     .spawn::<()>()
+    ///     .unwrap();
+    ///
+    /// let before = lc.id();
+    /// assert!(lc.launch(|| panic!("oops")).unwrap().wait().is_err());
+    ///
+    /// // the same lcore keeps serving from a fresh thread
+    /// assert!(lc.launch(|| ()).unwrap().wait().is_ok());
+    /// assert_ne!(lc.id(), before);
+    /// ```
+    ///
+    /// [`PanicPolicy`]: struct.PanicPolicy.html
+    pub fn on_panic(mut self, policy: PanicPolicy) -> Builder {
+        self.on_panic = Some(policy);
+        self
+    }
+
+    /// Marks the new lcore as a dataplane lcore, whose cpus are checked
+    /// against the isolation settings of the kernel when spawning.
+    ///
+    /// The checks report cpus which are not isolated (`isolcpus=`) or not
+    /// tickless (`nohz_full=`), cpus already used by another dataplane lcore,
+    /// and cpus sharing a core with one used by another dataplane lcore.
+    /// [`IsolationCheck::Warn`] logs them, [`IsolationCheck::Fail`] makes
+    /// [`spawn`] fail with `InvalidInput`.
+    ///
+    /// See the [`isolation`] module for details.
+    ///
+    /// ```
+    /// use dpdk::core::isolation::IsolationCheck;
+    /// use dpdk::core::lcore;
+    ///
+    /// let lc = lcore::Builder::new()
+    ///     .affinity(&[0])
+    ///     .isolation_check(IsolationCheck::Warn)
+    ///     .spawn::<()>()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// [`IsolationCheck::Warn`]: ../isolation/enum.IsolationCheck.html#variant.Warn
+    /// [`IsolationCheck::Fail`]: ../isolation/enum.IsolationCheck.html#variant.Fail
+    /// [`spawn`]: struct.Builder.html#method.spawn
+    /// [`isolation`]: ../isolation/index.html
+    pub fn isolation_check(mut self, check: IsolationCheck) -> Builder {
+        self.isolation = Some(check);
+        self
+    }
+
     /// Spawns a new lcore by taking ownership of the [`Builder`], and return an
     /// `io::Result` to [`LCore`].
     ///
     /// The spawned lcore may outlive the caller (unless the caller thread
     /// is the main thread. There's no `join` method because the `lcore` runs
-    /// in a endless loop until the main thread finished).
+    /// in a endless loop until the [`LCore`] is dropped).
     ///
     /// # Errors
     ///
     /// Unlike the [`spawn`] free function, this method yeilds an
     /// `io::Result` to capture any failure when creating the thread at the OS level.
     ///
+    /// Yields `EAGAIN` when [`MAX_LCORE`] lcores are already alive, the
+    /// error of the first failing init callback registered by
+    /// [`callback_register`], and `InvalidInput` when a dataplane lcore fails
+    /// the [`isolation_check`].
+    ///
     /// [`Builder`]: struct.Builder.html
     /// [`LCore`]: struct.LCore.html
     /// [`spawn`]: fn.spawn.html
+    /// [`MAX_LCORE`]: constant.MAX_LCORE.html
+    /// [`callback_register`]: fn.callback_register.html
+    /// [`isolation_check`]: struct.Builder.html#method.isolation_check
     ///
     /// # Panics
     ///
@@ -349,80 +824,813 @@ impl Builder {
     /// let lc = builder.spawn::<()>().unwrap();
     /// ```
     pub fn spawn<R: Send + 'static>(self) -> io::Result<LCore<R>> {
-        unsafe { self.spawn_unchecked() }
+        let (prologue, on_panic, isolation) = self.prologue();
+
+        let ctx = Context::spawn(Mailbox::new()?, prologue, on_panic, isolation)?;
+
+        Ok(LCore { ctx })
     }
 
-    unsafe fn spawn_unchecked<R: Send + 'static>(self) -> io::Result<LCore<R>> {
-        let Builder { name, cpuset } = self;
+    /// Spawns a new lcore serving a bounded queue of `capacity` tasks, and
+    /// return an `io::Result` to [`QueuedLCore`].
+    ///
+    /// Unlike [`LCore`], which holds at most one pending task, a queued lcore
+    /// accepts tasks from any number of threads concurrently and executes them
+    /// in submission order.
+    ///
+    /// # Errors
+    ///
+    /// Yields `EINVAL` if `capacity` is zero, and captures any failure when
+    /// creating the thread at the OS level.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a lcore name was set and it contained null bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use dpdk::core::lcore;
+    ///
+    /// let lc = lcore::Builder::new()
+    ///     .name("queued0".into())
+    ///     .spawn_queued::<u32>(16)
+    ///     .unwrap();
+    ///
+    /// let a = lc.submit(|| 1).unwrap();
+    /// let b = lc.submit(|| 2).unwrap();
+    ///
+    /// assert_eq!(a.wait().unwrap() + b.wait().unwrap(), 3);
+    /// ```
+    ///
+    /// [`QueuedLCore`]: struct.QueuedLCore.html
+    /// [`LCore`]: struct.LCore.html
+    pub fn spawn_queued<R: Send + 'static>(self, capacity: usize) -> io::Result<QueuedLCore<R>> {
+        if capacity == 0 || capacity > u32::MAX as usize {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
 
-        let send_efd = cvt(libc::eventfd(0, libc::EFD_CLOEXEC))?;
-        let ack_efd = cvt(libc::eventfd(0, libc::EFD_CLOEXEC))?;
+        let (prologue, on_panic, isolation) = self.prologue();
 
-        let my_state = Arc::new(AtomicUsize::new(State::WAIT as usize));
-        let their_state = my_state.clone();
+        let ctx = Context::spawn(TaskQueue::new(capacity)?, prologue, on_panic, isolation)?;
 
-        let my_func = Arc::new(UnsafeCell::new(None));
-        let their_func = my_func.clone();
+        Ok(QueuedLCore { ctx })
+    }
 
-        let my_packet = Arc::new(UnsafeCell::new(None));
-        let their_packet = my_packet.clone();
+    // Detaches the per-thread settings from the builder, checking the name
+    // on the caller side so that a bad one panics there.
+    fn prologue(self) -> (Prologue, Option<PanicPolicy>, Option<IsolationCheck>) {
+        let Builder { name, cpuset, on_panic, isolation } = self;
 
-        let main = move || {
-            if let Some(s) = name {
-                libc::pthread_setname_np(libc::pthread_self(), s.as_ptr() as *const _);
-            }
+        let prologue = Prologue {
+            name: name.map(|s| CString::new(s).expect("lcore name contains null bytes")),
+            cpuset,
+        };
+
+        (prologue, on_panic, isolation)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder::new()
+    }
+}
+
+// The settings applied by a lcore thread to itself before serving any task.
+struct Prologue {
+    name: Option<CString>,
+    cpuset: Option<libc::cpu_set_t>,
+}
+
+impl fmt::Display for Prologue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "{:?}", name),
+            None => f.write_str("<unnamed>"),
+        }
+    }
+}
+
+impl Prologue {
+    fn apply(&self) {
+        if let Some(ref name) = self.name {
+            thread::Thread::set_name(name);
+        }
 
-            if let Some(cpuset) = cpuset {
+        if let Some(ref cpuset) = self.cpuset {
+            unsafe {
                 libc::pthread_setaffinity_np(
                     libc::pthread_self(),
-                    mem::size_of_val(&cpuset),
-                    &cpuset as *const _,
+                    mem::size_of_val(cpuset),
+                    cpuset as *const _,
                 );
             }
+        }
+    }
+}
 
-            let mut dummy = [0u8; 8];
-            loop {
-                let func = their_func.clone();
-                let packet = their_packet.clone();
+// The state shared between a lcore handle and the thread serving it.
+//
+// It outlives any single native thread: when a panicked lcore is respawned,
+// the replacement thread picks up the same context.
+struct Context<C> {
+    id: LCoreId,
+    _pin: Option<Pin>,         // the cpus held as a dataplane lcore
+    prologue: Prologue,
+    on_panic: Option<PanicPolicy>,
+    thread: AtomicU64,         // the native thread currently serving
+    stop: AtomicBool,          // set when the handle is dropped
+    ready: Startup,            // outcome of the first thread's init callbacks
+    chan: C,                   // how tasks reach the thread
+}
 
-                // wait command
-                read_r(send_efd, &mut dummy)
-                    .expect("cannot read on eventfd with master");
+// Why a serving loop returned.
+enum Exit {
+    // the handle was dropped
+    Stop,
+    // a task panicked under `PanicAction::Respawn`
+    Respawn,
+}
 
-                their_state.store(State::RUNNING as usize, Ordering::Relaxed);
+// The serving loop of a lcore thread, one per kind of lcore.
+trait Serve: Sized + Send + Sync + 'static {
+    // Serves tasks until the thread has to stop or be respawned.
+    fn serve(&self, ctx: &Context<Self>) -> Exit;
+}
 
-                // send ack
-                write_r(ack_efd, &dummy)
-                    .expect("cannot write on eventfd with master");
+impl<C: Serve> Context<C> {
+    fn new(
+        prologue: Prologue,
+        on_panic: Option<PanicPolicy>,
+        isolation: Option<IsolationCheck>,
+        chan: C,
+    ) -> io::Result<Context<C>> {
+        let id = LCoreId::alloc()?;
+        let pin = match isolation {
+            Some(check) => Some(Pin::claim(&id, &prologue, check)?),
+            None => None,
+        };
 
-                // call the function and store the return value
-                if let Some(f) = (*func.get()).take() {
-                    let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
-                    *packet.get() = Some(result);
-                }
+        Ok(Context {
+            id,
+            _pin: pin,
+            prologue,
+            on_panic,
+            thread: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+            ready: Startup::default(),
+            chan,
+        })
+    }
 
-                fence(Ordering::Release);
+    // Spawns the first thread serving a new context, and waits until the
+    // init callbacks have been run there.
+    fn spawn(
+        chan: C,
+        prologue: Prologue,
+        on_panic: Option<PanicPolicy>,
+        isolation: Option<IsolationCheck>,
+    ) -> io::Result<Arc<Self>> {
+        let ctx = Arc::new(Context::new(prologue, on_panic, isolation, chan)?);
 
-                their_state.store(State::FINISHED as usize, Ordering::Relaxed);
-            }
-        };
+        Context::start(&ctx)?;
+        ctx.ready.wait()?;
 
-        Ok(LCore {
-            thread: thread::Thread::new(
-                thread::DEFAULT_MIN_STACK_SIZE,
-                Box::new(main)
-            )?,
-            send_efd: send_efd,
-            ack_efd: ack_efd,
-            state: my_state,
-            func: FuncPacket(my_func),
-            packet: Packet(my_packet),
-        })
+        Ok(ctx)
     }
-}
 
-/// Spawns a new lcore, returning a [`LCore`].
-///
+    // Spawns a native thread serving this context.
+    fn start(this: &Arc<Self>) -> io::Result<()> {
+        if this.on_panic.is_some() {
+            install_panic_hook();
+        }
+
+        let ctx = this.clone();
+        let t = unsafe {
+            thread::Thread::new(thread::DEFAULT_MIN_STACK_SIZE, Box::new(move || Context::run(ctx)))?
+        };
+        this.thread.store(t.id(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn run(this: Arc<Self>) {
+        this.prologue.apply();
+        this.thread.store(unsafe { libc::pthread_self() }, Ordering::Relaxed);
+        LCORE_ID.with(|id| id.set(Some(this.id.0)));
+
+        if let Some(ref policy) = this.on_panic {
+            PANIC_CAPTURE.with(|c| c.set(Some(policy.capture)));
+        }
+
+        let inited = callbacks::sync(this.id.0);
+        if !this.ready.is_done() {
+            // the spawner is waiting, a failing init vetoes the lcore
+            let failed = inited.is_err();
+            this.ready.done(inited);
+            if failed {
+                callbacks::teardown(this.id.0);
+                return;
+            }
+        } else if let Err(e) = inited {
+            // respawned, there is nobody to report to, retried before next task
+            this.log(log::Level::Error, format_args!("init callback failed: {}", e));
+        }
+
+        loop {
+            let exit = this.chan.serve(&this);
+
+            callbacks::teardown(this.id.0);
+
+            match exit {
+                Exit::Stop => return,
+                Exit::Respawn => match Context::start(&this) {
+                    Ok(()) => return,
+                    Err(e) => {
+                        // keep serving from this thread rather than losing the lcore
+                        this.log(log::Level::Error, format_args!("cannot respawn: {}", e));
+                    }
+                },
+            }
+        }
+    }
+
+    // Calls a task, applying the panic policy if it panics.
+    //
+    // The init callbacks registered since the previous task are run first, if
+    // one of them fails the task is dropped and the error is returned instead.
+    //
+    // Returns the task result and whether the thread has to be respawned.
+    fn call<T, F: FnOnce() -> T>(&self, f: F) -> (Result<T>, bool) {
+        if let Err(e) = callbacks::sync(self.id.0) {
+            return (Err(Box::new(e)), false);
+        }
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
+
+        let respawn = match (&result, &self.on_panic) {
+            (Err(payload), Some(policy)) => policy.apply(&self.prologue, payload.as_ref()),
+            _ => false,
+        };
+
+        (result, respawn)
+    }
+
+    fn log(&self, level: log::Level, args: fmt::Arguments) {
+        log_lcore(&self.id, &self.prologue, level, args);
+    }
+}
+
+fn log_lcore(id: &LCoreId, prologue: &Prologue, level: log::Level, args: fmt::Arguments) {
+    log::logger().log(level, file!(), line!(),
+                      format_args!("lcore {} ({}): {}", id.0, prologue, args));
+}
+
+impl<C> Context<C> {
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::Acquire)
+    }
+
+    // Asks the serving thread to exit once the pending tasks are done, the
+    // channel has to wake it up then.
+    fn request_stop(&self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+// The one-shot report of the first lcore thread to its spawner.
+#[derive(Default)]
+struct Startup {
+    result: Mutex<Option<io::Result<()>>>,
+    cond: Condvar,
+    done: AtomicBool,
+}
+
+impl Startup {
+    fn is_done(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    fn done(&self, result: io::Result<()>) {
+        *self.result.lock().unwrap() = Some(result);
+        self.done.store(true, Ordering::Release);
+        self.cond.notify_one();
+    }
+
+    fn wait(&self) -> io::Result<()> {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            match result.take() {
+                Some(r) => return r,
+                None => result = self.cond.wait(result).unwrap(),
+            }
+        }
+    }
+}
+
+/// The maximum number of lcores alive at the same time.
+pub const MAX_LCORE: usize = 128;
+
+// The lcore id, released when the last thread serving the lcore is gone.
+struct LCoreId(usize);
+
+static LCORE_IDS: Mutex<[bool; MAX_LCORE]> = Mutex::new([false; MAX_LCORE]);
+
+impl LCoreId {
+    fn alloc() -> io::Result<LCoreId> {
+        let mut used = LCORE_IDS.lock().unwrap();
+
+        match used.iter().position(|&u| !u) {
+            Some(id) => {
+                used[id] = true;
+                Ok(LCoreId(id))
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EAGAIN)),
+        }
+    }
+}
+
+impl Drop for LCoreId {
+    fn drop(&mut self) {
+        LCORE_IDS.lock().unwrap()[self.0] = false;
+    }
+}
+
+// The cpus of the alive dataplane lcores, by lcore id.
+static PINS: Mutex<Vec<(usize, Vec<usize>)>> = Mutex::new(Vec::new());
+
+// The cpus of a dataplane lcore, released on drop.
+struct Pin(usize);
+
+impl Pin {
+    // Checks the cpus of a dataplane lcore against the kernel settings and the
+    // other dataplane lcores, then holds them.
+    fn claim(id: &LCoreId, prologue: &Prologue, check: IsolationCheck) -> io::Result<Pin> {
+        let cpus: Vec<usize> = match prologue.cpuset {
+            Some(ref cpuset) => (0..libc::CPU_SETSIZE as usize)
+                .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, cpuset) })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let topo = match isolation::CpuTopology::read() {
+            Ok(topo) => topo,
+            // every cpu would look not isolated
+            Err(e) if check == IsolationCheck::Fail => return Err(e),
+            Err(e) => {
+                log_lcore(id, prologue, log::Level::Warn,
+                          format_args!("cannot read cpu topology: {}", e));
+                isolation::CpuTopology::default()
+            }
+        };
+
+        // the registry is held from the check to the insertion, so that two
+        // lcores spawned concurrently see each other
+        let mut pins = PINS.lock().unwrap();
+
+        let issues = isolation::check(&cpus, &topo, &pins);
+        match check {
+            IsolationCheck::Fail if !issues.is_empty() => {
+                let msg = issues.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            _ => {
+                for issue in &issues {
+                    log_lcore(id, prologue, log::Level::Warn, format_args!("{}", issue));
+                }
+            }
+        }
+
+        pins.push((id.0, cpus));
+
+        Ok(Pin(id.0))
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        PINS.lock().unwrap().retain(|&(id, _)| id != self.0);
+    }
+}
+
+thread_local! {
+    static LCORE_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// The id of the lcore running the caller, in `0..MAX_LCORE`.
+///
+/// Returns `None` when called from a thread which is not a lcore.
+///
+/// # Examples
+///
+/// ```
+/// use dpdk::core::lcore;
+///
+/// assert_eq!(lcore::lcore_id(), None);
+///
+/// let lc = lcore::spawn::<Option<usize>>();
+/// let id = lc.launch(|| lcore::lcore_id()).unwrap().wait().unwrap();
+///
+/// assert_eq!(id, Some(lc.lcore_id()));
+/// ```
+pub fn lcore_id() -> Option<usize> {
+    LCORE_ID.with(|id| id.get())
+}
+
+/// The handle of a registered pair of init/uninit callbacks.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CallbackHandle(u64);
+
+/// Registers callbacks to set up and tear down per-lcore state.
+///
+/// `init` runs on every lcore thread, with its lcore id, before the thread
+/// serves its first task. For the lcores already running, it runs before
+/// their next task. `uninit` runs on the same thread when the lcore stops,
+/// for each lcore whose `init` succeeded.
+///
+/// A failing `init` vetoes the spawn of a new lcore: [`Builder::spawn`]
+/// returns its error. On a running lcore, tasks are refused with the error
+/// as long as `init` keeps failing.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use dpdk::core::lcore;
+///
+/// thread_local! {
+///     static CACHE: Cell<Option<usize>> = Cell::new(None);
+/// }
+///
+/// let handle = lcore::callback_register(
+///     "cache",
+///     |id| {
+///         CACHE.with(|c| c.set(Some(id)));
+///         Ok(())
+///     },
+///     |_| CACHE.with(|c| c.set(None)),
+/// );
+///
+/// let lc = lcore::spawn::<Option<usize>>();
+/// let cache = lc.launch(|| CACHE.with(|c| c.get())).unwrap().wait().unwrap();
+/// assert_eq!(cache, Some(lc.lcore_id()));
+///
+/// lcore::callback_unregister(handle);
+/// ```
+///
+/// [`Builder::spawn`]: struct.Builder.html#method.spawn
+pub fn callback_register<I, U>(name: &str, init: I, uninit: U) -> CallbackHandle
+where
+    I: Fn(usize) -> io::Result<()> + Send + Sync + 'static,
+    U: Fn(usize) + Send + Sync + 'static,
+{
+    CallbackHandle(callbacks::register(name, Box::new(init), Box::new(uninit)))
+}
+
+/// Unregisters callbacks.
+///
+/// No lcore runs `init` afterwards, the running lcores run `uninit` before
+/// their next task or when they stop.
+pub fn callback_unregister(handle: CallbackHandle) {
+    callbacks::unregister(handle.0);
+}
+
+// The registry of lcore init/uninit callbacks.
+//
+// Every lcore thread keeps the callbacks it has initialized in a thread local,
+// and synchronizes it with the registry when the registry generation changed.
+mod callbacks {
+    use std::io;
+    use std::panic;
+    use std::sync::{Arc, Mutex};
+    use std::cell::{Cell, RefCell};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    pub type Init = Box<dyn Fn(usize) -> io::Result<()> + Send + Sync>;
+    pub type Uninit = Box<dyn Fn(usize) + Send + Sync>;
+
+    struct Callback {
+        id: u64,
+        name: String,
+        init: Init,
+        uninit: Uninit,
+    }
+
+    static REGISTRY: Mutex<Vec<Arc<Callback>>> = Mutex::new(Vec::new());
+    static GENERATION: AtomicUsize = AtomicUsize::new(0);
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    thread_local! {
+        static INITED: RefCell<Vec<Arc<Callback>>> = const { RefCell::new(Vec::new()) };
+        // the registry generation this thread is synchronized with
+        static SYNCED: Cell<usize> = const { Cell::new(usize::MAX) };
+    }
+
+    pub fn register(name: &str, init: Init, uninit: Uninit) -> u64 {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+        REGISTRY.lock().unwrap().push(Arc::new(Callback {
+            id,
+            name: name.into(),
+            init,
+            uninit,
+        }));
+        GENERATION.fetch_add(1, Ordering::Release);
+
+        id
+    }
+
+    pub fn unregister(id: u64) {
+        REGISTRY.lock().unwrap().retain(|cb| cb.id != id);
+        GENERATION.fetch_add(1, Ordering::Release);
+    }
+
+    // Runs `uninit` for the unregistered callbacks then `init` for the newly
+    // registered ones.
+    pub fn sync(lcore_id: usize) -> io::Result<()> {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if SYNCED.with(|s| s.get()) == generation {
+            return Ok(());
+        }
+
+        // don't hold the registry while calling out
+        let registered = REGISTRY.lock().unwrap().clone();
+
+        INITED.with(|inited| {
+            let mut inited = inited.borrow_mut();
+
+            for i in (0..inited.len()).rev() {
+                if !registered.iter().any(|cb| cb.id == inited[i].id) {
+                    let cb = inited.remove(i);
+                    (cb.uninit)(lcore_id);
+                }
+            }
+
+            for cb in registered {
+                if inited.iter().any(|done| done.id == cb.id) {
+                    continue;
+                }
+
+                match panic::catch_unwind(panic::AssertUnwindSafe(|| (cb.init)(lcore_id))) {
+                    Ok(Ok(())) => inited.push(cb),
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => {
+                        return Err(io::Error::other(format!("init callback {:?} panicked", cb.name)));
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        SYNCED.with(|s| s.set(generation));
+        Ok(())
+    }
+
+    // Runs `uninit` for every initialized callback, last initialized first.
+    pub fn teardown(lcore_id: usize) {
+        INITED.with(|inited| {
+            while let Some(cb) = inited.borrow_mut().pop() {
+                (cb.uninit)(lcore_id);
+            }
+        });
+        SYNCED.with(|s| s.set(usize::MAX));
+    }
+}
+
+/// What becomes of a lcore after one of its tasks panicked.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PanicAction {
+    /// Keep serving tasks on the same thread
+    Continue,
+    /// Replace the thread by a fresh one with the same name and affinity
+    Respawn,
+    /// Abort the whole process
+    Abort,
+}
+
+/// The information about a panicked task handed to a [`PanicPolicy`] callback.
+///
+/// [`PanicPolicy`]: struct.PanicPolicy.html
+pub struct PanicReport<'a> {
+    lcore: Option<&'a str>,
+    message: Option<&'a str>,
+    location: Option<&'a str>,
+    backtrace: Option<&'a Backtrace>,
+}
+
+impl<'a> PanicReport<'a> {
+    /// The name of the lcore, if it has one
+    pub fn lcore(&self) -> Option<&'a str> {
+        self.lcore
+    }
+
+    /// The panic message, if the payload is a string
+    pub fn message(&self) -> Option<&'a str> {
+        self.message
+    }
+
+    /// The `file:line:column` the panic originated from
+    pub fn location(&self) -> Option<&'a str> {
+        self.location
+    }
+
+    /// The backtrace captured at the panic site, if enabled by the policy
+    pub fn backtrace(&self) -> Option<&'a Backtrace> {
+        self.backtrace
+    }
+}
+
+/// Panic handling policy of a lcore, set via [`Builder::on_panic`].
+///
+/// Whatever the policy, the panic payload is still returned to the waiter of
+/// the task.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use dpdk::core::lcore::{self, PanicPolicy};
+///
+/// let panics = Arc::new(AtomicUsize::new(0));
+/// let counter = panics.clone();
+///
+/// let policy = PanicPolicy::new()
+///     .backtrace(true)
+///     .log(false)
+///     .callback(move |report| {
+///         assert_eq!(report.message(), Some("oops"));
+///         assert!(report.backtrace().is_some());
+///         counter.fetch_add(1, Ordering::Relaxed);
+///     });
+///
+/// let lc = lcore::Builder::new().on_panic(policy).spawn::<()>().unwrap();
+///
+/// assert!(lc.launch(|| panic!("oops")).unwrap().wait().is_err());
+/// assert_eq!(panics.load(Ordering::Relaxed), 1);
+/// ```
+///
+/// [`Builder::on_panic`]: struct.Builder.html#method.on_panic
+pub struct PanicPolicy {
+    capture: Capture,
+    callback: Option<PanicCallback>,
+    action: PanicAction,
+}
+
+impl Default for PanicPolicy {
+    fn default() -> Self {
+        PanicPolicy::new()
+    }
+}
+
+impl PanicPolicy {
+    /// The default policy: log the panic without backtrace, then keep serving.
+    pub fn new() -> PanicPolicy {
+        PanicPolicy {
+            capture: Capture {
+                backtrace: false,
+                log: true,
+            },
+            callback: None,
+            action: PanicAction::Continue,
+        }
+    }
+
+    /// Captures a backtrace at the panic site.
+    pub fn backtrace(mut self, enable: bool) -> PanicPolicy {
+        self.capture.backtrace = enable;
+        self
+    }
+
+    /// Logs the panic through [`core::log`] on `stderr` instead of the
+    /// default panic message.
+    ///
+    /// [`core::log`]: ../log/index.html
+    pub fn log(mut self, enable: bool) -> PanicPolicy {
+        self.capture.log = enable;
+        self
+    }
+
+    /// Invokes `f` on the lcore thread for every panicked task, before the
+    /// [`PanicAction`] is taken.
+    ///
+    /// [`PanicAction`]: enum.PanicAction.html
+    pub fn callback<F: Fn(&PanicReport) + Send + Sync + 'static>(mut self, f: F) -> PanicPolicy {
+        self.callback = Some(Box::new(f));
+        self
+    }
+
+    /// Sets what becomes of the lcore after the panic.
+    pub fn action(mut self, action: PanicAction) -> PanicPolicy {
+        self.action = action;
+        self
+    }
+
+    // Returns whether the thread has to be respawned.
+    fn apply(&self, prologue: &Prologue, payload: &(dyn Any + Send)) -> bool {
+        let (location, backtrace) = PANIC_SITE
+            .with(|site| site.borrow_mut().take())
+            .unwrap_or((None, None));
+
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()));
+
+        let report = PanicReport {
+            lcore: prologue.name.as_ref().and_then(|s| s.to_str().ok()),
+            message,
+            location: location.as_deref(),
+            backtrace: backtrace.as_ref(),
+        };
+
+        if self.capture.log {
+            log::logger().log(log::Level::Crit, file!(), line!(), format_args!(
+                "lcore {} panicked at {}: {}{}{}",
+                prologue,
+                report.location.unwrap_or("<unknown>"),
+                report.message.unwrap_or("Box<dyn Any>"),
+                if report.backtrace.is_some() { "\n" } else { "" },
+                DisplayOpt(report.backtrace),
+            ));
+        }
+
+        if let Some(ref f) = self.callback {
+            f(&report);
+        }
+
+        match self.action {
+            PanicAction::Continue => false,
+            PanicAction::Respawn => true,
+            PanicAction::Abort => process::abort(),
+        }
+    }
+}
+
+type PanicCallback = Box<dyn Fn(&PanicReport) + Send + Sync>;
+
+// What the panic hook records on a lcore thread with a panic policy.
+#[derive(Copy, Clone)]
+struct Capture {
+    backtrace: bool,
+    log: bool,
+}
+
+struct DisplayOpt<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for DisplayOpt<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(ref t) => t.fmt(f),
+            None => Ok(()),
+        }
+    }
+}
+
+type PanicSite = (Option<String>, Option<Backtrace>);
+
+thread_local! {
+    // Set on lcore threads spawned with a panic policy.
+    static PANIC_CAPTURE: Cell<Option<Capture>> = const { Cell::new(None) };
+    // The location and backtrace of the last panic, recorded by the hook.
+    static PANIC_SITE: RefCell<Option<PanicSite>> = const { RefCell::new(None) };
+}
+
+// Chains a panic hook recording the panic site on lcore threads which have a
+// panic policy. The backtrace can only be captured there since the stack is
+// already unwound when `catch_unwind` returns.
+fn install_panic_hook() {
+    static ONCE: Once = Once::new();
+
+    ONCE.call_once(|| {
+        let prev = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            let capture = PANIC_CAPTURE.with(|c| c.get());
+
+            if let Some(capture) = capture {
+                let location = info.location().map(|l| l.to_string());
+                let backtrace = if capture.backtrace {
+                    Some(Backtrace::force_capture())
+                } else {
+                    None
+                };
+                PANIC_SITE.with(|site| *site.borrow_mut() = Some((location, backtrace)));
+
+                if capture.log {
+                    return;
+                }
+            }
+
+            prev(info);
+        }));
+    });
+}
+
+/// Spawns a new lcore, returning a [`LCore`].
+///
 /// [`LCore`]: struct.LCore.html
 pub fn spawn<R: Send + 'static>() -> LCore<R> {
     Builder::new().spawn().expect("failed to spawn lcore")
@@ -432,27 +1640,524 @@ pub fn spawn<R: Send + 'static>() -> LCore<R> {
 #[repr(usize)]
 enum State {
     /// waiting a new command
-    WAIT,
+    Wait,
     /// executing command
-    RUNNING,
+    Running,
     /// command executed
-    FINISHED,
+    Finished,
+}
+
+// The single task slot of a `LCore`, shared by the parent thread and the
+// lcore thread through the `Context`.
+//
+// The task is handed over via `eventfd`. There is no need for a mutex around
+// the return value because synchronization happens with `wait()`.
+struct Mailbox<R> {
+    send_efd: RawFd,                        // communication eventfd with master
+    ack_efd: RawFd,                         // communication eventfd with master
+    state: AtomicUsize,                     // thread state
+    func: UnsafeCell<Option<Func<R>>>,      // function to call
+    token: UnsafeCell<Option<CancelToken>>, // token of a cancellable function
+    packet: UnsafeCell<Option<(Result<R>, bool)>>, // return value, and cancelled
+}
+
+type Func<R> = Box<dyn FnOnce() -> R>;
+
+unsafe impl<R> Send for Mailbox<R> {}
+unsafe impl<R> Sync for Mailbox<R> {}
+
+impl<R> Mailbox<R> {
+    fn new() -> io::Result<Mailbox<R>> {
+        let send_efd = cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) })?;
+        let ack_efd = match cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) }) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = close(send_efd);
+                return Err(e);
+            }
+        };
+
+        Ok(Mailbox {
+            send_efd,
+            ack_efd,
+            state: AtomicUsize::new(State::Wait as usize),
+            func: UnsafeCell::new(None),
+            token: UnsafeCell::new(None),
+            packet: UnsafeCell::new(None),
+        })
+    }
+}
+
+impl<R: Send + 'static> Serve for Mailbox<R> {
+    fn serve(&self, ctx: &Context<Self>) -> Exit {
+        let mut dummy = [0u8; 8];
+        loop {
+            // wait command
+            read_r(self.send_efd, &mut dummy)
+                .expect("cannot read on eventfd with master");
+
+            if ctx.stopping() {
+                return Exit::Stop;
+            }
+
+            self.state.store(State::Running as usize, Ordering::Relaxed);
+
+            // send ack
+            write_r(self.ack_efd, &dummy)
+                .expect("cannot write on eventfd with master");
+
+            // call the function and store the return value
+            let mut respawn = false;
+            if let Some(f) = unsafe { (*self.func.get()).take() } {
+                let (result, r) = ctx.call(f);
+                // as seen when the task returned, not when it is waited for
+                let cancelled = unsafe { (*self.token.get()).take() }
+                    .is_some_and(|token| token.is_cancelled());
+                unsafe {
+                    *self.packet.get() = Some((result, cancelled));
+                }
+                respawn = r;
+            }
+
+            fence(Ordering::Release);
+
+            self.state.store(State::Finished as usize, Ordering::Relaxed);
+
+            if respawn {
+                return Exit::Respawn;
+            }
+        }
+    }
 }
 
-// This packet is used to communicate the return value between the child thread
-// and the parent thread. Memory is shared through the `Arc` within.
-// There is no need for a mutex because synchronization happens with `wait()`
+impl<R> Mailbox<R> {
+    // Wakes up the serving loop without a task.
+    fn wake(&self) {
+        write_r(self.send_efd, &1u64.to_ne_bytes())
+            .expect("cannot write on eventfd with slave");
+    }
+}
+
+impl<R> Drop for Mailbox<R> {
+    fn drop(&mut self) {
+        let _ = close(self.send_efd);
+        let _ = close(self.ack_efd);
+    }
+}
+
+// The result of a queued task. Written once by the lcore before `done` is set
+// and read once by the `Completion` after observing it.
+struct Slot<T> {
+    done: AtomicBool,
+    result: UnsafeCell<Option<Result<T>>>,
+}
+
+impl<T> Slot<T> {
+    fn complete(&self, result: Result<T>) {
+        unsafe {
+            *self.result.get() = Some(result);
+        }
+
+        self.done.store(true, Ordering::Release);
+    }
+}
+
+type SendFunc<R> = Box<dyn FnOnce() -> R + Send>;
+
+type Job<R> = (SendFunc<R>, Arc<Slot<R>>);
+
+// A bounded multi-producer single-consumer task queue.
 //
-struct Packet<T>(Arc<UnsafeCell<Option<Result<T>>>>);
+// The ring itself is protected by a spinlock, the blocking is done by two
+// eventfds in semaphore mode: `items_efd` counts queued jobs and `slots_efd`
+// counts free slots.
+struct TaskQueue<R> {
+    jobs: SpinLock<VecDeque<Job<R>>>,
+    items_efd: RawFd,
+    slots_efd: RawFd,
+    capacity: usize,
+}
+
+unsafe impl<R: Send> Send for TaskQueue<R> {}
+unsafe impl<R: Send> Sync for TaskQueue<R> {}
+
+impl<R> TaskQueue<R> {
+    fn new(capacity: usize) -> io::Result<TaskQueue<R>> {
+        let items_efd = cvt(unsafe {
+            libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_SEMAPHORE)
+        })?;
+        let slots_efd = match cvt(unsafe {
+            libc::eventfd(
+                capacity as u32,
+                libc::EFD_CLOEXEC | libc::EFD_SEMAPHORE | libc::EFD_NONBLOCK,
+            )
+        }) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = close(items_efd);
+                return Err(e);
+            }
+        };
+
+        Ok(TaskQueue {
+            jobs: SpinLock::new(VecDeque::with_capacity(capacity)),
+            items_efd,
+            slots_efd,
+            capacity,
+        })
+    }
+
+    fn push(&self, f: SendFunc<R>, block: bool) -> io::Result<Completion<R>> {
+        self.acquire_slot(block)?;
+
+        let slot = Arc::new(Slot {
+            done: AtomicBool::new(false),
+            result: UnsafeCell::new(None),
+        });
+
+        self.jobs.lock().push_back((f, slot.clone()));
+
+        write_r(self.items_efd, &1u64.to_ne_bytes())
+            .expect("cannot write on eventfd with slave");
+
+        Ok(Completion { slot })
+    }
+
+    // Wakes up the serving loop without a job, `pop` returns `None` once the
+    // jobs queued before are consumed.
+    fn wake(&self) {
+        write_r(self.items_efd, &1u64.to_ne_bytes())
+            .expect("cannot write on eventfd with slave");
+    }
+
+    fn pop(&self) -> Option<Job<R>> {
+        let mut dummy = [0u8; 8];
+        read_r(self.items_efd, &mut dummy)
+            .expect("cannot read on eventfd with master");
+
+        let job = self.jobs.lock().pop_front();
+
+        if job.is_some() {
+            write_r(self.slots_efd, &1u64.to_ne_bytes())
+                .expect("cannot write on eventfd with master");
+        }
+
+        job
+    }
+
+    fn acquire_slot(&self, block: bool) -> io::Result<()> {
+        let mut dummy = [0u8; 8];
+        loop {
+            match read_r(self.slots_efd, &mut dummy) {
+                Ok(_) => return Ok(()),
+                Err(ref e) if block && e.kind() == io::ErrorKind::WouldBlock => {
+                    let mut pfd = libc::pollfd {
+                        fd: self.slots_efd,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    cvt_r(|| unsafe { libc::poll(&mut pfd, 1, -1) })?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<R: Send + 'static> Serve for TaskQueue<R> {
+    fn serve(&self, ctx: &Context<Self>) -> Exit {
+        while let Some((f, completion)) = self.pop() {
+            let (result, respawn) = ctx.call(f);
+            completion.complete(result);
+
+            if respawn {
+                return Exit::Respawn;
+            }
+        }
+
+        Exit::Stop
+    }
+}
+
+impl<R> Drop for TaskQueue<R> {
+    fn drop(&mut self) {
+        let _ = close(self.items_efd);
+        let _ = close(self.slots_efd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use std::thread as std_thread;
+
+    #[test]
+    fn cancel_after_completion() {
+        let lc = spawn::<u64>();
+
+        let task = lc.launch_cancellable(|token| token.is_cancelled() as u64).unwrap();
+        while lc.ctx.chan.state.load(Ordering::Relaxed) == State::Running as usize {
+            spin_loop();
+        }
+        task.cancel();
+        assert_eq!(task.outcome().unwrap(), Outcome::Completed(0));
+
+        // the plain tasks are never cancelled
+        let task = lc.launch(|| 1).unwrap();
+        task.cancel();
+        assert_eq!(task.outcome().unwrap(), Outcome::Completed(1));
+    }
+
+    #[test]
+    fn isolation_check_pins() {
+        let pinned = |id: usize| PINS.lock().unwrap().iter().any(|&(i, _)| i == id);
+
+        let warned = Builder::new()
+            .affinity(&[0])
+            .isolation_check(IsolationCheck::Warn)
+            .spawn::<()>()
+            .unwrap();
+        let id = warned.lcore_id();
+        assert!(pinned(id));
+
+        // cpu 0 is taken, whatever the kernel settings
+        let err = Builder::new()
+            .affinity(&[0])
+            .isolation_check(IsolationCheck::Fail)
+            .spawn::<()>()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains(&format!("cpu 0 is already used by lcore {}", id)));
+
+        // released once the thread exited
+        drop(warned);
+        spin_until(|| !pinned(id));
+
+        let err = Builder::new()
+            .isolation_check(IsolationCheck::Fail)
+            .spawn_queued::<()>(1)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn queued_lcore_zero_capacity() {
+        let err = Builder::new().spawn_queued::<()>(0).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn queued_lcore_multi_producer() {
+        const NPRODUCER: u32 = 4;
+        const NTASK: u32 = 1000;
+
+        let lc = Arc::new(Builder::new().spawn_queued::<u32>(8).unwrap());
+        let counter = Arc::new(AtomicU32::new(0));
+
+        let producers: Vec<_> = (0..NPRODUCER)
+            .map(|_| {
+                let lc = lc.clone();
+                let counter = counter.clone();
+
+                std_thread::spawn(move || {
+                    let tasks: Vec<_> = (0..NTASK)
+                        .map(|i| {
+                            let counter = counter.clone();
+                            lc.submit(move || {
+                                counter.fetch_add(1, Ordering::Relaxed);
+                                i
+                            })
+                            .unwrap()
+                        })
+                        .collect();
+
+                    tasks
+                        .into_iter()
+                        .enumerate()
+                        .all(|(i, t)| t.wait().unwrap() == i as u32)
+                })
+            })
+            .collect();
 
-unsafe impl<T: Send> Send for Packet<T> {}
-unsafe impl<T: Sync> Sync for Packet<T> {}
+        for h in producers {
+            assert!(h.join().unwrap());
+        }
+        assert_eq!(counter.load(Ordering::Relaxed), NPRODUCER * NTASK);
+        assert!(lc.is_empty());
+    }
+
+    #[test]
+    fn queued_lcore_panic() {
+        let lc = Builder::new().spawn_queued::<i32>(2).unwrap();
+
+        let bad = lc.submit(|| panic!("boom")).unwrap();
+        let good = lc.submit(|| 42).unwrap();
+
+        assert!(bad.wait().is_err());
+        assert_eq!(good.wait().unwrap(), 42);
+    }
+
+    fn current_name() -> String {
+        let mut buff = [0u8; 16];
+        unsafe {
+            libc::pthread_getname_np(libc::pthread_self(), buff.as_mut_ptr() as *mut _, buff.len());
+        }
+        let len = buff.iter().position(|&b| b == 0).unwrap_or(buff.len());
+        String::from_utf8_lossy(&buff[..len]).into_owned()
+    }
+
+    fn spin_until<F: Fn() -> bool>(cond: F) {
+        let start = std::time::Instant::now();
+        while !cond() {
+            assert!(start.elapsed().as_secs() < 10, "timed out");
+            spin_loop();
+        }
+    }
+
+    #[test]
+    fn callbacks_init_uninit() {
+        thread_local! {
+            static INITED: Cell<Option<usize>> = const { Cell::new(None) };
+        }
+        let uninited = Arc::new(AtomicU32::new(0));
+        let counter = uninited.clone();
+
+        let handle = callback_register(
+            "test",
+            |id| {
+                if current_name() == "cb-test" {
+                    INITED.with(|i| i.set(Some(id)));
+                }
+                Ok(())
+            },
+            move |id| {
+                if INITED.with(|i| i.get()) == Some(id) {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+        );
+
+        let lc = Builder::new().name("cb-test".into()).spawn::<Option<usize>>().unwrap();
+        let id = lc.lcore_id();
+        assert_eq!(lc.launch(|| INITED.with(|i| i.get())).unwrap().wait().unwrap(), Some(id));
+
+        drop(lc);
+        spin_until(|| uninited.load(Ordering::Relaxed) == 1);
+
+        callback_unregister(handle);
+    }
+
+    #[test]
+    fn callbacks_running_lcore() {
+        thread_local! {
+            static INITED: Cell<bool> = const { Cell::new(false) };
+        }
 
-// This packet is used to communicate the lcore task (`FnOnce() -> R`) between
-// the child thread and the parent thread. Memory is shared through the `Arc`
-// within.
-// Synchronization via `eventfd`.
-struct FuncPacket<R>(Arc<UnsafeCell<Option<Box<dyn FnOnce() -> R>>>>);
+        let lc = Builder::new().spawn_queued::<bool>(1).unwrap();
+        assert!(!lc.submit(|| INITED.with(|i| i.get())).unwrap().wait().unwrap());
 
-unsafe impl<R> Send for FuncPacket<R> {}
-unsafe impl<R> Sync for FuncPacket<R> {}
+        let handle = callback_register(
+            "late",
+            |_| {
+                INITED.with(|i| i.set(true));
+                Ok(())
+            },
+            |_| (),
+        );
+        assert!(lc.submit(|| INITED.with(|i| i.get())).unwrap().wait().unwrap());
+
+        // unregistering uninits before the next task
+        callback_unregister(handle);
+        let handle = callback_register("late-uninit", |_| Ok(()), |_| INITED.with(|i| i.set(false)));
+        assert!(lc.submit(|| INITED.with(|i| i.get())).unwrap().wait().unwrap());
+        callback_unregister(handle);
+        assert!(!lc.submit(|| INITED.with(|i| i.get())).unwrap().wait().unwrap());
+    }
+
+    #[test]
+    fn callbacks_veto_spawn() {
+        let uninited = Arc::new(AtomicU32::new(0));
+        let counter = uninited.clone();
+
+        let first = callback_register("first", |_| Ok(()), move |_| {
+            if current_name() == "cb-veto" {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let veto = callback_register(
+            "veto",
+            |_| {
+                if current_name() == "cb-veto" {
+                    Err(io::Error::from_raw_os_error(libc::EPERM))
+                } else {
+                    Ok(())
+                }
+            },
+            |_| (),
+        );
+
+        let err = Builder::new().name("cb-veto".into()).spawn::<()>().err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        spin_until(|| uninited.load(Ordering::Relaxed) == 1);
+
+        callback_unregister(veto);
+        callback_unregister(first);
+    }
+
+    #[test]
+    fn lcore_drop_drains_queue() {
+        let lc = Builder::new().spawn_queued::<u32>(4).unwrap();
+        let tasks: Vec<_> = (0..4).map(|i| lc.submit(move || i).unwrap()).collect();
+        drop(lc);
+
+        for (i, t) in tasks.into_iter().enumerate() {
+            assert_eq!(t.wait().unwrap(), i as u32);
+        }
+    }
+
+    #[test]
+    fn panic_policy_continue() {
+        let reports = Arc::new(AtomicU32::new(0));
+        let counter = reports.clone();
+
+        let lc = Builder::new()
+            .on_panic(PanicPolicy::new().log(false).callback(move |report| {
+                assert_eq!(report.lcore(), None);
+                assert_eq!(report.message(), Some("formatted 1"));
+                assert!(report.location().unwrap().contains("lcore.rs"));
+                assert!(report.backtrace().is_none());
+                counter.fetch_add(1, Ordering::Relaxed);
+            }))
+            .spawn::<()>()
+            .unwrap();
+
+        let id = lc.id();
+        assert!(lc.launch(|| panic!("formatted {}", 1)).unwrap().wait().is_err());
+        assert!(lc.launch(|| ()).unwrap().wait().is_ok());
+
+        assert_eq!(lc.id(), id);
+        assert_eq!(reports.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn panic_policy_respawn() {
+        let lc = Builder::new()
+            .name("respawned".into())
+            .affinity(&[0])
+            .on_panic(PanicPolicy::new().log(false).action(PanicAction::Respawn))
+            .spawn_queued::<(String, i32)>(4)
+            .unwrap();
+
+        let before = lc.submit(|| (current_name(), unsafe { libc::sched_getcpu() })).unwrap();
+        let bad = lc.submit(|| panic!("respawn me")).unwrap();
+        let after = lc.submit(|| (current_name(), unsafe { libc::sched_getcpu() })).unwrap();
+
+        let before = before.wait().unwrap();
+        assert!(bad.wait().is_err());
+        assert_eq!(after.wait().unwrap(), before);
+        assert_eq!(before, ("respawned".into(), 0));
+    }
+}
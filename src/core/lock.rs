@@ -0,0 +1,441 @@
+//! The raw lock traits.
+//!
+//! `RawLock` is the exclusive lock interface shared by the core lock types,
+//! and `RawSharedLock` its reader-writer extension, so that code can be
+//! generic over the lock, e.g. the [`Elided`] adapter adding TSX elision to
+//! any of them.
+//!
+//! | lock                    | `RawLock` | `RawSharedLock` |
+//! |-------------------------|-----------|-----------------|
+//! | `RawSpinLock`           | yes       |                 |
+//! | `RawTicketLock`         | yes       |                 |
+//! | `RawFutexLock`          | yes       |                 |
+//! | `RawRwLock`             | yes       | yes             |
+//! | `RawPfLock`             | yes       | yes             |
+//! | `Elided<L>`             | as `L`    | as `L`          |
+//!
+//! The recursive locks are not, a second `lock` by the owner must not be
+//! assumed exclusive. Nor is the MCS lock, which needs a queue node.
+//!
+//! With the `lock_api` feature, they also implement `lock_api::RawMutex` and
+//! `lock_api::RawRwLock`, to plug into the `lock_api` generic lock types:
+//!
+//! ```
+//! # #[cfg(feature = "lock_api")]
+//! # {
+//! use dpdk::core::ticketlock::RawTicketLock;
+//!
+//! type Mutex<T> = lock_api::Mutex<RawTicketLock, T>;
+//!
+//! let m = Mutex::new(0);
+//! *m.lock() += 1;
+//! assert_eq!(*m.lock(), 1);
+//! # }
+//! ```
+//!
+//! Their lock order is not checked by [`lockdep`]: the locks built from `INIT`
+//! share its class, whatever their creation site.
+//!
+//! # Example
+//! ```
+//! use dpdk::core::lock::RawLock;
+//! use dpdk::core::{spinlock::RawSpinLock, ticketlock::RawTicketLock};
+//!
+//! fn bump<L: RawLock>(lk: &L, val: &mut u64) {
+//!     lk.lock();
+//!     *val += 1;
+//!     unsafe { lk.unlock() };
+//! }
+//!
+//! let mut val = 0;
+//! bump(&RawSpinLock::new(), &mut val);
+//! bump(&RawTicketLock::new(), &mut val);
+//! assert_eq!(val, 2);
+//! ```
+//!
+//! [`Elided`]: ../tsx/struct.Elided.html
+//! [`lockdep`]: ../lockdep/index.html
+
+use super::futexlock::RawFutexLock;
+use super::pflock::RawPfLock;
+use super::rwlock::RawRwLock;
+use super::spinlock::RawSpinLock;
+use super::ticketlock::RawTicketLock;
+
+/// An exclusive raw lock
+///
+/// # Safety
+///
+/// Implementations must hold the lock exclusively from a successful `lock` or
+/// `try_lock` until `unlock`.
+pub unsafe trait RawLock {
+    /// Take the lock. Loop until the lock is held.
+    fn lock(&self);
+
+    /// Try to take the lock, returns true if it is held
+    fn try_lock(&self) -> bool;
+
+    /// Release the lock
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the caller.
+    unsafe fn unlock(&self);
+
+    /// Test if the lock is held by anyone
+    fn is_locked(&self) -> bool;
+}
+
+/// A raw reader-writer lock, `RawLock` being its exclusive side
+///
+/// # Safety
+///
+/// Implementations must not let the exclusive lock be held along with a
+/// shared one.
+pub unsafe trait RawSharedLock: RawLock {
+    /// Take a shared lock. Loop until the lock is held.
+    fn lock_shared(&self);
+
+    /// Try to take a shared lock, returns true if it is held
+    fn try_lock_shared(&self) -> bool;
+
+    /// Release a shared lock
+    ///
+    /// # Safety
+    ///
+    /// A shared lock must be held by the caller.
+    unsafe fn unlock_shared(&self);
+}
+
+unsafe impl RawLock for RawSpinLock {
+    fn lock(&self) {
+        RawSpinLock::lock(self)
+    }
+
+    fn try_lock(&self) -> bool {
+        self.trylock()
+    }
+
+    unsafe fn unlock(&self) {
+        RawSpinLock::unlock(self)
+    }
+
+    fn is_locked(&self) -> bool {
+        RawSpinLock::is_locked(self)
+    }
+}
+
+unsafe impl RawLock for RawTicketLock {
+    fn lock(&self) {
+        RawTicketLock::lock(self)
+    }
+
+    fn try_lock(&self) -> bool {
+        self.trylock()
+    }
+
+    unsafe fn unlock(&self) {
+        RawTicketLock::unlock(self)
+    }
+
+    fn is_locked(&self) -> bool {
+        RawTicketLock::is_locked(self)
+    }
+}
+
+unsafe impl RawLock for RawFutexLock {
+    fn lock(&self) {
+        RawFutexLock::lock(self)
+    }
+
+    fn try_lock(&self) -> bool {
+        self.trylock()
+    }
+
+    unsafe fn unlock(&self) {
+        RawFutexLock::unlock(self)
+    }
+
+    fn is_locked(&self) -> bool {
+        RawFutexLock::is_locked(self)
+    }
+}
+
+unsafe impl RawLock for RawRwLock {
+    fn lock(&self) {
+        self.write_lock()
+    }
+
+    fn try_lock(&self) -> bool {
+        self.write_trylock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.write_unlock()
+    }
+
+    fn is_locked(&self) -> bool {
+        RawRwLock::is_locked(self)
+    }
+}
+
+unsafe impl RawSharedLock for RawRwLock {
+    fn lock_shared(&self) {
+        self.read_lock()
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.read_trylock()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.read_unlock()
+    }
+}
+
+unsafe impl RawLock for RawPfLock {
+    fn lock(&self) {
+        self.write_lock()
+    }
+
+    fn try_lock(&self) -> bool {
+        self.write_trylock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.write_unlock()
+    }
+
+    fn is_locked(&self) -> bool {
+        RawPfLock::is_locked(self)
+    }
+}
+
+unsafe impl RawSharedLock for RawPfLock {
+    fn lock_shared(&self) {
+        self.read_lock()
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.read_trylock()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.read_unlock()
+    }
+}
+
+#[cfg(feature = "lock_api")]
+mod lock_api_impls {
+    use super::*;
+    use crate::core::tsx::Elided;
+
+    macro_rules! raw_mutex {
+        ($ty:ty, $init:expr, $marker:ty $(, $bound:ident)?) => {
+            unsafe impl$(<L: RawLock + lock_api::$bound>)? lock_api::RawMutex for $ty {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: Self = $init;
+
+                type GuardMarker = $marker;
+
+                fn lock(&self) {
+                    RawLock::lock(self)
+                }
+
+                fn try_lock(&self) -> bool {
+                    RawLock::try_lock(self)
+                }
+
+                unsafe fn unlock(&self) {
+                    RawLock::unlock(self)
+                }
+
+                fn is_locked(&self) -> bool {
+                    RawLock::is_locked(self)
+                }
+            }
+        };
+    }
+
+    macro_rules! raw_rwlock {
+        ($ty:ty, $init:expr, $marker:ty $(, $bound:ident)?) => {
+            unsafe impl$(<L: RawSharedLock + lock_api::$bound>)? lock_api::RawRwLock for $ty {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: Self = $init;
+
+                type GuardMarker = $marker;
+
+                fn lock_shared(&self) {
+                    RawSharedLock::lock_shared(self)
+                }
+
+                fn try_lock_shared(&self) -> bool {
+                    RawSharedLock::try_lock_shared(self)
+                }
+
+                unsafe fn unlock_shared(&self) {
+                    RawSharedLock::unlock_shared(self)
+                }
+
+                fn lock_exclusive(&self) {
+                    RawLock::lock(self)
+                }
+
+                fn try_lock_exclusive(&self) -> bool {
+                    RawLock::try_lock(self)
+                }
+
+                unsafe fn unlock_exclusive(&self) {
+                    RawLock::unlock(self)
+                }
+
+                fn is_locked(&self) -> bool {
+                    RawLock::is_locked(self)
+                }
+            }
+        };
+    }
+
+    raw_mutex!(RawSpinLock, RawSpinLock::new(), lock_api::GuardSend);
+    raw_mutex!(RawTicketLock, RawTicketLock::new(), lock_api::GuardSend);
+    raw_mutex!(RawFutexLock, RawFutexLock::new(), lock_api::GuardSend);
+    // a transaction ends on the thread which began it
+    raw_mutex!(Elided<L>, Elided::new(L::INIT), lock_api::GuardNoSend, RawMutex);
+
+    raw_rwlock!(RawRwLock, RawRwLock::new(), lock_api::GuardSend);
+    raw_rwlock!(RawPfLock, RawPfLock::new(), lock_api::GuardSend);
+    raw_rwlock!(Elided<L>, Elided::new(L::INIT), lock_api::GuardNoSend, RawRwLock);
+
+    unsafe impl lock_api::RawRwLockDowngrade for RawRwLock {
+        unsafe fn downgrade(&self) {
+            RawRwLock::downgrade(self)
+        }
+    }
+
+    unsafe impl lock_api::RawRwLockDowngrade for Elided<RawRwLock> {
+        unsafe fn downgrade(&self) {
+            Elided::<RawRwLock>::downgrade(self)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tsx::Elided;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn exclusive<L: RawLock + Send + Sync + 'static>(lk: L) {
+        const NWORKER: usize = 4;
+        const NITER: usize = 1000;
+
+        struct Shared<L> {
+            lk: L,
+            val: std::cell::UnsafeCell<usize>,
+        }
+        unsafe impl<L: Sync> Sync for Shared<L> {}
+
+        let shared = Arc::new(Shared {
+            lk,
+            val: std::cell::UnsafeCell::new(0),
+        });
+
+        assert!(shared.lk.try_lock());
+        assert!(!shared.lk.try_lock());
+        unsafe { shared.lk.unlock() };
+
+        let threads: Vec<_> = (0..NWORKER)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..NITER {
+                        shared.lk.lock();
+                        unsafe {
+                            *shared.val.get() += 1;
+                            shared.lk.unlock();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert!(!shared.lk.is_locked());
+        assert_eq!(unsafe { *shared.val.get() }, NWORKER * NITER);
+    }
+
+    fn shared<L: RawSharedLock>(lk: L) {
+        lk.lock_shared();
+        assert!(lk.try_lock_shared());
+        assert!(!lk.try_lock());
+        unsafe {
+            lk.unlock_shared();
+            lk.unlock_shared();
+        }
+
+        lk.lock();
+        assert!(!lk.try_lock_shared());
+        unsafe { lk.unlock() };
+        assert!(!lk.is_locked());
+    }
+
+    #[test]
+    fn raw_locks() {
+        exclusive(RawSpinLock::new());
+        exclusive(RawTicketLock::new());
+        exclusive(RawFutexLock::with_spin_cycles(0));
+        exclusive(RawRwLock::new());
+        exclusive(RawPfLock::new());
+        exclusive(Elided::new(RawTicketLock::new()));
+
+        shared(RawRwLock::new());
+        shared(RawPfLock::new());
+        shared(Elided::new(RawPfLock::new()));
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn lock_api_types() {
+        let m = lock_api::Mutex::<Elided<RawSpinLock>, _>::new(0);
+        *m.lock() += 1;
+        assert_eq!(*m.lock(), 1);
+
+        let rw = lock_api::RwLock::<RawRwLock, _>::new(0);
+        {
+            let w = rw.write();
+            let r = lock_api::RwLockWriteGuard::downgrade(w);
+            assert!(rw.try_write().is_none());
+            assert_eq!(*r, 0);
+        }
+        *rw.write() += 1;
+        assert_eq!(*rw.read(), 1);
+    }
+
+    // the lock_api locks share the class of INIT, their inversion is missed
+    #[cfg(all(feature = "lock_api", debug_assertions))]
+    #[test]
+    fn lock_api_lockdep_class() {
+        use crate::core::lockdep;
+
+        let a = lock_api::Mutex::<RawSpinLock, _>::new(0);
+        let b = lock_api::Mutex::<RawSpinLock, _>::new(0);
+        let before = lockdep::cycles().len();
+
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _b = b.lock();
+                let _a = a.lock();
+            });
+        });
+
+        assert_eq!(lockdep::cycles().len(), before);
+    }
+}
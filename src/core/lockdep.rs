@@ -0,0 +1,419 @@
+//! A lock order checker, for debug builds.
+//!
+//! Two lcores taking the locks A then B, and B then A, deadlock once they run
+//! at the same time, which may take months in production. The checker finds
+//! the inversion as soon as both orders have been seen, even apart.
+//!
+//! With `debug_assertions`, the spinlocks and rwlocks belong to a class, their
+//! kind and creation site, e.g. all the locks created by the same line. Each
+//! thread tracks the locks it holds, and each lock taken while holding others
+//! adds an edge to a global class order graph. The first time an edge closes
+//! a cycle, it is reported on stderr with the backtraces of the acquisitions
+//! which added its edges, and kept for [`cycles`].
+//!
+//! A lock may be released by another thread than the one which took it, e.g.
+//! with a `lock_api` guard sent there: the held locks of all the threads are
+//! tracked together, each with its owner.
+//!
+//! Taking a lock it already holds is reported too, except for the read locks.
+//! The locks of the same class may be nested, e.g. in address order, their
+//! order is not checked. An elided acquisition is not tracked, it does not
+//! take the lock.
+//!
+//! The `lock_api` locks get theirs from the `INIT` constant of the raw lock:
+//! all of a raw lock type share a class, their order is not checked.
+//!
+//! Release builds do no checking, and report no cycle.
+//!
+//! # Example
+//! ```
+//! use dpdk::core::{lockdep, spinlock::SpinLock};
+//!
+//! let a = SpinLock::new(0);
+//! let b = SpinLock::new(0);
+//!
+//! {
+//!     let _a = a.lock();
+//!     let _b = b.lock();
+//! }
+//! {
+//!     let _b = b.lock();
+//!     let _a = a.lock();
+//! }
+//!
+//! for cycle in lockdep::cycles() {
+//!     println!("{}", cycle);
+//! }
+//! ```
+//!
+//! [`cycles`]: fn.cycles.html
+
+use std::fmt;
+use std::backtrace::Backtrace;
+use std::sync::Arc;
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+#[cfg(debug_assertions)]
+use libc::pthread_t;
+#[cfg(debug_assertions)]
+use std::collections::HashSet;
+#[cfg(debug_assertions)]
+use std::panic::Location;
+#[cfg(debug_assertions)]
+use std::sync::Mutex;
+
+// A lock class.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    kind: &'static str,
+    site: &'static Location<'static>,
+}
+
+#[cfg(debug_assertions)]
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} created at {}", self.kind, self.site)
+    }
+}
+
+/// The class of a lock, empty in release builds
+pub(crate) struct Class {
+    #[cfg(debug_assertions)]
+    key: Key,
+}
+
+impl Class {
+    /// A class of `kind` locks created at the caller location
+    #[track_caller]
+    pub const fn new(kind: &'static str) -> Self {
+        let _ = kind;
+
+        Class {
+            #[cfg(debug_assertions)]
+            key: Key {
+                kind,
+                site: Location::caller(),
+            },
+        }
+    }
+}
+
+/// An edge of a lock order cycle: `to` was taken while holding `from`
+#[derive(Clone)]
+pub struct Link {
+    /// The class of the held lock
+    pub from: String,
+    /// The class of the lock taken
+    pub to: String,
+    /// Where it was taken, the first time in this order
+    pub backtrace: Arc<Backtrace>,
+}
+
+/// A lock order cycle, a possible deadlock
+#[derive(Clone)]
+pub struct Cycle {
+    /// The edges, each one taking the `from` of the next one
+    pub links: Vec<Link>,
+}
+
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "possible deadlock, lock order cycle:")?;
+
+        for (i, link) in self.links.iter().enumerate() {
+            writeln!(f, "#{} {} taken while holding {}, at:", i, link.to, link.from)?;
+            writeln!(f, "{}", link.backtrace)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The cycles found so far
+pub fn cycles() -> Vec<Cycle> {
+    #[cfg(debug_assertions)]
+    return CYCLES.lock().unwrap().clone();
+
+    #[cfg(not(debug_assertions))]
+    Vec::new()
+}
+
+/// Check the order of taking the lock at `addr`, before waiting for it
+#[inline]
+pub(crate) fn check(class: &Class, addr: usize, exclusive: bool) {
+    let _ = (class, addr, exclusive);
+
+    #[cfg(debug_assertions)]
+    check_order(class.key, addr, exclusive);
+}
+
+/// Track the lock at `addr`, once taken
+#[inline]
+pub(crate) fn acquired(class: &Class, addr: usize, exclusive: bool) {
+    let _ = (class, addr, exclusive);
+
+    #[cfg(debug_assertions)]
+    HELD.lock().unwrap().push(Held {
+        owner: unsafe { libc::pthread_self() },
+        key: class.key,
+        addr,
+        exclusive,
+    });
+}
+
+/// Untrack the lock at `addr`, on release
+#[inline]
+pub(crate) fn release(addr: usize) {
+    let _ = addr;
+
+    #[cfg(debug_assertions)]
+    {
+        let me = unsafe { libc::pthread_self() };
+        let mut held = HELD.lock().unwrap();
+
+        // the caller's own acquisition, or else the one of the thread which
+        // took it
+        let i = held
+            .iter()
+            .rposition(|h| h.addr == addr && h.owner == me)
+            .or_else(|| held.iter().rposition(|h| h.addr == addr));
+        if let Some(i) = i {
+            held.remove(i);
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+struct Held {
+    owner: pthread_t,
+    key: Key,
+    addr: usize,
+    exclusive: bool,
+}
+
+#[cfg(debug_assertions)]
+struct Edge {
+    from: Key,
+    to: Key,
+    backtrace: Arc<Backtrace>,
+}
+
+// The locks held by every thread, one may release a lock taken by another.
+#[cfg(debug_assertions)]
+static HELD: Mutex<Vec<Held>> = Mutex::new(Vec::new());
+
+#[cfg(debug_assertions)]
+thread_local! {
+    // the edges this thread knows to be in the graph
+    static KNOWN: RefCell<HashSet<(Key, Key)>> = RefCell::new(HashSet::new());
+}
+
+#[cfg(debug_assertions)]
+static GRAPH: Mutex<Vec<Edge>> = Mutex::new(Vec::new());
+
+#[cfg(debug_assertions)]
+static CYCLES: Mutex<Vec<Cycle>> = Mutex::new(Vec::new());
+
+#[cfg(debug_assertions)]
+fn check_order(key: Key, addr: usize, exclusive: bool) {
+    let me = unsafe { libc::pthread_self() };
+    let held: Vec<_> = HELD
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|h| h.owner == me)
+        .map(|h| (h.key, h.addr, h.exclusive))
+        .collect();
+
+    for (from, from_addr, from_exclusive) in held {
+        if from_addr == addr {
+            if from_exclusive || exclusive {
+                let backtrace = Arc::new(Backtrace::force_capture());
+                report(vec![link(key, key, backtrace)]);
+            }
+        } else if from != key {
+            add_edge(from, key);
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+fn add_edge(from: Key, to: Key) {
+    let known = KNOWN
+        .try_with(|known| known.borrow().contains(&(from, to)))
+        .unwrap_or(false);
+    if known {
+        return;
+    }
+
+    let mut graph = GRAPH.lock().unwrap();
+
+    if !graph.iter().any(|e| e.from == from && e.to == to) {
+        let backtrace = Arc::new(Backtrace::force_capture());
+
+        // the new edge closes a cycle if `from` is reachable from `to`
+        if let Some(path) = find_path(&graph, to, from) {
+            let mut links: Vec<_> = path
+                .into_iter()
+                .map(|i| link(graph[i].from, graph[i].to, graph[i].backtrace.clone()))
+                .collect();
+            links.push(link(from, to, backtrace.clone()));
+
+            report(links);
+        }
+
+        graph.push(Edge { from, to, backtrace });
+    }
+
+    let _ = KNOWN.try_with(|known| known.borrow_mut().insert((from, to)));
+}
+
+// The edges of a path in the graph, by a depth first search.
+#[cfg(debug_assertions)]
+fn find_path(graph: &[Edge], from: Key, to: Key) -> Option<Vec<usize>> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![(from, Vec::new())];
+
+    while let Some((node, path)) = stack.pop() {
+        if node == to {
+            return Some(path);
+        }
+        if !visited.insert(node) {
+            continue;
+        }
+
+        for (i, e) in graph.iter().enumerate().filter(|(_, e)| e.from == node) {
+            let mut path = path.clone();
+            path.push(i);
+            stack.push((e.to, path));
+        }
+    }
+
+    None
+}
+
+#[cfg(debug_assertions)]
+fn link(from: Key, to: Key, backtrace: Arc<Backtrace>) -> Link {
+    Link {
+        from: from.to_string(),
+        to: to.to_string(),
+        backtrace,
+    }
+}
+
+#[cfg(debug_assertions)]
+fn report(links: Vec<Link>) {
+    let cycle = Cycle { links };
+
+    eprintln!("lockdep: {}", cycle);
+    CYCLES.lock().unwrap().push(cycle);
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use crate::core::rwlock::RwLock;
+    use crate::core::spinlock::{RawSpinLock, SpinLock};
+    use std::thread;
+
+    fn cycles_with(site: &str) -> Vec<Cycle> {
+        cycles()
+            .into_iter()
+            .filter(|c| c.links.iter().any(|l| l.from.contains(site) || l.to.contains(site)))
+            .collect()
+    }
+
+    #[test]
+    fn lockdep_ab_ba() {
+        let (a, line) = (SpinLock::new(0), line!());
+        let b = RwLock::new(0);
+        let site = format!("{}:{}:", file!(), line);
+
+        // the same order is fine
+        for _ in 0..2 {
+            let _a = a.lock();
+            let _b = b.read();
+        }
+        assert!(cycles_with(&site).is_empty());
+
+        // the inversion on another thread, not even concurrently
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _b = b.write();
+                let _a = a.lock();
+            });
+        });
+
+        let found = cycles_with(&site);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].links.len(), 2);
+        assert_eq!(found[0].links[0].from, found[0].links[1].to);
+        assert_eq!(found[0].links[0].to, found[0].links[1].from);
+
+        // reported once
+        {
+            let _b = b.write();
+            let _a = a.lock();
+        }
+        assert_eq!(cycles_with(&site).len(), 1);
+    }
+
+    #[test]
+    fn lockdep_foreign_release() {
+        let (lk, line) = (RawSpinLock::new(), line!());
+        let site = format!("{}:{}:", file!(), line);
+
+        lk.lock();
+        thread::scope(|s| {
+            s.spawn(|| lk.unlock());
+        });
+
+        // no longer held by this thread
+        lk.lock();
+        lk.unlock();
+
+        assert!(cycles_with(&site).is_empty());
+    }
+
+    #[test]
+    fn lockdep_named() {
+        let (a, line) = (SpinLock::named("lockdep_a", 0), line!());
+        let b = RwLock::named("lockdep_b", 0);
+        let site = format!("{}:{}:", file!(), line);
+
+        {
+            let _a = a.lock();
+            let _b = b.write();
+        }
+        {
+            let _b = b.read();
+            let _a = a.lock();
+        }
+
+        assert_eq!(cycles_with(&site).len(), 1);
+    }
+
+    #[test]
+    fn lockdep_same_class() {
+        let (locks, line) = ((0..2).map(|i| SpinLock::new(i)).collect::<Vec<_>>(), line!());
+        let site = format!("{}:{}:", file!(), line);
+
+        {
+            let _first = locks[0].lock();
+            let _second = locks[1].lock();
+        }
+        {
+            let _second = locks[1].lock();
+            let _first = locks[0].lock();
+        }
+
+        assert!(cycles_with(&site).is_empty());
+    }
+}
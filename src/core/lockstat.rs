@@ -0,0 +1,344 @@
+//! Lock contention profiling.
+//!
+//! With the `lock_stat` feature, the `SpinLock`, `RecursiveSpinLock` and
+//! `RwLock` count, per named lock:
+//!
+//! - the acquisitions, and how many were contended
+//! - the spin iterations waiting for the contended ones
+//! - the cycles waited and held, from `get_tsc_cycles`
+//!
+//! A lock is named by its `named` constructor, or after the source location
+//! it was created at. The locks of the same name share their counters, e.g.
+//! the per-queue locks created by the same line.
+//!
+//! The counters are shared by the lcores taking the lock, which costs some
+//! cache traffic, and aborts the transactions of an elided lock waiting for
+//! it: profile the contention with the elision off.
+//!
+//! # Example
+//! ```
+//! use dpdk::core::{lockstat, spinlock::SpinLock};
+//!
+//! let lk = SpinLock::named("rx_queue", 0);
+//! *lk.lock() += 1;
+//!
+//! let report = lockstat::top(10);
+//! let rx = report.iter().find(|r| r.name == "rx_queue").unwrap();
+//! assert!(rx.acquisitions >= 1);
+//!
+//! lockstat::dump(&mut std::io::stdout(), 10).unwrap();
+//! ```
+
+use super::cycles::get_tsc_cycles;
+use super::lock::RawLock;
+use super::tsx::Elided;
+use std::fmt;
+use std::io;
+use std::hint::spin_loop;
+use std::panic::Location;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The counters of a named lock
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The lock name, or its creation site
+    pub name: String,
+    /// Times the lock was taken
+    pub acquisitions: u64,
+    /// Times the lock was found held
+    pub contended: u64,
+    /// Spin iterations waiting for the lock
+    pub spins: u64,
+    /// Cycles waiting for the lock
+    pub wait_cycles: u64,
+    /// The longest wait, in cycles
+    pub max_wait_cycles: u64,
+    /// Cycles holding the lock
+    pub hold_cycles: u64,
+}
+
+impl Report {
+    /// The mean wait of a contended acquisition, in cycles
+    pub fn avg_wait_cycles(&self) -> u64 {
+        self.wait_cycles.checked_div(self.contended).unwrap_or(0)
+    }
+
+    /// The mean hold of an acquisition, in cycles
+    pub fn avg_hold_cycles(&self) -> u64 {
+        self.hold_cycles.checked_div(self.acquisitions).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<40} {:>12} {:>12} {:>14} {:>12} {:>14} {:>12}",
+            self.name,
+            self.acquisitions,
+            self.contended,
+            self.spins,
+            self.avg_wait_cycles(),
+            self.max_wait_cycles,
+            self.avg_hold_cycles()
+        )
+    }
+}
+
+/// The counters of all the named locks, the most contended first
+pub fn report() -> Vec<Report> {
+    let mut reports: Vec<_> = REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, counters)| counters.report(name.clone()))
+        .collect();
+
+    reports.sort_by(|a, b| b.contended.cmp(&a.contended).then(b.wait_cycles.cmp(&a.wait_cycles)));
+    reports
+}
+
+/// The `n` most contended locks
+pub fn top(n: usize) -> Vec<Report> {
+    let mut reports = report();
+    reports.truncate(n);
+    reports
+}
+
+/// Write a table of the `n` most contended locks
+pub fn dump<W: io::Write>(w: &mut W, n: usize) -> io::Result<()> {
+    writeln!(
+        w,
+        "{:<40} {:>12} {:>12} {:>14} {:>12} {:>14} {:>12}",
+        "lock", "acquisitions", "contended", "spins", "avg wait", "max wait", "avg hold"
+    )?;
+
+    for r in top(n) {
+        writeln!(w, "{}", r)?;
+    }
+
+    Ok(())
+}
+
+/// Clear the counters of all the locks
+pub fn reset() {
+    for (_, counters) in REGISTRY.lock().unwrap().iter() {
+        counters.reset();
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spins: AtomicU64,
+    wait_cycles: AtomicU64,
+    max_wait_cycles: AtomicU64,
+    hold_cycles: AtomicU64,
+}
+
+impl Counters {
+    fn report(&self, name: String) -> Report {
+        Report {
+            name,
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+            wait_cycles: self.wait_cycles.load(Ordering::Relaxed),
+            max_wait_cycles: self.max_wait_cycles.load(Ordering::Relaxed),
+            hold_cycles: self.hold_cycles.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+        self.spins.store(0, Ordering::Relaxed);
+        self.wait_cycles.store(0, Ordering::Relaxed);
+        self.max_wait_cycles.store(0, Ordering::Relaxed);
+        self.hold_cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+// The counters by lock name, never removed so that the dropped locks are
+// still reported.
+static REGISTRY: Mutex<Vec<(String, Arc<Counters>)>> = Mutex::new(Vec::new());
+
+fn register(name: String) -> Arc<Counters> {
+    let mut registry = REGISTRY.lock().unwrap();
+
+    match registry.iter().find(|(n, _)| *n == name) {
+        Some((_, counters)) => counters.clone(),
+        None => {
+            let counters = Arc::new(Counters::default());
+            registry.push((name, counters.clone()));
+            counters
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Name {
+    Named(&'static str),
+    Site(&'static Location<'static>),
+}
+
+/// The profile of a lock, registered on its first acquisition
+pub(crate) struct LockStat {
+    name: Name,
+    counters: OnceLock<Arc<Counters>>,
+}
+
+impl LockStat {
+    /// A lock named after the caller location
+    #[track_caller]
+    pub const fn new() -> Self {
+        LockStat {
+            name: Name::Site(Location::caller()),
+            counters: OnceLock::new(),
+        }
+    }
+
+    /// A lock named `name`
+    pub const fn named(name: &'static str) -> Self {
+        LockStat {
+            name: Name::Named(name),
+            counters: OnceLock::new(),
+        }
+    }
+
+    fn counters(&self) -> &Counters {
+        self.counters.get_or_init(|| {
+            register(match self.name {
+                Name::Named(name) => name.to_owned(),
+                Name::Site(site) => site.to_string(),
+            })
+        })
+    }
+
+    /// Take the lock with `try_lock`, spinning on `busy` until it looks free
+    /// before trying again
+    pub fn acquire<F, B>(&self, mut try_lock: F, busy: B)
+    where
+        F: FnMut() -> bool,
+        B: Fn() -> bool,
+    {
+        let counters = self.counters();
+        counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        if try_lock() {
+            return;
+        }
+
+        let start = get_tsc_cycles();
+        let mut spins = 0;
+        loop {
+            // a load per spin, not to bounce the line away from the holder
+            while busy() {
+                spin_loop();
+                spins += 1;
+            }
+
+            if try_lock() {
+                break;
+            }
+        }
+        let wait = get_tsc_cycles().wrapping_sub(start);
+
+        counters.contended.fetch_add(1, Ordering::Relaxed);
+        counters.spins.fetch_add(spins, Ordering::Relaxed);
+        counters.wait_cycles.fetch_add(wait, Ordering::Relaxed);
+        counters.max_wait_cycles.fetch_max(wait, Ordering::Relaxed);
+    }
+
+    /// Take the elided `lock` in a transaction as its `lock` does, or else
+    /// with `try_lock` and `busy` on the inner lock, so that the wait neither
+    /// begins a transaction per spin nor bypasses the retry policy
+    pub(crate) fn acquire_elided<L, F, B>(&self, lock: &Elided<L>, try_lock: F, busy: B)
+    where
+        L: RawLock,
+        F: Fn(&L) -> bool,
+        B: Fn(&L) -> bool,
+    {
+        if lock.elide_lock() {
+            self.acquired();
+        } else {
+            self.acquire(|| try_lock(lock.inner()), || busy(lock.inner()));
+        }
+    }
+
+    /// Count an acquisition which did not wait, e.g. a successful try
+    pub fn acquired(&self) {
+        self.counters().acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the hold of the lock acquired at `since` cycles
+    pub fn release(&self, since: u64) {
+        let held = get_tsc_cycles().wrapping_sub(since);
+        self.counters().hold_cycles.fetch_add(held, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::rwlock::RwLock;
+    use crate::core::spinlock::SpinLock;
+    use std::thread;
+
+    #[test]
+    fn lockstat_contention() {
+        static HOT: SpinLock<u64> = SpinLock::named("lockstat_hot", 0);
+        let cold = RwLock::named("lockstat_cold", 0);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..1000 {
+                        let mut v = HOT.lock();
+                        *v += 1;
+                        // let the others find it held
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..10 {
+            *cold.write() += 1;
+            let _ = *cold.read();
+        }
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let reports = report();
+        let hot = reports.iter().position(|r| r.name == "lockstat_hot").unwrap();
+        let cold = reports.iter().position(|r| r.name == "lockstat_cold").unwrap();
+
+        assert_eq!(reports[hot].acquisitions, 4000);
+        assert!(reports[hot].contended > 0);
+        assert!(reports[hot].spins >= reports[hot].contended);
+        assert!(reports[hot].hold_cycles > 0);
+        assert_eq!(reports[cold].acquisitions, 20);
+        assert_eq!(reports[cold].contended, 0);
+        assert!(hot < cold);
+
+        let mut out = Vec::new();
+        dump(&mut out, reports.len()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.lines().any(|l| l.starts_with("lockstat_hot ")));
+    }
+
+    #[test]
+    fn lockstat_creation_site() {
+        let lk = SpinLock::new(0);
+        let line = line!() - 1;
+        *lk.lock() += 1;
+
+        let site = format!("{}:{}:", file!(), line);
+        assert!(report().iter().any(|r| r.name.starts_with(&site)));
+    }
+}
@@ -60,6 +60,63 @@
 //! # fn main() {}
 //! ```
 //!
+//! # Shared logger
+//!
+//! A `Logger` borrows its writer mutably, it cannot be shared by the lcores.
+//! The process-wide [`logger`] is a `Sync` [`SharedLogger`], writing to
+//! stderr by default. Each thread formats its lines in a buffer of its own,
+//! and writes every line at once, so that the lines logged concurrently by
+//! the lcores never interleave.
+//!
+//! The macros log to it when given no logger:
+//!
+//! ```
+//! #[macro_use]
+//! extern crate dpdk;
+//!
+//! use dpdk::core::log;
+//! use std::thread;
+//!
+//! # fn main() {
+//! log::logger().set_level(log::Level::Debug);
+//!
+//! let workers: Vec<_> = (0..4)
+//!     .map(|i| thread::spawn(move || info!("worker {} started", i)))
+//!     .collect();
+//!
+//! for w in workers {
+//!     w.join().unwrap();
+//! }
+//! # }
+//! ```
+//!
+//! # Log types
+//!
+//! The components register their log type by name, e.g. `pmd.net.pcap`, each
+//! one having its own level, so that one component can be debugged without
+//! the debug messages of the others. The levels are set by type, or for all
+//! the types whose name matches a glob or a regular expression, including the
+//! ones registered later. A type logs to the shared logger through the same
+//! macros, its messages filtered by its own level only, and prefixed by its
+//! name:
+//!
+//! ```
+//! #[macro_use]
+//! extern crate dpdk;
+//!
+//! use dpdk::core::log;
+//! use std::sync::LazyLock;
+//!
+//! static PCAP: LazyLock<log::LogType> = LazyLock::new(|| log::register("pmd.net.pcap"));
+//!
+//! # fn main() {
+//! log::set_level_arg("pmd.*:debug").unwrap();
+//!
+//! debug!(PCAP, "opened {}", "eth0");
+//! assert_eq!(PCAP.level(), log::Level::Debug);
+//! # }
+//! ```
+//!
 //! # Log format
 //!
 //! Every log message obeys the following fixed and easily-parsable format:
@@ -75,7 +132,7 @@
 //! - `<SS>` denotes seconds zero-padded to *2* digits,
 //! - `<mss>` denotes milliseconds zero-padded to *3* digits.
 //! - `<level>` is the log level as defined by `Level`.
-//! - `<message>` is the log message.
+//! - `<message>` is the log message, after `<type>: ` for a log type.
 //!
 //! NOTE: a newline is automatically inserted at the end.
 //!
@@ -84,11 +141,19 @@
 //!
 //! Any errors returned by the sink when writing are ignored.
 //!
+//! [`logger`]: fn.logger.html
+//! [`register`]: fn.register.html
+//! [`SharedLogger`]: struct.SharedLogger.html
 
 use std::io;
 use std::fmt;
 use std::error;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
 use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// The standard logging macro.
@@ -110,10 +175,16 @@ use std::time::{SystemTime, UNIX_EPOCH};
 /// let mut logger = log::Logger::new(log::Level::Debug, &mut stderr);
 ///
 /// log!(logger, log::Level::Error, "{}\n", 123);
+///
+/// // to the shared logger
+/// log!(log::Level::Error, "{}", 123);
 /// # }
 /// ```
 #[macro_export]
 macro_rules! log {
+    ($lvl:expr, $fmt:literal $($arg:tt)*) => {
+        $crate::core::log::logger().log($lvl, file!(), line!(), format_args!($fmt $($arg)*));
+    };
     ($logger:expr, $lvl:expr, $($arg:tt)+) => {
         $logger.log($lvl, file!(), line!(), format_args!($($arg)+));
     };
@@ -139,6 +210,9 @@ macro_rules! log {
 /// ```
 #[macro_export]
 macro_rules! emerg {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Emerg, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Emerg, $($arg)+);
     };
@@ -164,6 +238,9 @@ macro_rules! emerg {
 /// ```
 #[macro_export]
 macro_rules! alert {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Alert, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Alert, $($arg)+);
     };
@@ -189,6 +266,9 @@ macro_rules! alert {
 /// ```
 #[macro_export]
 macro_rules! crit {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Crit, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Crit, $($arg)+);
     };
@@ -214,6 +294,9 @@ macro_rules! crit {
 /// ```
 #[macro_export]
 macro_rules! error {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Error, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Error, $($arg)+);
     };
@@ -239,6 +322,9 @@ macro_rules! error {
 /// ```
 #[macro_export]
 macro_rules! warn {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Warn, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Warn, $($arg)+);
     };
@@ -264,6 +350,9 @@ macro_rules! warn {
 /// ```
 #[macro_export]
 macro_rules! notice {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Notice, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Notice, $($arg)+);
     };
@@ -289,6 +378,9 @@ macro_rules! notice {
 /// ```
 #[macro_export]
 macro_rules! info {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Info, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Info, $($arg)+);
     };
@@ -314,6 +406,9 @@ macro_rules! info {
 /// ```
 #[macro_export]
 macro_rules! debug {
+    ($fmt:literal $($arg:tt)*) => {
+        log!($crate::core::log::Level::Debug, $fmt $($arg)*);
+    };
     ($logger:expr, $($arg:tt)+) => {
         log!($logger, $crate::core::log::Level::Debug, $($arg)+);
     };
@@ -323,7 +418,7 @@ macro_rules! debug {
 /// A format optimized Logger
 pub struct Logger<'a> {
     filter: Level,
-    writer: &'a mut io::Write,
+    writer: &'a mut dyn io::Write,
     last_ts: u64,
     buffer: [u8; 4096],
 }
@@ -345,10 +440,10 @@ impl<'a> Logger<'a> {
     /// # }
     /// ```
     #[inline]
-    pub fn new(filter: Level, writer: &'a mut io::Write) -> Self {
+    pub fn new(filter: Level, writer: &'a mut dyn io::Write) -> Self {
         Self {
-            filter: filter,
-            writer: writer,
+            filter,
+            writer,
             last_ts: 0,
             buffer: [0u8; 4096],
         }
@@ -361,91 +456,441 @@ impl<'a> Logger<'a> {
     }
 
     /// Logs the message.
-    pub fn log<'r>(&mut self, level: Level, file: &'static str, mut line: u32,
+    pub fn log<'r>(&mut self, level: Level, file: &'static str, line: u32,
                    args: fmt::Arguments<'r>) {
         if level > self.filter {
             return;
         }
 
-        let dur = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        let secs_since_epoch = dur.as_secs();
-        let msec = dur.subsec_millis();
+        let idx = format_prefix(&mut self.buffer, &mut self.last_ts, level, file, line);
+
+        let _ = self.writer.write(&self.buffer[0..idx]);
+        let _ = self.writer.write_fmt(args);
+        let _ = self.writer.write(b"\n");
+        let _ = self.writer.flush();
+    }
+}
+
+// Formats the line prefix into `buffer`, returns its length. The date is
+// only formatted again when the second `last_ts` changes.
+fn format_prefix(buffer: &mut [u8], last_ts: &mut u64, level: Level, file: &'static str,
+                 mut line: u32) -> usize {
+    let dur = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let secs_since_epoch = dur.as_secs();
+    let msec = dur.subsec_millis();
+
+    if secs_since_epoch >= 253402300800u64 {
+        panic!("can't format year 9999");
+    }
+
+    let (year, mon, day, hr, min, sec) = private::localtime(secs_since_epoch);
+
+    if *last_ts != secs_since_epoch {
+        *last_ts = secs_since_epoch;
+
+        buffer[0] = b'0' + (year / 1000) as u8;
+        buffer[1] = b'0' + (year / 100 % 10) as u8;
+        buffer[2] = b'0' + (year / 10 % 100) as u8;
+        buffer[3] = b'0' + (year % 10) as u8;
+        buffer[4] = b'-';
+        buffer[5] = b'0' + (mon / 10) as u8;
+        buffer[6] = b'0' + (mon % 10) as u8;
+        buffer[7] = b'-';
+        buffer[8] = b'0' + (day / 10) as u8;
+        buffer[9] = b'0' + (day % 10) as u8;
+        buffer[10] = b' ';
+        buffer[11] = b'0' + (hr / 10) as u8;
+        buffer[12] = b'0' + (hr % 10) as u8;
+        buffer[13] = b':';
+        buffer[14] = b'0' + (min / 10) as u8;
+        buffer[15] = b'0' + (min % 10) as u8;
+        buffer[16] = b':';
+        buffer[17] = b'0' + (sec / 10) as u8;
+        buffer[18] = b'0' + (sec % 10) as u8;
+        buffer[19] = b'.';
+    }
+
+    buffer[20] = b'0' + (msec / 100) as u8;
+    buffer[21] = b'0' + (msec / 10 % 10) as u8;
+    buffer[22] = b'0' + (msec % 10) as u8;
+    buffer[23] = b' ';
+
+    buffer[24] = b'[';
+
+    let len = LOG_LEVEL_NAMES[level as usize].len();
+    buffer[25..][..len].copy_from_slice(LOG_LEVEL_NAMES[level as usize].as_bytes());
+
+    let mut idx = 25 + len;
+
+    buffer[idx] = b']';
+    idx += 1;
+
+    buffer[idx] = b' ';
+    idx += 1;
+
+    buffer[idx..][..file.len()].copy_from_slice(file.as_bytes());
+    idx += file.len();
+
+    buffer[idx] = b':';
+    idx += 1;
+
+    let digits_begin = idx;
+    const DIGITS: [u8; 10] = [b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9'];
+    loop {
+        buffer[idx] = DIGITS[(line % 10) as usize];
+        idx += 1;
+
+        line /= 10;
+        if line == 0 {
+            break;
+        }
+    }
+    buffer[digits_begin..idx].reverse();
+
+    buffer[idx] = b' ';
+    idx += 1;
+
+    idx
+}
+
+/// A `Sync` logger, shared by the threads
+///
+/// The lines are formatted in per-thread buffers, and written to the sink at
+/// once, under its lock.
+///
+/// ```
+/// use dpdk::core::log;
+///
+/// let logger = log::SharedLogger::new(log::Level::Info, Box::new(std::io::stdout()));
+///
+/// logger.log(log::Level::Info, file!(), line!(), format_args!("{} rx queues", 4));
+/// ```
+pub struct SharedLogger {
+    filter: AtomicUsize,
+    sink: Mutex<Box<dyn io::Write + Send>>,
+}
+
+// The line being formatted by the thread, with the date of its last line.
+struct LineBuffer {
+    last_ts: u64,
+    buffer: Vec<u8>,
+}
+
+thread_local! {
+    static LINE: RefCell<LineBuffer> = const {
+        RefCell::new(LineBuffer { last_ts: 0, buffer: Vec::new() })
+    };
+}
 
-        if secs_since_epoch >= 253402300800u64 {
-            panic!("can't format year 9999");
+impl SharedLogger {
+    /// Constructs a shared logger with specified `Level` and sink
+    pub fn new(filter: Level, sink: Box<dyn io::Write + Send>) -> Self {
+        SharedLogger {
+            filter: AtomicUsize::new(filter as usize),
+            sink: Mutex::new(sink),
         }
+    }
+
+    /// Sets the logger log level.
+    pub fn set_level(&self, level: Level) {
+        self.filter.store(level as usize, Ordering::Relaxed);
+    }
+
+    /// The logger log level.
+    pub fn level(&self) -> Level {
+        Level::from(self.filter.load(Ordering::Relaxed))
+    }
 
-        let (year, mon, day, hr, min, sec) = private::localtime(secs_since_epoch);
-
-        if self.last_ts != secs_since_epoch {
-            self.last_ts = secs_since_epoch;
-
-            self.buffer[0] = b'0' + (year / 1000) as u8;
-            self.buffer[1] = b'0' + (year / 100 % 10) as u8;
-            self.buffer[2] = b'0' + (year / 10 % 100) as u8;
-            self.buffer[3] = b'0' + (year % 10) as u8;
-            self.buffer[4] = b'-';
-            self.buffer[5] = b'0' + (mon / 10) as u8;
-            self.buffer[6] = b'0' + (mon % 10) as u8;
-            self.buffer[7] = b'-';
-            self.buffer[8] = b'0' + (day / 10) as u8;
-            self.buffer[9] = b'0' + (day % 10) as u8;
-            self.buffer[10] = b' ';
-            self.buffer[11] = b'0' + (hr / 10) as u8;
-            self.buffer[12] = b'0' + (hr % 10) as u8;
-            self.buffer[13] = b':';
-            self.buffer[14] = b'0' + (min / 10) as u8;
-            self.buffer[15] = b'0' + (min % 10) as u8;
-            self.buffer[16] = b':';
-            self.buffer[17] = b'0' + (sec / 10) as u8;
-            self.buffer[18] = b'0' + (sec % 10) as u8;
-            self.buffer[19] = b'.';
+    /// Replaces the sink, returning the previous one.
+    pub fn set_sink(&self, sink: Box<dyn io::Write + Send>) -> Box<dyn io::Write + Send> {
+        let mut current = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *current, sink)
+    }
+
+    /// Logs the message.
+    pub fn log(&self, level: Level, file: &'static str, line: u32, args: fmt::Arguments) {
+        if level > self.level() {
+            return;
         }
 
-        self.buffer[20] = b'0' + (msec / 100) as u8;
-        self.buffer[21] = b'0' + (msec / 10 % 10) as u8;
-        self.buffer[22] = b'0' + (msec % 10) as u8;
-        self.buffer[23] = b' ';
+        self.write_line(level, file, line, args);
+    }
 
-        self.buffer[24] = b'[';
+    /// Logs the message of the log type `ty`, filtered by its own level.
+    pub fn log_type(&self, ty: LogType, level: Level, file: &'static str, line: u32,
+                    args: fmt::Arguments) {
+        if level > ty.level() {
+            return;
+        }
 
-        let len = LOG_LEVEL_NAMES[level as usize].len();
-        self.buffer[25..][..len].copy_from_slice(LOG_LEVEL_NAMES[level as usize].as_bytes());
+        self.write_line(level, file, line, format_args!("{}: {}", ty.name(), args));
+    }
 
-        let mut idx = 25 + len;
+    fn write_line(&self, level: Level, file: &'static str, line: u32, args: fmt::Arguments) {
+        let emitted = LINE.try_with(|cell| match cell.try_borrow_mut() {
+            Ok(mut line_buffer) => {
+                self.emit(&mut line_buffer, level, file, line, args);
+                true
+            }
+            Err(_) => false,
+        });
 
-        self.buffer[idx] = b']';
-        idx += 1;
+        // logging while formatting, or the thread exiting
+        if emitted != Ok(true) {
+            let mut line_buffer = LineBuffer { last_ts: 0, buffer: Vec::new() };
+            self.emit(&mut line_buffer, level, file, line, args);
+        }
+    }
 
-        self.buffer[idx] = b' ';
-        idx += 1;
+    fn emit(&self, line_buffer: &mut LineBuffer, level: Level, file: &'static str, line: u32,
+            args: fmt::Arguments) {
+        let LineBuffer { last_ts, buffer } = line_buffer;
 
-        self.buffer[idx..][..file.len()].copy_from_slice(file.as_bytes());
-        idx += file.len();
+        // the date cached at the start of the buffer is kept
+        buffer.resize(4096, 0);
+        let idx = format_prefix(buffer, last_ts, level, file, line);
+        buffer.truncate(idx);
 
-        self.buffer[idx] = b':';
-        idx += 1;
+        let _ = io::Write::write_fmt(buffer, args);
+        buffer.push(b'\n');
 
-        let digits_begin = idx;
-        const DIGITS: [u8; 10] = [b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9'];
-        loop {
-            self.buffer[idx] = DIGITS[(line % 10) as usize];
-            idx += 1;
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = sink.write_all(buffer);
+        let _ = sink.flush();
+    }
+}
 
-            line /= 10;
-            if line == 0 {
-                break;
+impl fmt::Debug for SharedLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedLogger").field("level", &self.level()).finish()
+    }
+}
+
+/// The process-wide shared logger, at the `Info` level to stderr until set
+/// otherwise
+pub fn logger() -> &'static SharedLogger {
+    static LOGGER: OnceLock<SharedLogger> = OnceLock::new();
+
+    LOGGER.get_or_init(|| SharedLogger::new(Level::Info, Box::new(io::stderr())))
+}
+
+/// A registered log type, with its own level
+#[derive(Clone, Copy)]
+pub struct LogType {
+    entry: &'static TypeEntry,
+}
+
+struct TypeEntry {
+    id: u32,
+    name: String,
+    filter: AtomicUsize,
+}
+
+// The patterns set so far, applied in order to the types registered later.
+enum Pattern {
+    Glob(CString),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn matches(&self, name: &str) -> io::Result<bool> {
+        let name = CString::new(name).map_err(invalid_input)?;
+
+        match *self {
+            Pattern::Glob(ref glob) => {
+                Ok(unsafe { libc::fnmatch(glob.as_ptr(), name.as_ptr(), 0) } == 0)
             }
+            Pattern::Regex(ref regex) => Ok(regex.is_match(&name)),
+        }
+    }
+}
+
+// A POSIX extended regular expression, compiled once.
+struct Regex(libc::regex_t);
+
+// The compiled regex only points to buffers of its own, which regexec reads
+// and regfree frees from any thread. The registry lock serializes the uses.
+unsafe impl Send for Regex {}
+
+impl Regex {
+    fn new(pattern: &CStr) -> io::Result<Regex> {
+        let mut regex = MaybeUninit::<libc::regex_t>::uninit();
+
+        let rc = unsafe {
+            libc::regcomp(regex.as_mut_ptr(), pattern.as_ptr(),
+                          libc::REG_EXTENDED | libc::REG_NOSUB)
+        };
+        if rc != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid regex {:?}", pattern)));
         }
-        self.buffer[digits_begin..idx].reverse();
 
-        self.buffer[idx] = b' ';
-        idx += 1;
+        Ok(Regex(unsafe { regex.assume_init() }))
+    }
 
-        let _ = self.writer.write(&self.buffer[0..idx]);
-        let _ = self.writer.write_fmt(args);
-        let _ = self.writer.write(b"\n");
-        let _ = self.writer.flush();
+    fn is_match(&self, s: &CStr) -> bool {
+        unsafe { libc::regexec(&self.0, s.as_ptr(), 0, std::ptr::null_mut(), 0) == 0 }
+    }
+}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        unsafe { libc::regfree(&mut self.0) };
+    }
+}
+
+fn invalid_input<E: error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+struct Registry {
+    types: Vec<&'static TypeEntry>,
+    patterns: Vec<(Pattern, Level)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    types: Vec::new(),
+    patterns: Vec::new(),
+});
+
+/// The level of a new log type, unless a pattern matches its name
+pub const DEFAULT_TYPE_LEVEL: Level = Level::Info;
+
+/// Registers the log type `name`, or returns it if already registered
+///
+/// Its level is set by the last pattern matching its name, if any.
+///
+/// ```
+/// use dpdk::core::log;
+///
+/// let eal = log::register("lib.eal");
+///
+/// assert_eq!(eal.name(), "lib.eal");
+/// assert_eq!(log::register("lib.eal").id(), eal.id());
+/// ```
+pub fn register(name: &str) -> LogType {
+    let mut registry = REGISTRY.lock().unwrap();
+
+    if let Some(&entry) = registry.types.iter().find(|e| e.name == name) {
+        return LogType { entry };
+    }
+
+    let level = registry
+        .patterns
+        .iter()
+        .rev()
+        .find(|(pattern, _)| pattern.matches(name).unwrap_or(false))
+        .map_or(DEFAULT_TYPE_LEVEL, |&(_, level)| level);
+
+    // registered for good, as in the lines logged by the lcores
+    let entry = Box::leak(Box::new(TypeEntry {
+        id: registry.types.len() as u32,
+        name: name.to_owned(),
+        filter: AtomicUsize::new(level as usize),
+    }));
+    registry.types.push(entry);
+
+    LogType { entry }
+}
+
+/// The registered log types, in registration order
+pub fn types() -> Vec<LogType> {
+    REGISTRY.lock().unwrap().types.iter().map(|&entry| LogType { entry }).collect()
+}
+
+// Sets the level of the types matching `pattern`, and of the ones registered
+// later, returns how many matched.
+fn set_level_matching(pattern: Pattern, level: Level) -> io::Result<usize> {
+    let mut registry = REGISTRY.lock().unwrap();
+
+    let mut matched = 0;
+    for entry in registry.types.iter() {
+        if pattern.matches(&entry.name)? {
+            entry.filter.store(level as usize, Ordering::Relaxed);
+            matched += 1;
+        }
+    }
+
+    registry.patterns.push((pattern, level));
+    Ok(matched)
+}
+
+/// Sets the level of the log types whose name matches the glob `pattern`,
+/// returns how many matched
+///
+/// The types registered later get the level too.
+pub fn set_level_pattern(pattern: &str, level: Level) -> io::Result<usize> {
+    let glob = CString::new(pattern).map_err(invalid_input)?;
+    set_level_matching(Pattern::Glob(glob), level)
+}
+
+/// Sets the level of the log types whose name matches the POSIX extended
+/// regular expression `regex`, returns how many matched
+///
+/// The types registered later get the level too.
+pub fn set_level_regexp(regex: &str, level: Level) -> io::Result<usize> {
+    let regex = CString::new(regex).map_err(invalid_input)?;
+    set_level_matching(Pattern::Regex(Regex::new(&regex)?), level)
+}
+
+/// Sets the levels from a `<glob>:<level>` argument, as the `--log-level`
+/// option, e.g. `pmd.net.*:debug` or `lib.eal:7`
+pub fn set_level_arg(arg: &str) -> io::Result<usize> {
+    let (pattern, level) = arg.rsplit_once(':').ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("no level in {:?}", arg))
+    })?;
+
+    let level = match level.parse::<usize>() {
+        Ok(n) if n <= Level::Debug as usize => Level::from(n),
+        _ => level.parse::<Level>().map_err(invalid_input)?,
+    };
+
+    set_level_pattern(pattern, level)
+}
+
+/// Writes the registered log types with their levels
+pub fn dump<W: io::Write>(w: &mut W) -> io::Result<()> {
+    for ty in types() {
+        writeln!(w, "id {}: {}, level is {}", ty.id(), ty.name(), ty.level())?;
+    }
+
+    Ok(())
+}
+
+impl LogType {
+    /// The type id, in registration order
+    pub fn id(&self) -> u32 {
+        self.entry.id
+    }
+
+    /// The type name
+    pub fn name(&self) -> &'static str {
+        &self.entry.name
+    }
+
+    /// The type log level
+    pub fn level(&self) -> Level {
+        Level::from(self.entry.filter.load(Ordering::Relaxed))
+    }
+
+    /// Sets the type log level
+    pub fn set_level(&self, level: Level) {
+        self.entry.filter.store(level as usize, Ordering::Relaxed);
+    }
+
+    /// Logs the message to the shared logger, what the macros call
+    pub fn log(&self, level: Level, file: &'static str, line: u32, args: fmt::Arguments) {
+        logger().log_type(*self, level, file, line, args);
+    }
+}
+
+impl fmt::Debug for LogType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LogType")
+            .field("id", &self.id())
+            .field("name", &self.name())
+            .field("level", &self.level())
+            .finish()
     }
 }
 
@@ -552,7 +997,7 @@ impl FromStr for Level {
                     .all(|(a, b)| (a | 0x20) == (b | 0x20))
             })
             .into_iter()
-            .map(|idx| Level::from(idx))
+            .map(Level::from)
             .next();
 
         match opt {
@@ -829,6 +1274,136 @@ mod private {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    // Records every write to the sink apart.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shared_logger_lines() {
+        const NTHREAD: usize = 4;
+        const NLINE: usize = 500;
+
+        let capture = Capture::default();
+        let logger = SharedLogger::new(Level::Info, Box::new(capture.clone()));
+
+        thread::scope(|s| {
+            for t in 0..NTHREAD {
+                let logger = &logger;
+                s.spawn(move || {
+                    for i in 0..NLINE {
+                        info!(logger, "thread {} line {}", t, i);
+                        debug!(logger, "filtered out");
+                    }
+                });
+            }
+        });
+
+        let writes = capture.0.lock().unwrap();
+        assert_eq!(writes.len(), NTHREAD * NLINE);
+
+        // one write per line, each one whole
+        for w in writes.iter() {
+            let line = std::str::from_utf8(w).unwrap();
+            assert!(line.ends_with('\n'));
+            assert_eq!(line.matches('\n').count(), 1);
+            assert_eq!(&line[23..31], " [INFO] ");
+            assert!(line.contains("src/core/log.rs:"));
+
+            let msg: Vec<_> = line.trim_end().rsplitn(4, ' ').collect();
+            assert_eq!(msg[1], "line");
+            assert_eq!(msg[3].rsplit(' ').next(), Some("thread"));
+        }
+    }
+
+    #[test]
+    fn shared_logger_level() {
+        let capture = Capture::default();
+        let logger = SharedLogger::new(Level::Warn, Box::new(capture.clone()));
+
+        notice!(logger, "filtered out");
+        logger.set_level(Level::Notice);
+        assert_eq!(logger.level(), Level::Notice);
+        notice!(logger, "logged");
+
+        let previous = logger.set_sink(Box::new(io::sink()));
+        drop(previous);
+        notice!(logger, "to the new sink");
+
+        let writes = capture.0.lock().unwrap();
+        assert_eq!(writes.len(), 1);
+        assert!(writes[0].ends_with(b" logged\n"));
+    }
+
+    #[test]
+    fn log_type_levels() {
+        let rx = register("test.pmd.net.rx");
+        let tx = register("test.pmd.net.tx");
+        let eal = register("test.lib.eal");
+
+        assert_eq!(register("test.pmd.net.rx").id(), rx.id());
+        assert_ne!(rx.id(), tx.id());
+        assert_eq!(rx.level(), DEFAULT_TYPE_LEVEL);
+
+        assert_eq!(set_level_pattern("test.pmd.*", Level::Debug).unwrap(), 2);
+        assert_eq!(rx.level(), Level::Debug);
+        assert_eq!(tx.level(), Level::Debug);
+        assert_eq!(eal.level(), DEFAULT_TYPE_LEVEL);
+
+        assert_eq!(set_level_regexp("^test\\..*\\.(tx|eal)$", Level::Error).unwrap(), 2);
+        assert_eq!(rx.level(), Level::Debug);
+        assert_eq!(tx.level(), Level::Error);
+        assert_eq!(eal.level(), Level::Error);
+
+        // the patterns apply to the types registered later, the last one first
+        assert_eq!(register("test.pmd.crypto").level(), Level::Debug);
+        assert_eq!(register("test.pmd.net.tx2").level(), Level::Debug);
+        assert_eq!(register("test.pmd.crypto.tx").level(), Level::Error);
+
+        assert_eq!(set_level_arg("test.lib.*:notice").unwrap(), 1);
+        assert_eq!(eal.level(), Level::Notice);
+        assert_eq!(set_level_arg("test.lib.*:3").unwrap(), 1);
+        assert_eq!(eal.level(), Level::Crit);
+
+        assert!(set_level_arg("test.lib.eal").is_err());
+        assert!(set_level_arg("test.lib.eal:loud").is_err());
+        assert!(set_level_regexp("test.(", Level::Debug).is_err());
+
+        let mut out = Vec::new();
+        dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("test.lib.eal, level is CRIT"));
+    }
+
+    #[test]
+    fn log_type_lines() {
+        let capture = Capture::default();
+        let logger = SharedLogger::new(Level::None, Box::new(capture.clone()));
+        let ty = register("test.log.lines");
+        ty.set_level(Level::Debug);
+
+        // filtered by the type level only
+        logger.log_type(ty, Level::Debug, file!(), line!(), format_args!("queue {}", 3));
+        ty.set_level(Level::Info);
+        logger.log_type(ty, Level::Debug, file!(), line!(), format_args!("filtered out"));
+
+        let writes = capture.0.lock().unwrap();
+        assert_eq!(writes.len(), 1);
+        assert!(writes[0].ends_with(b" test.log.lines: queue 3\n"));
+    }
 
     #[test]
     #[should_panic]
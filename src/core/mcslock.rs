@@ -0,0 +1,410 @@
+//! A MCS queue lock.
+//!
+//! Each lcore waiting for a MCS lock spins on its own queue node instead of
+//! the shared lock word, so that a contended lock hands over with a single
+//! cache line transfer instead of bouncing the lock word between all waiters.
+//!
+//! The node is usually allocated on the stack of the locker, it has to be
+//! pinned until the lock is released:
+//!
+//! # Example
+//! ```
+//! use std::pin::pin;
+//! use dpdk::core::mcslock::{McsLock, McsNode};
+//!
+//! let lk = McsLock::new(0);
+//!
+//! let mut node = pin!(McsNode::new());
+//! *lk.lock(node.as_mut()) += 1;
+//!
+//! assert_eq!(*lk.lock(node.as_mut()), 1);
+//! ```
+//!
+//! The raw `RawMcsLock` is a bare lock word, for the data living outside the
+//! lock. The basic lock primitives, all taking the caller's node:
+//!
+//! - `lock`
+//! - `unlock`
+//! - `trylock`
+
+use std::fmt;
+use std::ptr;
+use std::process;
+use std::cell::UnsafeCell;
+use std::hint::spin_loop;
+use std::marker::{PhantomData, PhantomPinned};
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::thread::panicking;
+
+/// The queue node of a lcore holding or waiting for a MCS lock
+///
+/// It is aligned on a cache line, so that the waiters spin on distinct lines.
+#[repr(align(64))]
+pub struct McsNode {
+    locked: AtomicBool,           // set while waiting for the previous node
+    next: AtomicPtr<McsNode>,     // the node waiting behind, if any
+    queued: AtomicBool,           // in a queue, must not go away
+    _pinned: PhantomPinned,
+}
+
+impl McsNode {
+    /// Construct an unqueued node
+    pub const fn new() -> Self {
+        McsNode {
+            locked: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(false),
+            _pinned: PhantomPinned,
+        }
+    }
+}
+
+impl Default for McsNode {
+    fn default() -> Self {
+        McsNode::new()
+    }
+}
+
+impl Drop for McsNode {
+    fn drop(&mut self) {
+        // other lcores may still write to it, e.g. its guard was forgotten
+        if self.queued.load(Ordering::Acquire) {
+            process::abort();
+        }
+    }
+}
+
+/// The raw MCS lock type
+pub struct RawMcsLock {
+    /// The last node of the queue, null if unlocked
+    tail: AtomicPtr<McsNode>,
+}
+
+unsafe impl Sync for RawMcsLock {}
+unsafe impl Send for RawMcsLock {}
+
+impl Default for RawMcsLock {
+    /// Construct the MCS lock with unlocked state
+    fn default() -> Self {
+        RawMcsLock::new()
+    }
+}
+
+impl Drop for RawMcsLock {
+    fn drop(&mut self) {
+        if self.is_locked() && !panicking() {
+            panic!("mcslock still locked");
+        }
+    }
+}
+
+impl RawMcsLock {
+    /// Construct the MCS lock with unlocked state
+    pub const fn new() -> Self {
+        RawMcsLock {
+            tail: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Take the MCS lock, queueing `me` behind the current waiters
+    ///
+    /// # Safety
+    ///
+    /// `me` must not be queued already, and must stay at the same address
+    /// until `unlock` is called with it.
+    pub unsafe fn lock(&self, me: &McsNode) {
+        let me_ptr = me as *const McsNode as *mut McsNode;
+
+        me.queued.store(true, Ordering::Relaxed);
+        me.locked.store(true, Ordering::Relaxed);
+        me.next.store(ptr::null_mut(), Ordering::Relaxed);
+
+        // the node has to be initialized before being visible
+        let prev = self.tail.swap(me_ptr, Ordering::AcqRel);
+        if prev.is_null() {
+            // the queue was empty
+            return;
+        }
+
+        (*prev).next.store(me_ptr, Ordering::Release);
+
+        while me.locked.load(Ordering::Acquire) {
+            spin_loop();
+        }
+    }
+
+    /// Release the MCS lock, handing it to the next waiter if any
+    ///
+    /// # Safety
+    ///
+    /// `me` must be the node which took the lock.
+    pub unsafe fn unlock(&self, me: &McsNode) {
+        let me_ptr = me as *const McsNode as *mut McsNode;
+
+        if me.next.load(Ordering::Acquire).is_null() {
+            if self
+                .tail
+                .compare_exchange(me_ptr, ptr::null_mut(), Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                me.queued.store(false, Ordering::Release);
+                return;
+            }
+
+            // a waiter swapped the tail but did not link itself yet
+            while me.next.load(Ordering::Acquire).is_null() {
+                spin_loop();
+            }
+        }
+
+        let next = me.next.load(Ordering::Acquire);
+        (*next).locked.store(false, Ordering::Release);
+        me.queued.store(false, Ordering::Release);
+    }
+
+    /// Try to take the MCS lock, fails if anyone holds it
+    ///
+    /// # Safety
+    ///
+    /// Same as `lock`, when it succeeds.
+    pub unsafe fn trylock(&self, me: &McsNode) -> bool {
+        let me_ptr = me as *const McsNode as *mut McsNode;
+
+        me.locked.store(false, Ordering::Relaxed);
+        me.next.store(ptr::null_mut(), Ordering::Relaxed);
+
+        let taken = self
+            .tail
+            .compare_exchange(ptr::null_mut(), me_ptr, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        if taken {
+            me.queued.store(true, Ordering::Relaxed);
+        }
+
+        taken
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Acquire).is_null()
+    }
+}
+
+/// A MCS lock protecting a `T`
+///
+/// # Examples
+///
+/// ```
+/// use std::pin::pin;
+/// use std::sync::Arc;
+/// use std::thread;
+/// use dpdk::core::mcslock::{McsLock, McsNode};
+///
+/// let lk = Arc::new(McsLock::new(0));
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let lk = lk.clone();
+///         thread::spawn(move || {
+///             let mut node = pin!(McsNode::new());
+///             for _ in 0..100 {
+///                 *lk.lock(node.as_mut()) += 1;
+///             }
+///         })
+///     })
+///     .collect();
+///
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+///
+/// let mut node = pin!(McsNode::new());
+/// assert_eq!(*lk.lock(node.as_mut()), 400);
+/// ```
+pub struct McsLock<T: ?Sized> {
+    raw: RawMcsLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for McsLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for McsLock<T> {}
+
+impl<T> McsLock<T> {
+    /// Construct the MCS lock with unlocked state
+    pub const fn new(data: T) -> Self {
+        McsLock {
+            raw: RawMcsLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the MCS lock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> McsLock<T> {
+    /// Take the MCS lock with `node`, released when the guard is dropped
+    ///
+    /// # Panics
+    ///
+    /// Panics if `node` is already queued.
+    pub fn lock<'a>(&'a self, node: Pin<&'a mut McsNode>) -> McsLockGuard<'a, T> {
+        let node = node.into_ref().get_ref();
+        assert!(!node.queued.load(Ordering::Relaxed), "mcs node already queued");
+
+        // the pin and the guard borrow keep the node in place until unlocked,
+        // a forgotten guard makes the node abort when dropped
+        unsafe { self.raw.lock(node) };
+        McsLockGuard::new(self, node)
+    }
+
+    /// Try to take the MCS lock with `node`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `node` is already queued.
+    pub fn try_lock<'a>(&'a self, node: Pin<&'a mut McsNode>) -> Option<McsLockGuard<'a, T>> {
+        let node = node.into_ref().get_ref();
+        assert!(!node.queued.load(Ordering::Relaxed), "mcs node already queued");
+
+        if unsafe { self.raw.trylock(node) } {
+            Some(McsLockGuard::new(self, node))
+        } else {
+            None
+        }
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw MCS lock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &RawMcsLock {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for McsLock<T> {
+    fn default() -> Self {
+        McsLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for McsLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut node = Box::pin(McsNode::new());
+        let res = match self.try_lock(node.as_mut()) {
+            Some(guard) => f.debug_struct("McsLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("McsLock").field("data", &"<locked>").finish(),
+        };
+        res
+    }
+}
+
+/// The RAII guard of a [`McsLock`], releasing it on drop.
+///
+/// [`McsLock`]: struct.McsLock.html
+pub struct McsLockGuard<'a, T: ?Sized> {
+    lock: &'a McsLock<T>,
+    node: &'a McsNode,
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for McsLockGuard<'_, T> {}
+
+impl<'a, T: ?Sized> McsLockGuard<'a, T> {
+    fn new(lock: &'a McsLock<T>, node: &'a McsNode) -> Self {
+        McsLockGuard {
+            lock,
+            node,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for McsLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for McsLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for McsLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw.unlock(self.node) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn mcslock_trylock() {
+        let lk = McsLock::new(0);
+        let mut a = pin!(McsNode::new());
+        let mut b = pin!(McsNode::new());
+
+        {
+            let mut guard = lk.try_lock(a.as_mut()).unwrap();
+            *guard += 1;
+            assert!(lk.is_locked());
+            assert!(lk.try_lock(b.as_mut()).is_none());
+        }
+        assert!(!lk.is_locked());
+
+        *lk.lock(b.as_mut()) += 1;
+        assert_eq!(lk.into_inner(), 2);
+    }
+
+    #[test]
+    fn mcslock_contended() {
+        const NTHREAD: usize = 4;
+        const NITER: usize = 10_000;
+
+        let lk = Arc::new(McsLock::new(0));
+
+        let threads: Vec<_> = (0..NTHREAD)
+            .map(|_| {
+                let lk = lk.clone();
+                thread::spawn(move || {
+                    let mut node = pin!(McsNode::new());
+                    for _ in 0..NITER {
+                        *lk.lock(node.as_mut()) += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let lk = Arc::try_unwrap(lk).ok().unwrap();
+        assert_eq!(lk.into_inner(), NTHREAD * NITER);
+    }
+}
@@ -1,30 +1,73 @@
 use libc::{syscall, SYS_gettid};
 use std::cell::Cell;
 use std::io;
+#[cfg(test)]
+use std::path::PathBuf;
 use std::os::unix::io::RawFd;
+use std::sync::Once;
+use std::thread::LocalKey;
 
+pub mod barrier;
 pub mod cycles;
+pub mod executor;
+pub mod futexlock;
+pub mod isolation;
+pub mod keepalive;
 pub mod lcore;
+pub mod lock;
+pub mod lockdep;
+#[cfg(feature = "lock_stat")]
+pub mod lockstat;
 pub mod log;
+pub mod mcslock;
+pub mod pflock;
+pub mod power;
+pub mod rcu;
 pub mod rwlock;
+pub mod seqlock;
+pub mod shmlock;
 pub mod spinlock;
 pub mod thread;
+pub mod ticketlock;
+pub mod tsx;
 
 thread_local! {
-    static CURRENT_TID: Cell<i32> = Cell::new(-1);
+    static CURRENT_TID: Cell<i32> = const { Cell::new(-1) };
+    static CURRENT_PID: Cell<i32> = const { Cell::new(-1) };
 }
 
-pub fn gettid() -> i32 {
-    CURRENT_TID.with(|current| {
+/// The default sysfs cpu root.
+pub const SYSFS_CPU_ROOT: &str = "/sys/devices/system/cpu";
+
+static AT_FORK: Once = Once::new();
+
+// The forked child is a new process, its only thread has new ids.
+extern "C" fn forget_ids() {
+    let _ = CURRENT_TID.try_with(|current| current.set(-1));
+    let _ = CURRENT_PID.try_with(|current| current.set(-1));
+}
+
+fn cached_id(id: &'static LocalKey<Cell<i32>>, get: impl FnOnce() -> i32) -> i32 {
+    id.with(|current| {
         if current.get() == -1 {
-            let tid = unsafe { syscall(SYS_gettid) as i32 };
-            current.set(tid);
+            AT_FORK.call_once(|| unsafe {
+                libc::pthread_atfork(None, None, Some(forget_ids));
+            });
+            current.set(get());
         }
 
         current.get()
     })
 }
 
+pub fn gettid() -> i32 {
+    cached_id(&CURRENT_TID, || unsafe { syscall(SYS_gettid) as i32 })
+}
+
+pub fn getpid() -> i32 {
+    cached_id(&CURRENT_PID, || unsafe { libc::getpid() })
+}
+
 #[doc(hidden)]
 pub trait IsMinusOne {
     fn is_minus_one(&self) -> bool;
@@ -90,3 +133,14 @@ pub fn close(fd: RawFd) -> io::Result<()> {
     let result = unsafe { libc::close(fd) };
     cvt(result).map(drop)
 }
+
+// An empty directory unique to `name` and the process, to fake the sysfs
+// trees of the tests in.
+#[cfg(test)]
+pub(crate) fn temp_tree(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("dpdk-{}-{}", name, std::process::id()));
+
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    root
+}
@@ -0,0 +1,454 @@
+//! A phase-fair reader-writer lock.
+//!
+//! Readers and writers alternate in phases: a writer waits at most for the
+//! readers already in, and the readers arriving after a writer wait at most
+//! for that writer. Neither side starves, unlike the reader-preferring
+//! [`RwLock`].
+//!
+//! # Example
+//! ```
+//! use dpdk::core::pflock::PfLock;
+//!
+//! let lk = PfLock::new(0);
+//!
+//! *lk.write() += 10;
+//!
+//! assert_eq!(*lk.read(), 10);
+//! ```
+//!
+//! The raw `RawPfLock` is a bare lock word, for the data living outside the
+//! lock. The basic lock primitives:
+//!
+//! - `read_lock`
+//! - `read_unlock`
+//! - `write_lock`
+//! - `write_unlock`
+//!
+//! along with `read_trylock` and `write_trylock`.
+//!
+//! [`RwLock`]: ../rwlock/struct.RwLock.html
+
+use std::fmt;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::thread::panicking;
+
+// The writer bits in the reader ticket.
+const WBITS: u16 = 0x3;
+
+// A writer is present.
+const PRES: u16 = 0x2;
+
+// The phase of the present writer.
+const PHID: u16 = 0x1;
+
+// The reader bits.
+const LSB: u16 = 0xfff0;
+
+// A reader increment.
+const RINC: u16 = 0x10;
+
+/// The raw phase-fair lock type
+pub struct RawPfLock {
+    rd_in: AtomicU16,  // readers in, plus the present writer bits
+    rd_out: AtomicU16, // readers out
+    wr_in: AtomicU16,  // next writer ticket
+    wr_out: AtomicU16, // writer ticket being served
+}
+
+impl Default for RawPfLock {
+    fn default() -> Self {
+        RawPfLock::new()
+    }
+}
+
+impl Drop for RawPfLock {
+    fn drop(&mut self) {
+        if self.is_locked() && !panicking() {
+            panic!("pflock still locked");
+        }
+    }
+}
+
+impl RawPfLock {
+    /// Construct the pflock with unlocked state
+    pub const fn new() -> Self {
+        RawPfLock {
+            rd_in: AtomicU16::new(0),
+            rd_out: AtomicU16::new(0),
+            wr_in: AtomicU16::new(0),
+            wr_out: AtomicU16::new(0),
+        }
+    }
+
+    /// Take a read lock, waiting for the present writer if any
+    pub fn read_lock(&self) {
+        let w = self.rd_in.fetch_add(RINC, Ordering::Acquire) & WBITS;
+        if w == 0 {
+            return;
+        }
+
+        // wait until the writer of this phase is gone
+        while self.rd_in.load(Ordering::Acquire) & WBITS == w {
+            spin_loop();
+        }
+    }
+
+    /// Try to take a read lock, fails if a writer is present
+    pub fn read_trylock(&self) -> bool {
+        let mut rd_in = self.rd_in.load(Ordering::Relaxed);
+
+        loop {
+            if rd_in & WBITS != 0 {
+                return false;
+            }
+
+            match self.rd_in.compare_exchange_weak(
+                rd_in,
+                rd_in.wrapping_add(RINC),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(v) => rd_in = v,
+            }
+        }
+    }
+
+    /// Release a read lock
+    pub fn read_unlock(&self) {
+        self.rd_out.fetch_add(RINC, Ordering::Release);
+    }
+
+    /// Take a write lock, waiting for the previous writers and for the readers
+    /// already in
+    pub fn write_lock(&self) {
+        // the writers are served in order
+        let ticket = self.wr_in.fetch_add(1, Ordering::Relaxed);
+        while self.wr_out.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+
+        // block the readers to come, then wait for those in
+        let w = PRES | (ticket & PHID);
+        let readers = self.rd_in.fetch_add(w, Ordering::Relaxed);
+        while self.rd_out.load(Ordering::Acquire) != readers {
+            spin_loop();
+        }
+    }
+
+    /// Try to take a write lock, fails if anyone holds or waits for it
+    pub fn write_trylock(&self) -> bool {
+        let ticket = self.wr_out.load(Ordering::Relaxed);
+        if self
+            .wr_in
+            .compare_exchange(ticket, ticket.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+
+        // no reader may be in, nor enter meanwhile
+        let readers = self.rd_in.load(Ordering::Relaxed);
+        let w = PRES | (ticket & PHID);
+        if readers & WBITS == 0
+            && self.rd_out.load(Ordering::Acquire) == readers
+            && self
+                .rd_in
+                .compare_exchange(readers, readers | w, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            return true;
+        }
+
+        // hand the ticket over to the next writer
+        self.wr_out.fetch_add(1, Ordering::Release);
+        false
+    }
+
+    /// Release a write lock
+    pub fn write_unlock(&self) {
+        // let the readers in, then the next writer
+        self.rd_in.fetch_and(LSB, Ordering::Release);
+        self.wr_out.fetch_add(1, Ordering::Release);
+    }
+
+    /// Test if a read or write lock is held
+    pub fn is_locked(&self) -> bool {
+        let rd_in = self.rd_in.load(Ordering::Acquire);
+        rd_in & WBITS != 0 || rd_in & LSB != self.rd_out.load(Ordering::Acquire)
+    }
+}
+
+/// A phase-fair reader-writer lock protecting a `T`
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use dpdk::core::pflock::PfLock;
+///
+/// let lk = Arc::new(PfLock::new(Vec::new()));
+///
+/// let writers: Vec<_> = (0..4)
+///     .map(|i| {
+///         let lk = lk.clone();
+///         thread::spawn(move || lk.write().push(i))
+///     })
+///     .collect();
+///
+/// for w in writers {
+///     w.join().unwrap();
+/// }
+///
+/// assert_eq!(lk.read().len(), 4);
+/// ```
+pub struct PfLock<T: ?Sized> {
+    raw: RawPfLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for PfLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for PfLock<T> {}
+
+impl<T> PfLock<T> {
+    /// Construct the pflock with unlocked state
+    pub const fn new(data: T) -> Self {
+        PfLock {
+            raw: RawPfLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the pflock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> PfLock<T> {
+    /// Take a read lock, released when the guard is dropped
+    pub fn read(&self) -> PfLockReadGuard<'_, T> {
+        self.raw.read_lock();
+        PfLockReadGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Take a write lock, released when the guard is dropped
+    pub fn write(&self) -> PfLockWriteGuard<'_, T> {
+        self.raw.write_lock();
+        PfLockWriteGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Try to take a read lock, returns `None` if a writer is present
+    pub fn try_read(&self) -> Option<PfLockReadGuard<'_, T>> {
+        if self.raw.read_trylock() {
+            Some(PfLockReadGuard {
+                lock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Try to take a write lock, returns `None` if anyone holds or waits for it
+    pub fn try_write(&self) -> Option<PfLockWriteGuard<'_, T>> {
+        if self.raw.write_trylock() {
+            Some(PfLockWriteGuard {
+                lock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw pflock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &RawPfLock {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for PfLock<T> {
+    fn default() -> Self {
+        PfLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for PfLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("PfLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("PfLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// The RAII guard of a read lock on a [`PfLock`], releasing it on drop.
+///
+/// [`PfLock`]: struct.PfLock.html
+pub struct PfLockReadGuard<'a, T: ?Sized> {
+    lock: &'a PfLock<T>,
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for PfLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for PfLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for PfLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.read_unlock();
+    }
+}
+
+/// The RAII guard of a write lock on a [`PfLock`], releasing it on drop.
+///
+/// [`PfLock`]: struct.PfLock.html
+pub struct PfLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a PfLock<T>,
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for PfLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for PfLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for PfLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for PfLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.write_unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn pflock_debug() {
+        let lk = PfLock::new(1);
+        assert_eq!(format!("{:?}", lk), "PfLock { data: 1 }");
+
+        let _w = lk.write();
+        assert_eq!(format!("{:?}", lk), r#"PfLock { data: "<locked>" }"#);
+    }
+
+    #[test]
+    fn pflock_read_write() {
+        const NWORKER: usize = 4;
+        const NITER: usize = 1000;
+
+        let lk = Arc::new(PfLock::new((0usize, 0usize)));
+
+        let threads: Vec<_> = (0..NWORKER)
+            .map(|_| {
+                let lk = lk.clone();
+                thread::spawn(move || {
+                    for _ in 0..NITER {
+                        {
+                            let mut w = lk.write();
+                            w.0 += 1;
+                            w.1 += 1;
+                        }
+
+                        let r = lk.read();
+                        assert_eq!(r.0, r.1);
+                    }
+                })
+            })
+            .collect();
+
+        for h in threads {
+            h.join().unwrap();
+        }
+
+        let lk = Arc::try_unwrap(lk).ok().unwrap();
+        assert_eq!(lk.into_inner(), (NWORKER * NITER, NWORKER * NITER));
+    }
+
+    // The readers overlap, yielding while holding the lock, so that it is
+    // never free: a reader-preferring lock would starve the writer.
+    #[test]
+    fn pflock_writer_not_starved() {
+        const NREADER: usize = 2;
+
+        let lk = Arc::new(RawPfLock::new());
+        let stop = Arc::new(AtomicBool::new(false));
+        let reads = Arc::new(AtomicUsize::new(0));
+
+        let readers: Vec<_> = (0..NREADER)
+            .map(|_| {
+                let lk = lk.clone();
+                let stop = stop.clone();
+                let reads = reads.clone();
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        lk.read_lock();
+                        reads.fetch_add(1, Ordering::Relaxed);
+                        thread::yield_now();
+                        lk.read_unlock();
+                    }
+                })
+            })
+            .collect();
+
+        while reads.load(Ordering::Relaxed) < 100 {
+            thread::yield_now();
+        }
+
+        for _ in 0..10 {
+            let start = Instant::now();
+            lk.write_lock();
+            let latency = start.elapsed();
+            lk.write_unlock();
+
+            assert!(latency < Duration::from_secs(1), "writer waited {:?}", latency);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,489 @@
+//! Power management for polling lcores.
+//!
+//! A pinned lcore polling an idle device burns a whole core at full frequency.
+//! This module provides two knobs:
+//!
+//! - [`Backoff`]: an adaptive idle policy driven by the number of consecutive
+//!   empty polls. It escalates from spinning, to `pause`, to `tpause` (when
+//!   CPUID reports WAITPKG) and finally to short sleeps, and resets as soon as
+//!   a poll returns work.
+//! - [`CpuFreq`]: frequency scaling through the sysfs cpufreq `userspace`
+//!   governor. The sysfs root is configurable so that it can be pointed to a
+//!   fake tree.
+//!
+//! A `Backoff` given a `CpuFreq` lowers the frequency when it starts sleeping
+//! and restores the maximum frequency on traffic.
+//!
+//! # Example
+//!
+//! ```
+//! use dpdk::core::power;
+//!
+//! # fn rx_burst() -> usize { 0 }
+//! let mut backoff = power::Backoff::new(power::IdlePolicy::default());
+//!
+//! for _ in 0..1000 {
+//!     let n = rx_burst();
+//!     backoff.idle(n);
+//! }
+//!
+//! assert_eq!(backoff.empty_polls(), 1000);
+//! ```
+//!
+//! [`Backoff`]: struct.Backoff.html
+//! [`CpuFreq`]: struct.CpuFreq.html
+
+use super::SYSFS_CPU_ROOT;
+use super::cycles::get_tsc_cycles;
+use std::io;
+use std::fs;
+use std::thread;
+use std::hint::spin_loop;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::arch::asm;
+use std::arch::x86_64::__cpuid_count;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+// 0 for unknown, 1 for absent, 2 for present
+static WAITPKG: AtomicU8 = AtomicU8::new(0);
+
+/// Test if the CPU supports the `umonitor`/`umwait`/`tpause` instructions.
+///
+/// The CPUID lookup is done once and cached.
+pub fn cpu_has_waitpkg() -> bool {
+    match WAITPKG.load(Ordering::Relaxed) {
+        0 => {
+            // CPUID.(EAX=07H, ECX=0H):ECX.WAITPKG[bit 5]
+            let max_leaf = __cpuid_count(0, 0).eax;
+            let present = max_leaf >= 7 && __cpuid_count(7, 0).ecx & (1 << 5) != 0;
+
+            WAITPKG.store(if present { 2 } else { 1 }, Ordering::Relaxed);
+            present
+        }
+        v => v == 2,
+    }
+}
+
+/// Pauses the core until the TSC reaches `deadline`.
+///
+/// Uses `tpause` in the C0.2 state (the deeper, slower to wake one) if the CPU
+/// supports it, and a `pause` loop otherwise.
+#[inline]
+pub fn pause_until(deadline: u64) {
+    if cpu_has_waitpkg() {
+        unsafe {
+            // ecx bit 0 cleared selects C0.2
+            asm!(
+                "tpause {ctrl:e}",
+                ctrl = in(reg) 0u32,
+                in("eax") deadline as u32,
+                in("edx") (deadline >> 32) as u32,
+                options(nomem, nostack),
+            );
+        }
+    } else {
+        while get_tsc_cycles() < deadline {
+            spin_loop();
+        }
+    }
+}
+
+/// Waits until `addr` is written or the TSC reaches `deadline`.
+///
+/// Uses `umonitor`/`umwait` if the CPU supports it. Otherwise the value at
+/// `addr` is polled against `expected`, `pause`ing in between.
+///
+/// Like any monitor based wait, it may return early for no reason.
+#[inline]
+pub fn monitor_until(addr: &AtomicU64, expected: u64, deadline: u64) {
+    if cpu_has_waitpkg() {
+        unsafe {
+            asm!("umonitor {addr}", addr = in(reg) addr.as_ptr(), options(nostack));
+
+            // the value may have changed before the monitor was armed
+            if addr.load(Ordering::Acquire) != expected {
+                return;
+            }
+
+            asm!(
+                "umwait {ctrl:e}",
+                ctrl = in(reg) 0u32,
+                in("eax") deadline as u32,
+                in("edx") (deadline >> 32) as u32,
+                options(nostack),
+            );
+        }
+    } else {
+        while get_tsc_cycles() < deadline && addr.load(Ordering::Acquire) == expected {
+            spin_loop();
+        }
+    }
+}
+
+/// The thresholds of an adaptive [`Backoff`], in consecutive empty polls.
+///
+/// [`Backoff`]: struct.Backoff.html
+#[derive(Clone, Debug)]
+pub struct IdlePolicy {
+    /// Empty polls to keep spinning before pausing
+    pub spin_polls: u32,
+    /// Empty polls to keep `pause`ing before `tpause`ing
+    pub pause_polls: u32,
+    /// Empty polls to keep `tpause`ing before sleeping
+    pub wait_polls: u32,
+    /// Number of `pause` per empty poll at the pause level
+    pub pauses: u32,
+    /// Cycles to `tpause` per empty poll at the wait level
+    pub wait_cycles: u64,
+    /// Time to sleep per empty poll at the sleep level
+    pub sleep: Duration,
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        IdlePolicy {
+            spin_polls: 100,
+            pause_polls: 1_000,
+            wait_polls: 10_000,
+            pauses: 16,
+            wait_cycles: 10_000,
+            sleep: Duration::from_micros(100),
+        }
+    }
+}
+
+/// How deep a [`Backoff`] currently idles.
+///
+/// [`Backoff`]: struct.Backoff.html
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum IdleLevel {
+    /// Busy polling
+    Spin,
+    /// `pause` between polls
+    Pause,
+    /// `tpause` between polls, or `pause` for as long without WAITPKG
+    Wait,
+    /// Sleep between polls
+    Sleep,
+}
+
+/// An adaptive idle policy for a polling loop.
+///
+/// Call [`idle`] after every poll with the amount of work it returned.
+///
+/// [`idle`]: struct.Backoff.html#method.idle
+pub struct Backoff {
+    policy: IdlePolicy,
+    empty: u32,
+    freq: Option<CpuFreq>,
+}
+
+impl Backoff {
+    /// Constructs a backoff with the specified thresholds.
+    pub fn new(policy: IdlePolicy) -> Backoff {
+        Backoff {
+            policy,
+            empty: 0,
+            freq: None,
+        }
+    }
+
+    /// Scales the frequency of the lcore cpu along with the idle level: down
+    /// to the minimum when sleeping, back up to the maximum on traffic.
+    pub fn cpufreq(mut self, freq: CpuFreq) -> Backoff {
+        self.freq = Some(freq);
+        self
+    }
+
+    /// The number of consecutive empty polls
+    pub fn empty_polls(&self) -> u32 {
+        self.empty
+    }
+
+    /// The current idle level
+    pub fn level(&self) -> IdleLevel {
+        let p = &self.policy;
+
+        if self.empty < p.spin_polls {
+            IdleLevel::Spin
+        } else if self.empty < p.spin_polls.saturating_add(p.pause_polls) {
+            IdleLevel::Pause
+        } else if self.empty < p.spin_polls.saturating_add(p.pause_polls).saturating_add(p.wait_polls) {
+            IdleLevel::Wait
+        } else {
+            IdleLevel::Sleep
+        }
+    }
+
+    /// Accounts a poll which returned `work` items, and idles according to
+    /// the number of consecutive empty polls.
+    ///
+    /// Errors from the cpufreq interface are ignored, the frequency is left
+    /// as is.
+    pub fn idle(&mut self, work: usize) {
+        if work > 0 {
+            if self.level() == IdleLevel::Sleep {
+                if let Some(ref mut freq) = self.freq {
+                    let _ = freq.max();
+                }
+            }
+            self.empty = 0;
+            return;
+        }
+
+        self.empty = self.empty.saturating_add(1);
+
+        match self.level() {
+            IdleLevel::Spin => {}
+            IdleLevel::Pause => {
+                for _ in 0..self.policy.pauses {
+                    spin_loop();
+                }
+            }
+            IdleLevel::Wait => pause_until(get_tsc_cycles() + self.policy.wait_cycles),
+            IdleLevel::Sleep => {
+                let p = &self.policy;
+                if self.empty == p.spin_polls.saturating_add(p.pause_polls).saturating_add(p.wait_polls) {
+                    if let Some(ref mut freq) = self.freq {
+                        let _ = freq.min();
+                    }
+                }
+                thread::sleep(self.policy.sleep);
+            }
+        }
+    }
+}
+
+/// Frequency scaling of one cpu through the sysfs cpufreq interface.
+///
+/// The cpu is switched to the `userspace` governor on construction, and back
+/// to its previous governor on drop.
+///
+/// Frequencies are in kHz, indexes refer to [`freqs`] which is sorted from the
+/// highest frequency to the lowest.
+///
+/// [`freqs`]: struct.CpuFreq.html#method.freqs
+pub struct CpuFreq {
+    dir: PathBuf,
+    governor: String, // to restore on drop
+    freqs: Vec<u32>,
+    cur: usize,
+}
+
+impl CpuFreq {
+    /// Takes control of the frequency of `cpu`.
+    pub fn new(cpu: usize) -> io::Result<CpuFreq> {
+        CpuFreq::with_root(Path::new(SYSFS_CPU_ROOT), cpu)
+    }
+
+    /// Takes control of the frequency of `cpu`, with `root` standing for
+    /// `/sys/devices/system/cpu`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the cpufreq files cannot be read or written, and with
+    /// `ENOTSUP` if no frequency is available.
+    pub fn with_root(root: &Path, cpu: usize) -> io::Result<CpuFreq> {
+        let dir = root.join(format!("cpu{}", cpu)).join("cpufreq");
+
+        let governor = read_trimmed(&dir.join("scaling_governor"))?;
+
+        let mut freqs = read_trimmed(&dir.join("scaling_available_frequencies"))?
+            .split_whitespace()
+            .map(|f| f.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if freqs.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+        freqs.sort_unstable_by(|a, b| b.cmp(a));
+        freqs.dedup();
+
+        let cur_freq = read_trimmed(&dir.join("scaling_cur_freq"))?
+            .parse::<u32>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // the closest available frequency
+        let cur = freqs
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &f)| (i64::from(f) - i64::from(cur_freq)).abs())
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+
+        fs::write(dir.join("scaling_governor"), "userspace")?;
+
+        Ok(CpuFreq {
+            dir,
+            governor,
+            freqs,
+            cur,
+        })
+    }
+
+    /// The available frequencies, from the highest to the lowest
+    pub fn freqs(&self) -> &[u32] {
+        &self.freqs
+    }
+
+    /// The index of the current frequency
+    pub fn get(&self) -> usize {
+        self.cur
+    }
+
+    /// Sets the frequency by index.
+    ///
+    /// Returns whether the frequency changed.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `EINVAL` if the index is out of range.
+    pub fn set(&mut self, idx: usize) -> io::Result<bool> {
+        if idx >= self.freqs.len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if idx == self.cur {
+            return Ok(false);
+        }
+
+        fs::write(self.dir.join("scaling_setspeed"), self.freqs[idx].to_string())?;
+        self.cur = idx;
+
+        Ok(true)
+    }
+
+    /// Steps up to the next higher frequency.
+    pub fn up(&mut self) -> io::Result<bool> {
+        self.set(self.cur.saturating_sub(1))
+    }
+
+    /// Steps down to the next lower frequency.
+    pub fn down(&mut self) -> io::Result<bool> {
+        self.set((self.cur + 1).min(self.freqs.len() - 1))
+    }
+
+    /// Sets the highest frequency.
+    pub fn max(&mut self) -> io::Result<bool> {
+        self.set(0)
+    }
+
+    /// Sets the lowest frequency.
+    pub fn min(&mut self) -> io::Result<bool> {
+        self.set(self.freqs.len() - 1)
+    }
+}
+
+impl Drop for CpuFreq {
+    fn drop(&mut self) {
+        let _ = fs::write(self.dir.join("scaling_governor"), &self.governor);
+    }
+}
+
+fn read_trimmed(path: &Path) -> io::Result<String> {
+    fs::read_to_string(path).map(|s| s.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::temp_tree;
+
+    fn fake_sysfs(name: &str) -> PathBuf {
+        let root = temp_tree(&format!("power-{}", name));
+        let dir = root.join("cpu1").join("cpufreq");
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(dir.join("scaling_governor"), "ondemand\n").unwrap();
+        fs::write(dir.join("scaling_available_frequencies"), "1200000 2400000 1800000 \n").unwrap();
+        fs::write(dir.join("scaling_cur_freq"), "2399000\n").unwrap();
+        fs::write(dir.join("scaling_setspeed"), "<unsupported>\n").unwrap();
+
+        root
+    }
+
+    fn read(root: &Path, file: &str) -> String {
+        read_trimmed(&root.join("cpu1").join("cpufreq").join(file)).unwrap()
+    }
+
+    #[test]
+    fn cpufreq_scaling() {
+        let root = fake_sysfs("scaling");
+
+        {
+            let mut freq = CpuFreq::with_root(&root, 1).unwrap();
+            assert_eq!(read(&root, "scaling_governor"), "userspace");
+            assert_eq!(freq.freqs(), [2400000, 1800000, 1200000]);
+            assert_eq!(freq.get(), 0);
+
+            assert!(!freq.up().unwrap());
+            assert!(freq.down().unwrap());
+            assert_eq!(read(&root, "scaling_setspeed"), "1800000");
+
+            assert!(freq.min().unwrap());
+            assert!(!freq.down().unwrap());
+            assert_eq!(read(&root, "scaling_setspeed"), "1200000");
+
+            assert!(freq.max().unwrap());
+            assert_eq!(read(&root, "scaling_setspeed"), "2400000");
+
+            assert_eq!(freq.set(3).err().unwrap().raw_os_error(), Some(libc::EINVAL));
+        }
+
+        assert_eq!(read(&root, "scaling_governor"), "ondemand");
+        assert!(CpuFreq::with_root(&root, 0).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn backoff_levels() {
+        let root = fake_sysfs("backoff");
+        let policy = IdlePolicy {
+            spin_polls: 2,
+            pause_polls: 2,
+            wait_polls: 2,
+            pauses: 1,
+            wait_cycles: 100,
+            sleep: Duration::from_micros(1),
+        };
+        let mut backoff = Backoff::new(policy).cpufreq(CpuFreq::with_root(&root, 1).unwrap());
+
+        let levels: Vec<_> = (0..7)
+            .map(|_| {
+                backoff.idle(0);
+                backoff.level()
+            })
+            .collect();
+        assert_eq!(
+            levels,
+            [
+                IdleLevel::Spin,
+                IdleLevel::Pause,
+                IdleLevel::Pause,
+                IdleLevel::Wait,
+                IdleLevel::Wait,
+                IdleLevel::Sleep,
+                IdleLevel::Sleep,
+            ]
+        );
+        assert_eq!(read(&root, "scaling_setspeed"), "1200000");
+
+        backoff.idle(32);
+        assert_eq!(backoff.level(), IdleLevel::Spin);
+        assert_eq!(backoff.empty_polls(), 0);
+        assert_eq!(read(&root, "scaling_setspeed"), "2400000");
+
+        drop(backoff);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn pause_until_deadline() {
+        let deadline = get_tsc_cycles() + 10_000;
+        pause_until(deadline);
+        assert!(get_tsc_cycles() >= deadline || cpu_has_waitpkg());
+
+        let word = AtomicU64::new(0);
+        monitor_until(&word, 0, get_tsc_cycles() + 10_000);
+    }
+}
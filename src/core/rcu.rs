@@ -0,0 +1,475 @@
+//! Quiescent state based reclamation (QSBR), for the lock-free structures.
+//!
+//! A writer removing an element from a lock-free table cannot free it while
+//! readers may still hold references to it. The readers are registered by
+//! lcore id, and report a quiescent state with [`quiescent`] whenever they
+//! hold no such reference, e.g. between two polling iterations, which costs a
+//! load and a store.
+//!
+//! After removing an element, the writer [`start`]s a grace period, and
+//! [`check`]s its token: once every online reader reported a quiescent state
+//! since the start, no reader can reference the element anymore. A reader
+//! about to block, or to stop reading for a while, goes [`offline`] so that
+//! it does not hold back the grace periods.
+//!
+//! A [`DeferQueue`] holds the removed objects with their token, and drops
+//! them once their grace period is over, without blocking the writer.
+//!
+//! # Example
+//! ```
+//! use std::sync::Arc;
+//! use dpdk::core::rcu::{DeferQueue, Qsbr};
+//!
+//! let qsbr = Arc::new(Qsbr::new());
+//!
+//! // a reader, e.g. on lcore 3
+//! qsbr.register(3);
+//! qsbr.online(3);
+//!
+//! // a writer, removing an element
+//! let token = qsbr.start();
+//! assert!(!qsbr.check(token, false));
+//!
+//! // the reader, between two iterations
+//! qsbr.quiescent(3);
+//! assert!(qsbr.check(token, false));
+//!
+//! // or without waiting for the readers
+//! let dq = DeferQueue::new(qsbr.clone(), 64);
+//! dq.defer(Box::new([0u8; 64])).unwrap();
+//! qsbr.quiescent(3);
+//! assert_eq!(dq.reclaim(usize::MAX), 1);
+//! ```
+//!
+//! [`quiescent`]: struct.Qsbr.html#method.quiescent
+//! [`start`]: struct.Qsbr.html#method.start
+//! [`check`]: struct.Qsbr.html#method.check
+//! [`offline`]: struct.Qsbr.html#method.offline
+//! [`DeferQueue`]: struct.DeferQueue.html
+
+use super::lcore::MAX_LCORE;
+use std::array;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::hint::spin_loop;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+
+// The counter of an offline reader, the tokens start at 1.
+const OFFLINE: u64 = 0;
+
+/// The token of a grace period, from [`Qsbr::start`]
+///
+/// [`Qsbr::start`]: struct.Qsbr.html#method.start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Token(u64);
+
+// Written by the reader, read by the writers. One cache line per reader so
+// that reporting doesn't bounce the lines of the other readers.
+#[repr(align(64))]
+struct Slot {
+    registered: AtomicBool,
+    cnt: AtomicU64, // the token last seen, OFFLINE if offline
+}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            registered: AtomicBool::new(false),
+            cnt: AtomicU64::new(OFFLINE),
+        }
+    }
+}
+
+/// The QSBR state of a set of readers
+pub struct Qsbr {
+    token: AtomicU64,
+    // every reader passed this token
+    acked: AtomicU64,
+    slots: [Slot; MAX_LCORE],
+}
+
+impl Default for Qsbr {
+    fn default() -> Self {
+        Qsbr::new()
+    }
+}
+
+impl fmt::Debug for Qsbr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let readers: Vec<_> = (0..MAX_LCORE)
+            .filter(|&id| self.slots[id].registered.load(Ordering::Relaxed))
+            .collect();
+
+        f.debug_struct("Qsbr")
+            .field("token", &self.token.load(Ordering::Relaxed))
+            .field("acked", &self.acked.load(Ordering::Relaxed))
+            .field("readers", &readers)
+            .finish()
+    }
+}
+
+impl Qsbr {
+    /// Construct the QSBR state, with no reader
+    pub fn new() -> Qsbr {
+        Qsbr {
+            token: AtomicU64::new(1),
+            acked: AtomicU64::new(0),
+            slots: array::from_fn(|_| Slot::new()),
+        }
+    }
+
+    /// Register the reader `id`, offline until it goes `online`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not smaller than `MAX_LCORE`.
+    pub fn register(&self, id: usize) {
+        let slot = &self.slots[id];
+
+        slot.cnt.store(OFFLINE, Ordering::Relaxed);
+        slot.registered.store(true, Ordering::Release);
+    }
+
+    /// Unregister the reader `id`, it must be offline
+    pub fn unregister(&self, id: usize) {
+        let slot = &self.slots[id];
+
+        debug_assert_eq!(slot.cnt.load(Ordering::Relaxed), OFFLINE, "reader still online");
+        slot.registered.store(false, Ordering::Release);
+    }
+
+    /// Put the reader `id` online, before it references the shared objects
+    pub fn online(&self, id: usize) {
+        let slot = &self.slots[id];
+
+        slot.cnt.store(self.token.load(Ordering::Relaxed), Ordering::Relaxed);
+        // the reads which follow must not pass the store, or a writer
+        // checking meanwhile could miss them
+        fence(Ordering::SeqCst);
+    }
+
+    /// Put the reader `id` offline, it must not reference the shared objects
+    /// until it is online again
+    pub fn offline(&self, id: usize) {
+        self.slots[id].cnt.store(OFFLINE, Ordering::Release);
+    }
+
+    /// Report a quiescent state of the reader `id`: it references none of the
+    /// shared objects
+    #[inline]
+    pub fn quiescent(&self, id: usize) {
+        let token = self.token.load(Ordering::Acquire);
+        self.slots[id].cnt.store(token, Ordering::Release);
+    }
+
+    /// Start a grace period, after removing the objects to reclaim
+    pub fn start(&self) -> Token {
+        Token(self.token.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    /// Check that the grace period of `token` is over: every online reader
+    /// reported a quiescent state since it started. Spin until it is if
+    /// `wait`.
+    ///
+    /// A reader waiting for its own grace period must report its quiescent
+    /// state first, or go offline, not to wait forever.
+    pub fn check(&self, token: Token, wait: bool) -> bool {
+        if self.acked.load(Ordering::Acquire) >= token.0 {
+            return true;
+        }
+
+        // the readers going online from now on see this token or a newer one
+        let current = self.token.load(Ordering::Acquire);
+        // the oldest token seen by an online reader
+        let mut acked = current;
+
+        for slot in self.slots.iter() {
+            if !slot.registered.load(Ordering::Acquire) {
+                continue;
+            }
+
+            loop {
+                let cnt = slot.cnt.load(Ordering::Acquire);
+
+                if cnt == OFFLINE {
+                    break;
+                }
+                if cnt >= token.0 {
+                    acked = acked.min(cnt);
+                    break;
+                }
+                if !wait {
+                    return false;
+                }
+
+                spin_loop();
+            }
+        }
+
+        self.acked.fetch_max(acked, Ordering::Release);
+        true
+    }
+
+    /// Wait for a new grace period, the objects removed before being safe to
+    /// reclaim
+    ///
+    /// A reader calling it passes its `id` to report its quiescent state.
+    pub fn synchronize(&self, id: Option<usize>) {
+        let token = self.start();
+
+        if let Some(id) = id {
+            self.quiescent(id);
+        }
+        self.check(token, true);
+    }
+}
+
+/// A queue of objects to drop once their grace period is over
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use dpdk::core::rcu::{DeferQueue, Qsbr};
+///
+/// let qsbr = Arc::new(Qsbr::new());
+/// let dq = DeferQueue::new(qsbr, 1);
+///
+/// // with no reader online, the grace periods end at once
+/// dq.defer(String::from("entry v1")).unwrap();
+/// // full, the first one is reclaimed to make room
+/// dq.defer(String::from("entry v2")).unwrap();
+/// assert_eq!(dq.reclaim(usize::MAX), 1);
+/// assert!(dq.is_empty());
+/// ```
+pub struct DeferQueue {
+    qsbr: Arc<Qsbr>,
+    size: usize,
+    queue: Mutex<VecDeque<(Token, Box<dyn Send>)>>,
+}
+
+impl fmt::Debug for DeferQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeferQueue")
+            .field("size", &self.size)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl DeferQueue {
+    /// Construct the queue holding up to `size` objects, reclaimed after the
+    /// grace periods of `qsbr`
+    pub fn new(qsbr: Arc<Qsbr>, size: usize) -> DeferQueue {
+        DeferQueue {
+            qsbr,
+            size,
+            queue: Mutex::new(VecDeque::with_capacity(size)),
+        }
+    }
+
+    /// Drop `obj` once the grace period started now is over
+    ///
+    /// The reclaimable objects are dropped first when the queue is full. If it
+    /// is still full, `obj` is given back in the error.
+    pub fn defer<T: Send + 'static>(&self, obj: T) -> Result<(), (io::Error, T)> {
+        let token = self.qsbr.start();
+
+        if self.len() >= self.size {
+            self.reclaim(usize::MAX);
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.size {
+            return Err((io::Error::from_raw_os_error(libc::ENOSPC), obj));
+        }
+
+        queue.push_back((token, Box::new(obj)));
+        Ok(())
+    }
+
+    /// Drop up to `n` objects whose grace period is over, returns how many
+    pub fn reclaim(&self, n: usize) -> usize {
+        let mut reclaimed = Vec::new();
+
+        {
+            let mut queue = self.queue.lock().unwrap();
+
+            while reclaimed.len() < n {
+                match queue.front() {
+                    Some(&(token, _)) if self.qsbr.check(token, false) => {
+                        reclaimed.extend(queue.pop_front().map(|(_, obj)| obj));
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        // dropped out of the lock, as they may take long
+        reclaimed.len()
+    }
+
+    /// The number of objects waiting for their grace period
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Test if no object is waiting
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for DeferQueue {
+    /// Wait for the grace periods of the objects left, and drop them
+    fn drop(&mut self) {
+        let queue = self.queue.get_mut().unwrap();
+
+        if let Some(&(token, _)) = queue.back() {
+            self.qsbr.check(token, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize};
+    use std::thread;
+
+    #[derive(Debug)]
+    struct Tracked(Arc<AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn rcu_grace_period() {
+        let qsbr = Qsbr::new();
+        qsbr.register(0);
+        qsbr.register(1);
+
+        // offline readers do not hold it back
+        let t = qsbr.start();
+        assert!(qsbr.check(t, false));
+
+        qsbr.online(0);
+        qsbr.online(1);
+
+        let t = qsbr.start();
+        assert!(!qsbr.check(t, false));
+        qsbr.quiescent(0);
+        assert!(!qsbr.check(t, false));
+        qsbr.offline(1);
+        assert!(qsbr.check(t, false));
+
+        // an older token is over too
+        let t2 = qsbr.start();
+        assert!(t2 > t);
+        assert!(qsbr.check(t, false));
+        assert!(!qsbr.check(t2, false));
+
+        qsbr.synchronize(Some(0));
+        assert!(qsbr.check(t2, false));
+
+        qsbr.offline(0);
+        qsbr.unregister(0);
+        qsbr.unregister(1);
+    }
+
+    #[test]
+    fn rcu_defer_queue() {
+        let qsbr = Arc::new(Qsbr::new());
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let dq = DeferQueue::new(qsbr.clone(), 2);
+
+        qsbr.register(5);
+        qsbr.online(5);
+
+        dq.defer(Tracked(dropped.clone())).unwrap();
+        dq.defer(Tracked(dropped.clone())).unwrap();
+        assert_eq!(dq.reclaim(usize::MAX), 0);
+
+        // full, and nothing is reclaimable
+        let (e, obj) = dq.defer(Tracked(dropped.clone())).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSPC));
+        drop(obj);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+        qsbr.quiescent(5);
+        assert_eq!(dq.reclaim(1), 1);
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        // the last one is left to the drop of the queue
+        dq.defer(Tracked(dropped.clone())).unwrap();
+        assert_eq!(dq.len(), 2);
+        qsbr.offline(5);
+        drop(dq);
+        assert_eq!(dropped.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn rcu_readers_writer() {
+        const NREADER: usize = 3;
+        const NUPDATE: u64 = 1000;
+        const LIVE: u64 = 0x600d;
+
+        struct Entry {
+            magic: AtomicU64,
+            version: u64,
+        }
+
+        let qsbr = Qsbr::new();
+        let current = AtomicPtr::new(Box::into_raw(Box::new(Entry {
+            magic: AtomicU64::new(LIVE),
+            version: 0,
+        })));
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            for id in 0..NREADER {
+                qsbr.register(id);
+
+                let (qsbr, current, done) = (&qsbr, &current, &done);
+                s.spawn(move || {
+                    qsbr.online(id);
+                    while !done.load(Ordering::Relaxed) {
+                        let entry = unsafe { &*current.load(Ordering::Acquire) };
+                        assert_eq!(entry.magic.load(Ordering::Relaxed), LIVE);
+                        thread::yield_now();
+                        assert_eq!(entry.magic.load(Ordering::Relaxed), LIVE);
+
+                        qsbr.quiescent(id);
+                    }
+                    qsbr.offline(id);
+                });
+            }
+
+            for version in 1..=NUPDATE {
+                let entry = Box::into_raw(Box::new(Entry {
+                    magic: AtomicU64::new(LIVE),
+                    version,
+                }));
+                let old = current.swap(entry, Ordering::AcqRel);
+
+                qsbr.synchronize(None);
+
+                // poison it, a reader still using it would see
+                unsafe {
+                    (*old).magic.store(0, Ordering::Relaxed);
+                    assert_eq!((*old).version, version - 1);
+                    drop(Box::from_raw(old));
+                }
+            }
+
+            done.store(true, Ordering::Relaxed);
+        });
+
+        unsafe { drop(Box::from_raw(current.into_inner())) };
+    }
+}
@@ -1,20 +1,43 @@
 //! A rwlock
 //!
-//! The basic lock primitives:
+//! The `RwLock<T>` owns the data it protects, and gives access to it through
+//! RAII guards which release the lock when dropped.
+//!
+//! # Example
+//! ```
+//! extern crate dpdk;
+//!
+//! use dpdk::core::rwlock;
+//!
+//! # fn main() {
+//! let lk = rwlock::RwLock::new(0);
+//!
+//! println!("val = {}", *lk.read());
+//!
+//! *lk.write() += 10;
+//!
+//! assert_eq!(*lk.read(), 10);
+//!
+//! # }
+//! ```
+//!
+//! The raw `RawRwLock` is a bare lock word, for the data living outside the
+//! lock. The basic lock primitives:
 //!
 //! - `read_lock`
 //! - `read_unlock`
 //! - `write_lock`
 //! - `write_unlock`
 //!
-//! # Example
+//! along with `read_trylock`, `write_trylock`, `try_upgrade` and `downgrade`.
+//!
 //! ```
 //! extern crate dpdk;
 //!
 //! use dpdk::core::rwlock;
 //!
 //! # fn main() {
-//! let mut lk = rwlock::RwLock::default();
+//! let lk = rwlock::RawRwLock::default();
 //! let mut val = 0;
 //!
 //! lk.read_lock();
@@ -30,38 +53,64 @@
 //! lk.read_unlock();
 //!
 //! # }
+//! ```
 //!
+//! By default the readers are preferred: a writer waits until no read lock is
+//! held, which may never happen under a steady flow of overlapping readers.
+//! A lock built `with_preference(Preference::Writer)` holds back the new
+//! readers while a writer is waiting. The [`PfLock`] bounds the waits of both.
 //!
+//! [`PfLock`]: ../pflock/struct.PfLock.html
 
+#[cfg(feature = "lock_stat")]
+use crate::core::cycles::get_tsc_cycles;
+use crate::core::lock::{RawLock, RawSharedLock};
+use crate::core::lockdep::{self, Class};
+#[cfg(feature = "lock_stat")]
+use crate::core::lockstat::LockStat;
+use crate::core::tsx::{self, Elided};
+use std::fmt;
 use std::cell::UnsafeCell;
-use std::os::raw::c_int;
-use std::sync::atomic::{spin_loop_hint, AtomicI32, Ordering};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::thread::panicking;
 
-extern "C" {
-    fn rte_try_tm(lock: *mut i32) -> c_int;
-    fn rte_xend();
+/// Which side of a [`RwLock`] goes first when both are waiting
+///
+/// [`RwLock`]: struct.RwLock.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preference {
+    /// The readers go first, the writers may starve
+    #[default]
+    Reader,
+    /// The writers go first, the readers may starve
+    Writer,
 }
 
-/// The read/write lock type
+/// The raw read/write lock type
 ///
 /// cnt is -1 when write lock is held, and > 0 when read locks are held.
-pub struct RwLock {
+pub struct RawRwLock {
     cnt: UnsafeCell<AtomicI32>,
+    waiting: AtomicI32, // writers waiting, when preferred
+    pref: Preference,
+    class: Class,
 }
 
-unsafe impl Sync for RwLock {}
-unsafe impl Send for RwLock {}
+unsafe impl Sync for RawRwLock {}
+unsafe impl Send for RawRwLock {}
 
-impl Default for RwLock {
+impl Default for RawRwLock {
+    #[track_caller]
     fn default() -> Self {
-        RwLock {
-            cnt: UnsafeCell::new(AtomicI32::new(0)),
-        }
+        RawRwLock::new()
     }
 }
 
-impl Drop for RwLock {
+impl Drop for RawRwLock {
     fn drop(&mut self) {
         unsafe {
             if (*self.cnt.get()).load(Ordering::Relaxed) != 0 && !panicking() {
@@ -71,23 +120,56 @@ impl Drop for RwLock {
     }
 }
 
-impl RwLock {
+impl RawRwLock {
+    /// Construct the rwlock with unlocked state
+    #[track_caller]
+    pub const fn new() -> Self {
+        RawRwLock::with_preference(Preference::Reader)
+    }
+
+    /// Construct the rwlock with unlocked state, preferring the given side
+    #[track_caller]
+    pub const fn with_preference(pref: Preference) -> Self {
+        RawRwLock {
+            cnt: UnsafeCell::new(AtomicI32::new(0)),
+            waiting: AtomicI32::new(0),
+            pref,
+            class: Class::new("rwlock"),
+        }
+    }
+
+    // Check the lock order before waiting for the lock.
+    pub(crate) fn check_order(&self, exclusive: bool) {
+        lockdep::check(&self.class, self as *const _ as usize, exclusive);
+    }
+
+    fn acquired(&self, exclusive: bool) {
+        lockdep::acquired(&self.class, self as *const _ as usize, exclusive);
+    }
+
+    fn release(&self) {
+        lockdep::release(self as *const _ as usize);
+    }
+
+    /// The side going first when both are waiting
+    pub fn preference(&self) -> Preference {
+        self.pref
+    }
+
     /// Take a read lock. Loop until the lock is held.
     pub fn read_lock(&self) {
-        unsafe {
-            if cfg!(feature = "tsx") {
-                if rte_try_tm(self.cnt.get() as *mut i32) == 1 {
-                    return;
-                }
-            }
+        self.check_order(false);
 
+        unsafe {
             let mut success = false;
             while !success {
                 let x = (*self.cnt.get()).load(Ordering::Relaxed);
 
-                // write lock is held
-                if x < 0 {
-                    spin_loop_hint();
+                // write lock is held, or a preferred writer is waiting
+                let held_back =
+                    self.pref == Preference::Writer && self.waiting.load(Ordering::Relaxed) > 0;
+                if x < 0 || held_back {
+                    spin_loop();
                     continue;
                 }
 
@@ -96,27 +178,49 @@ impl RwLock {
                     .is_ok();
             }
         }
+
+        self.acquired(false);
+    }
+
+    /// Try to take a read lock, returns true if it is held
+    pub fn read_trylock(&self) -> bool {
+        unsafe {
+            loop {
+                let x = (*self.cnt.get()).load(Ordering::Relaxed);
+
+                // write lock is held, or a preferred writer is waiting
+                let held_back =
+                    self.pref == Preference::Writer && self.waiting.load(Ordering::Relaxed) > 0;
+                if x < 0 || held_back {
+                    return false;
+                }
+
+                if (*self.cnt.get())
+                    .compare_exchange_weak(x, x + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    self.acquired(false);
+                    return true;
+                }
+            }
+        }
     }
 
     /// Release a read lock
     pub fn read_unlock(&self) {
+        self.release();
+
         unsafe {
-            if (*self.cnt.get()).load(Ordering::Relaxed) != 0 {
-                (*self.cnt.get()).fetch_sub(1, Ordering::Release);
-            } else {
-                rte_xend();
-            }
+            (*self.cnt.get()).fetch_sub(1, Ordering::Release);
         }
     }
 
     /// Take a write lock. Loop until the lock is held.
     pub fn write_lock(&self) {
+        self.check_order(true);
+
         unsafe {
-            if cfg!(feature = "tsx") {
-                if rte_try_tm(self.cnt.get() as *mut i32) == 1 {
-                    return;
-                }
-            }
+            self.writer_waiting(true);
 
             let mut success = false;
             while !success {
@@ -124,7 +228,7 @@ impl RwLock {
 
                 // a lock is held
                 if x != 0 {
-                    spin_loop_hint();
+                    spin_loop();
                     continue;
                 }
 
@@ -132,18 +236,419 @@ impl RwLock {
                     .compare_exchange_weak(x, -1, Ordering::Acquire, Ordering::Relaxed)
                     .is_ok();
             }
+
+            self.writer_waiting(false);
+        }
+
+        self.acquired(true);
+    }
+
+    // Hold back the new readers while a preferred writer waits.
+    pub(crate) fn writer_waiting(&self, waiting: bool) {
+        if self.pref == Preference::Writer {
+            if waiting {
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.waiting.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Try to take a write lock, returns true if it is held
+    pub fn write_trylock(&self) -> bool {
+        let locked = unsafe {
+            (*self.cnt.get())
+                .compare_exchange(0, -1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        };
+
+        if locked {
+            self.acquired(true);
+        }
+        locked
+    }
+
+    /// Try to turn the held read lock into a write lock, returns true if the
+    /// write lock is held, false if the read lock is still held
+    ///
+    /// It only succeeds for the single reader.
+    pub fn try_upgrade(&self) -> bool {
+        let upgraded = unsafe {
+            (*self.cnt.get())
+                .compare_exchange(1, -1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        };
+
+        if upgraded {
+            self.release();
+            self.acquired(true);
+        }
+        upgraded
+    }
+
+    /// Turn the held write lock into a read lock, without letting a writer in
+    pub fn downgrade(&self) {
+        self.release();
+        self.acquired(false);
+
+        unsafe {
+            (*self.cnt.get()).store(1, Ordering::Release);
         }
     }
 
     /// Release a write lock
     pub fn write_unlock(&self) {
+        self.release();
+
         unsafe {
-            if (*self.cnt.get()).load(Ordering::Relaxed) != 0 {
-                (*self.cnt.get()).store(0, Ordering::Release);
-            } else {
-                rte_xend();
+            (*self.cnt.get()).store(0, Ordering::Release);
+        }
+    }
+
+    /// Test if a read or write lock is held
+    pub fn is_locked(&self) -> bool {
+        unsafe { (*self.cnt.get()).load(Ordering::Acquire) != 0 }
+    }
+
+    // Test if a new reader would wait: a writer holds the lock, or a preferred
+    // writer is waiting.
+    #[cfg(feature = "lock_stat")]
+    fn read_blocked(&self) -> bool {
+        let write_locked = unsafe { (*self.cnt.get()).load(Ordering::Relaxed) < 0 };
+        let held_back = self.pref == Preference::Writer && self.waiting.load(Ordering::Relaxed) > 0;
+
+        write_locked || held_back
+    }
+}
+
+impl Elided<RawRwLock> {
+    /// Try to turn the held read lock into a write lock, returns true if the
+    /// write lock is held, false if the read lock is still held
+    ///
+    /// An elided read lock cannot be upgraded within its transaction: it is
+    /// aborted, and the code since the `read_lock` runs again with the lock
+    /// taken for real.
+    pub fn try_upgrade(&self) -> bool {
+        if self.is_elided() {
+            // never returns
+            unsafe { tsx::xabort_upgrade() };
+        }
+
+        self.inner().try_upgrade()
+    }
+
+    /// Turn the held write lock into a read lock, without letting a writer in
+    pub fn downgrade(&self) {
+        // an elided write lock stays within its transaction
+        if !self.is_elided() {
+            self.inner().downgrade();
+        }
+    }
+}
+
+/// A read/write lock protecting a `T`
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use dpdk::core::rwlock::RwLock;
+///
+/// let lk = Arc::new(RwLock::new(vec![1, 2, 3]));
+///
+/// let reader = {
+///     let lk = lk.clone();
+///     thread::spawn(move || lk.read().len())
+/// };
+///
+/// lk.write().push(4);
+///
+/// let len = reader.join().unwrap();
+/// assert!(len == 3 || len == 4);
+/// assert_eq!(*lk.read(), [1, 2, 3, 4]);
+/// ```
+pub struct RwLock<T: ?Sized> {
+    raw: Elided<RawRwLock>,
+    #[cfg(feature = "lock_stat")]
+    stat: LockStat,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Construct the rwlock with unlocked state
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        RwLock::with_preference(data, Preference::Reader)
+    }
+
+    /// Construct the rwlock with unlocked state, profiled as `name` with the
+    /// `lock_stat` feature
+    #[track_caller]
+    pub const fn named(name: &'static str, data: T) -> Self {
+        let _ = name;
+
+        RwLock {
+            raw: Elided::new(RawRwLock::new()),
+            #[cfg(feature = "lock_stat")]
+            stat: LockStat::named(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Construct the rwlock with unlocked state, preferring the given side
+    ///
+    /// ```
+    /// use dpdk::core::rwlock::{Preference, RwLock};
+    ///
+    /// let lk = RwLock::with_preference(0, Preference::Writer);
+    ///
+    /// *lk.write() += 1;
+    /// assert_eq!(*lk.read(), 1);
+    /// ```
+    #[track_caller]
+    pub const fn with_preference(data: T, pref: Preference) -> Self {
+        RwLock {
+            raw: Elided::new(RawRwLock::with_preference(pref)),
+            #[cfg(feature = "lock_stat")]
+            stat: LockStat::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the rwlock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Take a read lock, released when the guard is dropped
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        #[cfg(feature = "lock_stat")]
+        {
+            self.raw.inner().check_order(false);
+            self.stat.acquire_elided(&self.raw, RawRwLock::read_trylock, RawRwLock::read_blocked);
+        }
+        #[cfg(not(feature = "lock_stat"))]
+        self.raw.lock_shared();
+
+        RwLockReadGuard::new(self)
+    }
+
+    /// Take a write lock, released when the guard is dropped
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        #[cfg(feature = "lock_stat")]
+        {
+            self.raw.inner().check_order(true);
+            self.raw.inner().writer_waiting(true);
+            self.stat.acquire_elided(&self.raw, RawRwLock::write_trylock, RawRwLock::is_locked);
+            self.raw.inner().writer_waiting(false);
+        }
+        #[cfg(not(feature = "lock_stat"))]
+        self.raw.lock();
+
+        RwLockWriteGuard::new(self)
+    }
+
+    /// Try to take a read lock, returns `None` if a writer holds it
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if self.raw.try_lock_shared() {
+            #[cfg(feature = "lock_stat")]
+            self.stat.acquired();
+
+            Some(RwLockReadGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Try to take a write lock, returns `None` if it is held
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if self.raw.try_lock() {
+            #[cfg(feature = "lock_stat")]
+            self.stat.acquired();
+
+            Some(RwLockWriteGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw rwlock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &Elided<RawRwLock> {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("RwLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// The RAII guard of a read lock on a [`RwLock`], releasing it on drop.
+///
+/// [`RwLock`]: struct.RwLock.html
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    #[cfg(feature = "lock_stat")]
+    since: u64,
+    // released on the thread which took it, as an elided lock must be
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RwLockReadGuard<'a, T> {
+    fn new(lock: &'a RwLock<T>) -> Self {
+        RwLockReadGuard {
+            lock,
+            #[cfg(feature = "lock_stat")]
+            since: get_tsc_cycles(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Try to turn the read lock into a write lock, gives the read guard back
+    /// if other readers hold the lock
+    ///
+    /// See [`Elided::try_upgrade`] for an elided read lock.
+    ///
+    /// ```
+    /// use dpdk::core::rwlock::{RwLock, RwLockReadGuard};
+    ///
+    /// let lk = RwLock::new(0);
+    ///
+    /// let r = lk.read();
+    /// if *r == 0 {
+    ///     let mut w = RwLockReadGuard::try_upgrade(r).ok().unwrap();
+    ///     *w = 1;
+    /// }
+    ///
+    /// assert_eq!(*lk.read(), 1);
+    /// ```
+    ///
+    /// [`Elided::try_upgrade`]: ../tsx/struct.Elided.html#method.try_upgrade
+    pub fn try_upgrade(this: Self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+        if this.lock.raw.try_upgrade() {
+            #[allow(unused_mut)]
+            let mut guard = RwLockWriteGuard::new(this.lock);
+            #[cfg(feature = "lock_stat")]
+            {
+                guard.since = this.since;
             }
+            mem::forget(this);
+
+            Ok(guard)
+        } else {
+            Err(this)
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // ends the transaction when elided
+        unsafe { self.lock.raw.unlock_shared() };
+
+        // out of the transaction
+        #[cfg(feature = "lock_stat")]
+        self.lock.stat.release(self.since);
+    }
+}
+
+/// The RAII guard of a write lock on a [`RwLock`], releasing it on drop.
+///
+/// [`RwLock`]: struct.RwLock.html
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    #[cfg(feature = "lock_stat")]
+    since: u64,
+    // released on the thread which took it, as an elided lock must be
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    fn new(lock: &'a RwLock<T>) -> Self {
+        RwLockWriteGuard {
+            lock,
+            #[cfg(feature = "lock_stat")]
+            since: get_tsc_cycles(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Turn the write lock into a read lock, no writer getting in between
+    pub fn downgrade(this: Self) -> RwLockReadGuard<'a, T> {
+        this.lock.raw.downgrade();
+
+        #[allow(unused_mut)]
+        let mut guard = RwLockReadGuard::new(this.lock);
+        #[cfg(feature = "lock_stat")]
+        {
+            guard.since = this.since;
         }
+        mem::forget(this);
+
+        guard
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // ends the transaction when elided
+        unsafe { self.lock.raw.unlock() };
+
+        // out of the transaction
+        #[cfg(feature = "lock_stat")]
+        self.lock.stat.release(self.since);
     }
 }
 
@@ -151,21 +656,23 @@ impl RwLock {
 mod tests {
     use super::*;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
     use std::thread;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn rwlock_lock_unlock() {
         const NWORKER: usize = 2;
 
-        let global = Arc::from(RwLock::default());
-        let rwlks = vec![Arc::from(RwLock::default()); NWORKER];
+        let global = Arc::from(RawRwLock::default());
+        let rwlks = vec![Arc::from(RawRwLock::default()); NWORKER];
 
         global.write_lock();
 
         let mut threads = Vec::new();
-        for i in 0..NWORKER {
+        for lk in rwlks.iter() {
             let global_lk = global.clone();
-            let local_lk = rwlks[i].clone();
+            let local_lk = lk.clone();
 
             threads.push(thread::spawn(move || {
                 global_lk.write_lock();
@@ -181,4 +688,158 @@ mod tests {
             let _ = h.join();
         }
     }
+
+    #[test]
+    fn rwlock_guards() {
+        const NWORKER: usize = 4;
+        const NITER: usize = 1000;
+
+        let lk = Arc::new(RwLock::new((0usize, 0usize)));
+
+        let threads: Vec<_> = (0..NWORKER)
+            .map(|_| {
+                let lk = lk.clone();
+                thread::spawn(move || {
+                    for _ in 0..NITER {
+                        {
+                            let mut w = lk.write();
+                            w.0 += 1;
+                            w.1 += 1;
+                        }
+
+                        let r = lk.read();
+                        assert_eq!(r.0, r.1);
+                    }
+                })
+            })
+            .collect();
+
+        for h in threads {
+            h.join().unwrap();
+        }
+
+        let lk = Arc::try_unwrap(lk).ok().unwrap();
+        assert_eq!(lk.into_inner(), (NWORKER * NITER, NWORKER * NITER));
+    }
+
+    // The readers overlap, yielding while holding the lock, so that it is
+    // never free: only a preferred writer gets in.
+    #[test]
+    fn rwlock_writer_preferring() {
+        const NREADER: usize = 2;
+
+        // a new reader passes a queued writer only under reader preference
+        for &pref in &[Preference::Reader, Preference::Writer] {
+            let lk = Arc::new(RawRwLock::with_preference(pref));
+            let queued = Arc::new(AtomicBool::new(false));
+            lk.read_lock();
+
+            let writer = {
+                let lk = lk.clone();
+                let queued = queued.clone();
+                thread::spawn(move || {
+                    queued.store(true, Ordering::Relaxed);
+                    lk.write_lock();
+                    lk.write_unlock();
+                })
+            };
+
+            while !queued.load(Ordering::Relaxed)
+                || (pref == Preference::Writer && lk.waiting.load(Ordering::Relaxed) == 0)
+            {
+                thread::yield_now();
+            }
+
+            if pref == Preference::Writer {
+                assert!(!lk.read_trylock());
+            } else {
+                assert!(lk.read_trylock());
+                lk.read_unlock();
+            }
+
+            lk.read_unlock();
+            writer.join().unwrap();
+        }
+
+        let lk = Arc::new(RawRwLock::with_preference(Preference::Writer));
+        let stop = Arc::new(AtomicBool::new(false));
+        let reads = Arc::new(AtomicUsize::new(0));
+
+        let readers: Vec<_> = (0..NREADER)
+            .map(|_| {
+                let lk = lk.clone();
+                let stop = stop.clone();
+                let reads = reads.clone();
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        lk.read_lock();
+                        reads.fetch_add(1, Ordering::Relaxed);
+                        thread::yield_now();
+                        lk.read_unlock();
+                    }
+                })
+            })
+            .collect();
+
+        while reads.load(Ordering::Relaxed) < 100 {
+            thread::yield_now();
+        }
+
+        for _ in 0..10 {
+            let start = Instant::now();
+            lk.write_lock();
+            let latency = start.elapsed();
+            lk.write_unlock();
+
+            assert!(latency < Duration::from_secs(1), "writer waited {:?}", latency);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn rwlock_trylock() {
+        let lk = RwLock::new(0);
+
+        {
+            let _r = lk.try_read().unwrap();
+            assert!(lk.try_read().is_some());
+            assert!(lk.try_write().is_none());
+        }
+
+        {
+            let _w = lk.try_write().unwrap();
+            assert!(lk.try_read().is_none());
+            assert!(lk.try_write().is_none());
+            assert_eq!(format!("{:?}", lk), r#"RwLock { data: "<locked>" }"#);
+        }
+
+        assert!(lk.try_write().is_some());
+    }
+
+    #[test]
+    fn rwlock_upgrade_downgrade() {
+        let lk = RwLock::new(0);
+
+        // another reader prevents the upgrade
+        let r1 = lk.read();
+        let r2 = lk.read();
+        let r1 = RwLockReadGuard::try_upgrade(r1).err().unwrap();
+        drop(r2);
+
+        let mut w = RwLockReadGuard::try_upgrade(r1).ok().unwrap();
+        *w += 1;
+        assert!(lk.try_read().is_none());
+
+        let r = RwLockWriteGuard::downgrade(w);
+        assert_eq!(*r, 1);
+        assert!(lk.try_write().is_none());
+        assert_eq!(*lk.try_read().unwrap(), 1);
+        drop(r);
+
+        assert!(lk.try_write().is_some());
+    }
 }
@@ -0,0 +1,286 @@
+//! A seqlock & seqcount, for read-mostly data.
+//!
+//! The readers never write to shared memory: they read the data along with a
+//! sequence number, and retry when a writer updated it meanwhile. The writers
+//! are serialized by a spinlock.
+//!
+//! # Example
+//! ```
+//! use dpdk::core::seqlock::SeqLock;
+//!
+//! #[derive(Copy, Clone)]
+//! struct Config {
+//!     mtu: u16,
+//!     promisc: bool,
+//! }
+//!
+//! let cfg = SeqLock::new(Config { mtu: 1500, promisc: false });
+//!
+//! cfg.write().mtu = 9000;
+//!
+//! let snapshot = cfg.read();
+//! assert_eq!(snapshot.mtu, 9000);
+//! assert!(!snapshot.promisc);
+//! ```
+//!
+//! The bare `SeqCount` is the sequence number alone, for data protected by
+//! other means, e.g. atomics updated by a single writer.
+
+use crate::core::spinlock::RawSpinLock;
+use std::fmt;
+use std::ptr;
+use std::cell::UnsafeCell;
+use std::hint::spin_loop;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+
+/// A sequence counter
+///
+/// It is odd while a write is in progress.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use dpdk::core::seqlock::SeqCount;
+///
+/// let sc = SeqCount::new();
+/// let (rx, tx) = (AtomicU64::new(0), AtomicU64::new(0));
+///
+/// // the single writer
+/// sc.write_begin();
+/// rx.store(10, Ordering::Relaxed);
+/// tx.store(20, Ordering::Relaxed);
+/// sc.write_end();
+///
+/// let total = sc.read(|| rx.load(Ordering::Relaxed) + tx.load(Ordering::Relaxed));
+/// assert_eq!(total, 30);
+/// ```
+pub struct SeqCount {
+    sn: AtomicU32,
+}
+
+impl Default for SeqCount {
+    fn default() -> Self {
+        SeqCount::new()
+    }
+}
+
+impl SeqCount {
+    /// Construct the sequence counter
+    pub const fn new() -> Self {
+        SeqCount {
+            sn: AtomicU32::new(0),
+        }
+    }
+
+    /// Begin a read, returns the sequence number to pass to `read_retry`
+    #[inline]
+    pub fn read_begin(&self) -> u32 {
+        self.sn.load(Ordering::Acquire)
+    }
+
+    /// Test if the data read since `read_begin` may be inconsistent
+    #[inline]
+    pub fn read_retry(&self, begin: u32) -> bool {
+        // the data loads must not be reordered after the sequence load
+        fence(Ordering::Acquire);
+
+        begin & 1 == 1 || self.sn.load(Ordering::Relaxed) != begin
+    }
+
+    /// Calls `f` until it ran without a concurrent write, returns its result
+    pub fn read<R, F: FnMut() -> R>(&self, mut f: F) -> R {
+        loop {
+            let begin = self.read_begin();
+            if begin & 1 == 1 {
+                spin_loop();
+                continue;
+            }
+
+            let res = f();
+            if !self.read_retry(begin) {
+                return res;
+            }
+        }
+    }
+
+    /// Begin a write, the writers must be serialized
+    #[inline]
+    pub fn write_begin(&self) {
+        let sn = self.sn.load(Ordering::Relaxed);
+        self.sn.store(sn.wrapping_add(1), Ordering::Relaxed);
+
+        // the data stores must not be reordered before the sequence store
+        fence(Ordering::Release);
+    }
+
+    /// End a write
+    #[inline]
+    pub fn write_end(&self) {
+        let sn = self.sn.load(Ordering::Relaxed);
+        self.sn.store(sn.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// A seqlock protecting a `T`
+///
+/// The payload is `Copy`: the readers copy it out, possibly while it is being
+/// written, and only return a copy made without a concurrent write.
+pub struct SeqLock<T: Copy> {
+    count: SeqCount,
+    lock: RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    /// Construct the seqlock
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        SeqLock {
+            count: SeqCount::new(),
+            lock: RawSpinLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Read a consistent copy of the data, never blocks the writers
+    pub fn read(&self) -> T {
+        // may be torn, it is only assumed valid once no write happened meanwhile
+        let copy = self.count.read(|| unsafe {
+            ptr::read_volatile(self.data.get() as *const MaybeUninit<T>)
+        });
+
+        unsafe { copy.assume_init() }
+    }
+
+    /// Take the writer lock, the readers retry until the guard is dropped
+    pub fn write(&self) -> SeqLockWriteGuard<'_, T> {
+        self.lock.lock();
+        self.count.write_begin();
+
+        SeqLockWriteGuard { lock: self }
+    }
+
+    /// Replace the data
+    pub fn set(&self, data: T) {
+        *self.write() = data;
+    }
+
+    /// Consumes the seqlock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        SeqLock::new(T::default())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SeqLock").field("data", &self.read()).finish()
+    }
+}
+
+/// The RAII guard of a write on a [`SeqLock`], ending it on drop.
+///
+/// [`SeqLock`]: struct.SeqLock.html
+pub struct SeqLockWriteGuard<'a, T: Copy> {
+    lock: &'a SeqLock<T>,
+}
+
+impl<T: Copy> Deref for SeqLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: Copy> DerefMut for SeqLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: Copy> Drop for SeqLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.count.write_end();
+        self.lock.lock.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn seqlock_consistent_reads() {
+        const NREADER: usize = 3;
+        const NWRITE: u64 = 100_000;
+
+        let lk = Arc::new(SeqLock::new([0u64; 8]));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..NREADER)
+            .map(|_| {
+                let lk = lk.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let snapshot = lk.read();
+                        assert!(snapshot.iter().all(|&v| v == snapshot[0]), "torn read");
+                        assert!(snapshot[0] >= last);
+                        last = snapshot[0];
+                    }
+                })
+            })
+            .collect();
+
+        for i in 1..=NWRITE {
+            let mut data = lk.write();
+            for v in data.iter_mut() {
+                *v = i;
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(lk.read(), [NWRITE; 8]);
+    }
+
+    #[test]
+    fn seqcount_retry() {
+        let sc = SeqCount::new();
+
+        let begin = sc.read_begin();
+        assert!(!sc.read_retry(begin));
+
+        sc.write_begin();
+        assert!(sc.read_retry(begin));
+        assert!(sc.read_retry(sc.read_begin()));
+        sc.write_end();
+
+        assert!(sc.read_retry(begin));
+        assert!(!sc.read_retry(sc.read_begin()));
+    }
+}
@@ -0,0 +1,533 @@
+//! A robust lock for the memory shared by processes.
+//!
+//! The primary and secondary processes share `mmap`'d regions, the locks
+//! living there must not depend on the address space or the process which
+//! created them. A shm lock is `#[repr(C)]`, 16 bytes of atomics with no
+//! pointer, and identifies its owner by pid and tid: the thread ids are only
+//! unique within a pid namespace, and a forked child has new ones.
+//!
+//! The lock is recursive, the owning thread may take it again. When the owner
+//! died holding it, e.g. a crashed secondary process, a waiter finds it gone
+//! with `tgkill(pid, tid, 0)` and takes the lock over, which `lock` reports as
+//! [`OwnerDied`]: the data may be inconsistent, and should be checked or
+//! repaired before use. An exited process must be reaped, its zombie is still
+//! alive; a pid reused by then hides the death until the new process exits.
+//!
+//! # Example
+//! ```
+//! use std::cell::Cell;
+//! use std::{mem, ptr};
+//! use dpdk::core::shmlock::ShmLock;
+//!
+//! let size = mem::size_of::<ShmLock<Cell<u64>>>();
+//! let addr = unsafe {
+//!     libc::mmap(
+//!         ptr::null_mut(),
+//!         size,
+//!         libc::PROT_READ | libc::PROT_WRITE,
+//!         libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+//!         -1,
+//!         0,
+//!     )
+//! };
+//! assert_ne!(addr, libc::MAP_FAILED);
+//!
+//! let lk = unsafe { ShmLock::init(addr as *mut _, Cell::new(0)) };
+//!
+//! match lk.lock() {
+//!     Ok(v) => v.set(v.get() + 1),
+//!     // repair the data left by the dead owner
+//!     Err(died) => died.into_inner().set(0),
+//! }
+//! assert_eq!(lk.lock().unwrap().get(), 1);
+//!
+//! unsafe { libc::munmap(addr, size) };
+//! ```
+//!
+//! [`OwnerDied`]: struct.OwnerDied.html
+
+use super::cycles::get_tsc_cycles;
+use super::{getpid, gettid};
+use libc::{syscall, SYS_tgkill, ESRCH};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::ptr;
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+// The TSC cycles between the checks of the owner, while waiting.
+const CHECK_CYCLES: u64 = 1 << 20;
+
+// The owner id, never 0 as the pid is not.
+fn current_owner() -> u64 {
+    (getpid() as u32 as u64) << 32 | gettid() as u32 as u64
+}
+
+fn owner_alive(owner: u64) -> bool {
+    let (pid, tid) = ((owner >> 32) as i32, owner as u32 as i32);
+
+    let rc = unsafe { syscall(SYS_tgkill, pid, tid, 0) };
+    rc == 0 || io::Error::last_os_error().raw_os_error() != Some(ESRCH)
+}
+
+/// The lock was taken over from an owner which died holding it
+///
+/// It holds the guard, or `()` for a raw lock: the lock is held.
+pub struct OwnerDied<G> {
+    guard: G,
+}
+
+impl<G> OwnerDied<G> {
+    /// The guard of the lock taken over
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    /// A reference to the guard of the lock taken over
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+}
+
+impl<G> fmt::Debug for OwnerDied<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OwnerDied").finish_non_exhaustive()
+    }
+}
+
+impl<G> fmt::Display for OwnerDied<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the lock owner died holding it")
+    }
+}
+
+impl<G> Error for OwnerDied<G> {}
+
+/// The raw shm lock type
+///
+/// Unlike the other locks, it does not panic when dropped locked: its owner
+/// may be another process.
+#[repr(C)]
+pub struct RawShmLock {
+    /// The owner pid in the high half, and tid in the low half, 0 if unlocked
+    owner: AtomicU64,
+    /// The count of times the owner took it
+    count: AtomicU32,
+    _reserved: u32,
+}
+
+impl Default for RawShmLock {
+    /// Construct the shm lock with unlocked state
+    fn default() -> Self {
+        RawShmLock::new()
+    }
+}
+
+impl RawShmLock {
+    /// Construct the shm lock with unlocked state
+    pub const fn new() -> Self {
+        RawShmLock {
+            owner: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+            _reserved: 0,
+        }
+    }
+
+    // Become the owner, the lock being taken.
+    fn own(&self) {
+        self.count.store(1, Ordering::Relaxed);
+    }
+
+    /// Take the shm lock. Loop until the lock is held, or taken over from
+    /// a dead owner.
+    pub fn lock(&self) -> Result<(), OwnerDied<()>> {
+        let me = current_owner();
+
+        if self.owner.load(Ordering::Relaxed) == me {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let mut checked = get_tsc_cycles();
+        loop {
+            let owner = self.owner.load(Ordering::Relaxed);
+
+            if owner == 0 {
+                if self
+                    .owner
+                    .compare_exchange_weak(0, me, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    self.own();
+                    return Ok(());
+                }
+                continue;
+            }
+
+            if get_tsc_cycles().wrapping_sub(checked) >= CHECK_CYCLES {
+                // the waiters may race to take it over, one succeeds
+                if !owner_alive(owner)
+                    && self
+                        .owner
+                        .compare_exchange(owner, me, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+                {
+                    self.own();
+                    return Err(OwnerDied { guard: () });
+                }
+                checked = get_tsc_cycles();
+            }
+
+            spin_loop();
+        }
+    }
+
+    /// Release the shm lock, for good once the owner released it as many
+    /// times as it took it
+    pub fn unlock(&self) {
+        let count = self.count.load(Ordering::Relaxed) - 1;
+        self.count.store(count, Ordering::Relaxed);
+
+        if count == 0 {
+            self.owner.store(0, Ordering::Release);
+        }
+    }
+
+    /// Try to take the shm lock. It fails on a lock held by a dead owner,
+    /// which only `lock` takes over.
+    pub fn trylock(&self) -> bool {
+        let me = current_owner();
+
+        if self.owner.load(Ordering::Relaxed) == me {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        if self
+            .owner
+            .compare_exchange(0, me, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.own();
+            return true;
+        }
+
+        false
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Acquire) != 0
+    }
+
+    /// Test if the lock is held by the calling thread
+    pub fn is_owner(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == current_owner()
+    }
+}
+
+/// A shm lock protecting a `T`, in the memory shared by processes
+///
+/// The `T` must not hold pointers, nor anything else local to a process. The
+/// lock is recursive, so the guards only give shared access to the data: use
+/// a `Cell` or atomics to mutate it.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use dpdk::core::shmlock::ShmLock;
+///
+/// let lk = ShmLock::new(Cell::new(0));
+///
+/// let outer = lk.lock().unwrap();
+/// {
+///     let inner = lk.lock().unwrap();
+///     inner.set(inner.get() + 1);
+/// }
+/// assert_eq!(outer.get(), 1);
+/// ```
+#[repr(C)]
+pub struct ShmLock<T: ?Sized> {
+    raw: RawShmLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for ShmLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for ShmLock<T> {}
+
+impl<T> ShmLock<T> {
+    /// Construct the shm lock with unlocked state
+    pub const fn new(data: T) -> Self {
+        ShmLock {
+            raw: RawShmLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Construct the shm lock with unlocked state at `ptr`, e.g. in a shared
+    /// mapping, and return it
+    ///
+    /// # Safety
+    ///
+    /// The `ptr` must be valid and aligned for a `ShmLock<T>` for `'a`, and
+    /// the other processes must not use it before it is initialized.
+    pub unsafe fn init<'a>(ptr: *mut Self, data: T) -> &'a Self {
+        ptr::write(ptr, ShmLock::new(data));
+        &*ptr
+    }
+
+    /// The shm lock initialized at `ptr`, e.g. by another process
+    ///
+    /// # Safety
+    ///
+    /// The `ptr` must point to a `ShmLock<T>` initialized by `init`, valid
+    /// for `'a`.
+    pub unsafe fn from_ptr<'a>(ptr: *const Self) -> &'a Self {
+        &*ptr
+    }
+
+    /// Consumes the shm lock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> ShmLock<T> {
+    /// Take the shm lock, released when the guard is dropped
+    ///
+    /// The guard is in the error when the lock was taken over from an owner
+    /// which died holding it.
+    pub fn lock(&self) -> Result<ShmLockGuard<'_, T>, OwnerDied<ShmLockGuard<'_, T>>> {
+        match self.raw.lock() {
+            Ok(()) => Ok(ShmLockGuard::new(self)),
+            Err(_) => Err(OwnerDied {
+                guard: ShmLockGuard::new(self),
+            }),
+        }
+    }
+
+    /// Try to take the shm lock
+    pub fn try_lock(&self) -> Option<ShmLockGuard<'_, T>> {
+        if self.raw.trylock() {
+            Some(ShmLockGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw shm lock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &RawShmLock {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for ShmLock<T> {
+    fn default() -> Self {
+        ShmLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ShmLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("ShmLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("ShmLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// The RAII guard of a [`ShmLock`], releasing it on drop.
+///
+/// [`ShmLock`]: struct.ShmLock.html
+pub struct ShmLockGuard<'a, T: ?Sized> {
+    lock: &'a ShmLock<T>,
+    // released on the thread which took it, as the owner is a thread id
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for ShmLockGuard<'_, T> {}
+
+impl<'a, T: ?Sized> ShmLockGuard<'a, T> {
+    fn new(lock: &'a ShmLock<T>) -> Self {
+        ShmLockGuard {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for ShmLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ShmLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::mem;
+
+    // A lock in a shared mapping, inherited by the forked children.
+    struct Shared<T>(*mut ShmLock<T>);
+
+    impl<T> Shared<T> {
+        fn new(data: T) -> Self {
+            let addr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    mem::size_of::<ShmLock<T>>(),
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            assert_ne!(addr, libc::MAP_FAILED);
+
+            let ptr = addr as *mut ShmLock<T>;
+            unsafe { ShmLock::init(ptr, data) };
+            Shared(ptr)
+        }
+
+        fn lock(&self) -> &ShmLock<T> {
+            unsafe { ShmLock::from_ptr(self.0) }
+        }
+    }
+
+    impl<T> Drop for Shared<T> {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.0 as *mut _, mem::size_of::<ShmLock<T>>()) };
+        }
+    }
+
+    // Run `f` in a forked child, returning its pid.
+    fn fork<F: FnOnce() -> i32>(f: F) -> i32 {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+
+        if pid == 0 {
+            let rc = f();
+            unsafe { libc::_exit(rc) };
+        }
+        pid
+    }
+
+    fn wait(pid: i32) -> i32 {
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        libc::WEXITSTATUS(status)
+    }
+
+    #[test]
+    fn shmlock_layout() {
+        assert_eq!(mem::size_of::<RawShmLock>(), 16);
+        assert_eq!(mem::align_of::<RawShmLock>(), 8);
+        assert_eq!(mem::size_of::<ShmLock<u64>>(), 24);
+    }
+
+    #[test]
+    fn shmlock_recursive() {
+        let lk = RawShmLock::new();
+
+        assert!(lk.lock().is_ok());
+        assert!(lk.trylock());
+        assert!(lk.is_owner());
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert!(!lk.trylock());
+                assert!(!lk.is_owner());
+            });
+        });
+
+        lk.unlock();
+        assert!(lk.is_locked());
+        lk.unlock();
+        assert!(!lk.is_locked());
+    }
+
+    #[test]
+    fn shmlock_processes() {
+        const NITER: u64 = 10000;
+
+        let shared = Shared::new(Cell::new(0u64));
+        let lk = shared.lock();
+
+        let bump = || {
+            for _ in 0..NITER {
+                match lk.lock() {
+                    Ok(v) => v.set(v.get() + 1),
+                    Err(_) => return 1,
+                }
+            }
+            0
+        };
+
+        let child = fork(bump);
+        assert_eq!(bump(), 0);
+        assert_eq!(wait(child), 0);
+
+        assert!(!lk.is_locked());
+        assert_eq!(lk.lock().unwrap().get(), 2 * NITER);
+    }
+
+    #[test]
+    fn shmlock_owner_died() {
+        let shared = Shared::new(Cell::new(0u64));
+        let lk = shared.lock();
+
+        // the child exits holding the lock
+        let child = fork(|| match lk.lock() {
+            Ok(v) => {
+                v.set(7);
+                mem::forget(v);
+                0
+            }
+            Err(_) => 1,
+        });
+        assert_eq!(wait(child), 0);
+        assert!(lk.is_locked());
+        assert!(lk.try_lock().is_none());
+
+        match lk.lock() {
+            Ok(_) => panic!("the owner death was not reported"),
+            Err(died) => {
+                let v = died.into_inner();
+                assert_eq!(v.get(), 7);
+                v.set(0);
+            }
+        }
+
+        assert!(!lk.is_locked());
+        assert_eq!(lk.lock().unwrap().get(), 0);
+    }
+}
@@ -1,12 +1,7 @@
 //! A spinlock & recursive spinlock.
 //!
-//! The `SpinLock&RecursiveSpinLock` provides the same interfaces except `is_locked`.
-//!
-//! The basic lock primitives:
-//!
-//! - `lock`
-//! - `unlock`
-//! - `trylock`
+//! The `SpinLock<T>&RecursiveSpinLock<T>` own the data they protect, and give
+//! access to it through RAII guards which release the lock when dropped.
 //!
 //! # Example
 //! ```
@@ -15,7 +10,27 @@
 //! use dpdk::core::spinlock;
 //!
 //! # fn main() {
-//! let mut lk = spinlock::SpinLock::default();
+//! let lk = spinlock::SpinLock::new(0);
+//!
+//! *lk.lock() += 1;
+//!
+//! assert_eq!(*lk.lock(), 1);
+//!
+//! # }
+//! ```
+//!
+//! The raw `RawSpinLock&RawRecursiveSpinLock` are bare lock words, for the
+//! data living outside the lock. They provide the same interfaces except
+//! `is_locked`:
+//!
+//! - `lock`
+//! - `unlock`
+//! - `trylock`
+//!
+//! ```
+//! use dpdk::core::spinlock::RawSpinLock;
+//!
+//! let lk = RawSpinLock::default();
 //! let mut val = 0;
 //!
 //! lk.lock();
@@ -23,47 +38,52 @@
 //! lk.unlock();
 //!
 //! assert_eq!(val, 1);
-//!
-//! # }
 //! ```
 //!
 //! NOTE:
 //! [TSX](https://gcc.gnu.org/onlinedocs/gcc-4.8.2/gcc/X86-transactional-memory-intrinsics.html)
+//! elision is done by the owning locks and the recursive spinlock, a bare
+//! `RawSpinLock` is elided by wrapping it in an [`Elided`].
 //!
-//!
+//! [`Elided`]: ../tsx/struct.Elided.html
 
 use super::gettid;
+use super::lockdep::{self, Class};
+#[cfg(feature = "lock_stat")]
+use super::cycles::get_tsc_cycles;
+#[cfg(feature = "lock_stat")]
+use super::lockstat::LockStat;
+use super::lock::RawLock;
+use super::tsx::Elided;
+use std::fmt;
 use std::cell::UnsafeCell;
-use std::os::raw::c_int;
-use std::sync::atomic::{spin_loop_hint, AtomicI32, Ordering};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::thread::panicking;
 
-extern "C" {
-    fn rte_try_tm(lock: *mut i32) -> c_int;
-    fn rte_xend();
-}
-
-/// The spinlock type
-pub struct SpinLock {
+/// The raw spinlock type
+pub struct RawSpinLock {
     /// The lock state
     // 0 indicates unlocked; 1 indicates locked.
     // locked must be of 32bit size for RTM
     locked: UnsafeCell<AtomicI32>,
+    class: Class,
 }
 
-unsafe impl Sync for SpinLock {}
-unsafe impl Send for SpinLock {}
+unsafe impl Sync for RawSpinLock {}
+unsafe impl Send for RawSpinLock {}
 
-impl Default for SpinLock {
+impl Default for RawSpinLock {
     /// Construct the spinlock with unlocked state
+    #[track_caller]
     fn default() -> Self {
-        SpinLock {
-            locked: UnsafeCell::new(AtomicI32::new(0)),
-        }
+        RawSpinLock::new()
     }
 }
 
-impl Drop for SpinLock {
+impl Drop for RawSpinLock {
     fn drop(&mut self) {
         if self.is_locked() && !panicking() {
             panic!("spinlock still locked");
@@ -71,51 +91,60 @@ impl Drop for SpinLock {
     }
 }
 
-impl SpinLock {
+impl RawSpinLock {
+    /// Construct the spinlock with unlocked state
+    #[track_caller]
+    pub const fn new() -> Self {
+        RawSpinLock {
+            locked: UnsafeCell::new(AtomicI32::new(0)),
+            class: Class::new("spinlock"),
+        }
+    }
+
+    // Check the lock order before waiting for the lock.
+    fn check_order(&self) {
+        lockdep::check(&self.class, self as *const _ as usize, true);
+    }
+
     /// Take the spinlock
     pub fn lock(&self) {
-        unsafe {
-            if cfg!(feature = "tsx") {
-                if rte_try_tm(self.locked.get() as *mut i32) == 1 {
-                    return;
-                }
-            }
+        self.check_order();
 
+        unsafe {
             while (*self.locked.get())
                 .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
                 .is_err()
             {
                 while (*self.locked.get()).load(Ordering::Relaxed) == 1 {
-                    spin_loop_hint();
+                    spin_loop();
                 }
             }
         }
+
+        lockdep::acquired(&self.class, self as *const _ as usize, true);
     }
 
     /// Release the spinlock
     pub fn unlock(&self) {
+        lockdep::release(self as *const _ as usize);
+
         unsafe {
-            if self.is_locked() {
-                (*self.locked.get()).store(0, Ordering::Release);
-            } else {
-                rte_xend();
-            }
+            (*self.locked.get()).store(0, Ordering::Release);
         }
     }
 
     /// Try to take the spinlock
     pub fn trylock(&self) -> bool {
-        unsafe {
-            if cfg!(feature = "tsx") {
-                if rte_try_tm(self.locked.get() as *mut i32) == 1 {
-                    return true;
-                }
-            }
-
+        let locked = unsafe {
             (*self.locked.get())
                 .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
                 .is_ok()
+        };
+
+        if locked {
+            lockdep::acquired(&self.class, self as *const _ as usize, true);
         }
+        locked
     }
 
     /// Test if the lock is taked
@@ -124,42 +153,48 @@ impl SpinLock {
     }
 }
 
-/// The recursive spinlock type
-pub struct RecursiveSpinLock {
+/// The raw recursive spinlock type
+pub struct RawRecursiveSpinLock {
     /// The actual spinlock
-    lk: SpinLock,
+    lk: Elided<RawSpinLock>,
     /// The thread id, -1 for unused
     tid: UnsafeCell<i32>,
     /// The count of times this lock has been called
     count: UnsafeCell<usize>,
 }
 
-unsafe impl Sync for RecursiveSpinLock {}
-unsafe impl Send for RecursiveSpinLock {}
+unsafe impl Sync for RawRecursiveSpinLock {}
+unsafe impl Send for RawRecursiveSpinLock {}
 
-impl Default for RecursiveSpinLock {
+impl Default for RawRecursiveSpinLock {
     /// Construct the recursive spinlock with unlocked state
+    #[track_caller]
     fn default() -> Self {
-        RecursiveSpinLock {
-            lk: SpinLock::default(),
+        RawRecursiveSpinLock::new()
+    }
+}
+
+impl RawRecursiveSpinLock {
+    /// Construct the recursive spinlock with unlocked state
+    #[track_caller]
+    pub const fn new() -> Self {
+        RawRecursiveSpinLock {
+            lk: Elided::new(RawSpinLock::new()),
             tid: UnsafeCell::new(-1),
             count: UnsafeCell::new(0),
         }
     }
-}
 
-impl RecursiveSpinLock {
+    /// Test if the lock is held by the calling thread
+    pub fn is_owner(&self) -> bool {
+        unsafe { *self.tid.get() == gettid() }
+    }
+
     /// Take the recursive spinlock
     pub fn lock(&self) {
         let id = gettid();
 
         unsafe {
-            if cfg!(feature = "tsx") {
-                if rte_try_tm(&self.lk as *const _ as *mut i32) == 1 {
-                    return;
-                }
-            }
-
             if *self.tid.get() != id {
                 self.lk.lock();
                 *self.tid.get() = id;
@@ -169,18 +204,26 @@ impl RecursiveSpinLock {
         }
     }
 
+    // Take the lock not held by the caller, profiling the wait in `stat`.
+    #[cfg(feature = "lock_stat")]
+    fn lock_profiled(&self, stat: &LockStat) {
+        stat.acquire_elided(&self.lk, RawSpinLock::trylock, RawSpinLock::is_locked);
+
+        unsafe {
+            *self.tid.get() = gettid();
+            *self.count.get() += 1;
+        }
+    }
+
     /// Release recursive spinlock
     pub fn unlock(&self) {
         unsafe {
-            if self.lk.is_locked() {
-                *self.count.get() -= 1;
+            *self.count.get() -= 1;
 
-                if *self.count.get() == 0 {
-                    *self.tid.get() = -1;
-                    self.lk.unlock();
-                }
-            } else {
-                rte_xend();
+            if *self.count.get() == 0 {
+                *self.tid.get() = -1;
+                // ends the transaction when elided
+                self.lk.unlock();
             }
         }
     }
@@ -190,14 +233,8 @@ impl RecursiveSpinLock {
         let id = gettid();
 
         unsafe {
-            if cfg!(feature = "tsx") {
-                if rte_try_tm(&self.lk as *const _ as *mut i32) == 1 {
-                    return true;
-                }
-            }
-
             if *self.tid.get() != id {
-                if !self.lk.trylock() {
+                if !self.lk.try_lock() {
                     return false;
                 }
                 *self.tid.get() = id;
@@ -209,6 +246,352 @@ impl RecursiveSpinLock {
     }
 }
 
+/// A spinlock protecting a `T`
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use dpdk::core::spinlock::SpinLock;
+///
+/// let lk = Arc::new(SpinLock::new(Vec::new()));
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|i| {
+///         let lk = lk.clone();
+///         thread::spawn(move || lk.lock().push(i))
+///     })
+///     .collect();
+///
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+///
+/// let mut v = lk.lock().clone();
+/// v.sort();
+/// assert_eq!(v, [0, 1, 2, 3]);
+/// ```
+pub struct SpinLock<T: ?Sized> {
+    raw: Elided<RawSpinLock>,
+    #[cfg(feature = "lock_stat")]
+    stat: LockStat,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Construct the spinlock with unlocked state
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            raw: Elided::new(RawSpinLock::new()),
+            #[cfg(feature = "lock_stat")]
+            stat: LockStat::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Construct the spinlock with unlocked state, profiled as `name` with
+    /// the `lock_stat` feature
+    #[track_caller]
+    pub const fn named(name: &'static str, data: T) -> Self {
+        let _ = name;
+
+        SpinLock {
+            raw: Elided::new(RawSpinLock::new()),
+            #[cfg(feature = "lock_stat")]
+            stat: LockStat::named(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the spinlock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Take the spinlock, released when the guard is dropped
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        #[cfg(feature = "lock_stat")]
+        {
+            self.raw.inner().check_order();
+            self.stat.acquire_elided(&self.raw, RawSpinLock::trylock, RawSpinLock::is_locked);
+        }
+        #[cfg(not(feature = "lock_stat"))]
+        self.raw.lock();
+
+        SpinLockGuard::new(self)
+    }
+
+    /// Try to take the spinlock
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        if self.raw.try_lock() {
+            #[cfg(feature = "lock_stat")]
+            self.stat.acquired();
+
+            Some(SpinLockGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw spinlock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &Elided<RawSpinLock> {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        SpinLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("SpinLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("SpinLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// The RAII guard of a [`SpinLock`], releasing it on drop.
+///
+/// [`SpinLock`]: struct.SpinLock.html
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    #[cfg(feature = "lock_stat")]
+    since: u64,
+    // released on the thread which took it, as an elided lock must be
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SpinLockGuard<'_, T> {}
+
+impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
+    fn new(lock: &'a SpinLock<T>) -> Self {
+        SpinLockGuard {
+            lock,
+            #[cfg(feature = "lock_stat")]
+            since: get_tsc_cycles(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // ends the transaction when elided
+        unsafe { self.lock.raw.unlock() };
+
+        // out of the transaction
+        #[cfg(feature = "lock_stat")]
+        self.lock.stat.release(self.since);
+    }
+}
+
+/// A recursive spinlock protecting a `T`
+///
+/// The lock may be taken again by the thread holding it, so the guards only
+/// give shared access to the data. Use a `Cell` or `RefCell` to mutate it.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use dpdk::core::spinlock::RecursiveSpinLock;
+///
+/// let lk = RecursiveSpinLock::new(Cell::new(0));
+///
+/// let outer = lk.lock();
+/// outer.set(1);
+/// {
+///     let inner = lk.lock();
+///     inner.set(inner.get() + 1);
+/// }
+/// assert_eq!(outer.get(), 2);
+/// ```
+pub struct RecursiveSpinLock<T: ?Sized> {
+    raw: RawRecursiveSpinLock,
+    #[cfg(feature = "lock_stat")]
+    stat: LockStat,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for RecursiveSpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RecursiveSpinLock<T> {}
+
+impl<T> RecursiveSpinLock<T> {
+    /// Construct the recursive spinlock with unlocked state
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        RecursiveSpinLock {
+            raw: RawRecursiveSpinLock::new(),
+            #[cfg(feature = "lock_stat")]
+            stat: LockStat::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Construct the recursive spinlock with unlocked state, profiled as
+    /// `name` with the `lock_stat` feature
+    #[track_caller]
+    pub const fn named(name: &'static str, data: T) -> Self {
+        let _ = name;
+
+        RecursiveSpinLock {
+            raw: RawRecursiveSpinLock::new(),
+            #[cfg(feature = "lock_stat")]
+            stat: LockStat::named(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the recursive spinlock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RecursiveSpinLock<T> {
+    /// Take the recursive spinlock, released when the guard is dropped
+    pub fn lock(&self) -> RecursiveSpinLockGuard<'_, T> {
+        // only the outermost acquisition is profiled
+        #[cfg(feature = "lock_stat")]
+        if self.raw.is_owner() {
+            self.raw.lock();
+        } else {
+            self.raw.lk.inner().check_order();
+            self.raw.lock_profiled(&self.stat);
+        }
+        #[cfg(not(feature = "lock_stat"))]
+        self.raw.lock();
+
+        RecursiveSpinLockGuard::new(self)
+    }
+
+    /// Try to take the recursive spinlock
+    pub fn try_lock(&self) -> Option<RecursiveSpinLockGuard<'_, T>> {
+        #[cfg(feature = "lock_stat")]
+        let outer = !self.raw.is_owner();
+
+        if self.raw.trylock() {
+            #[cfg(feature = "lock_stat")]
+            if outer {
+                self.stat.acquired();
+            }
+
+            Some(RecursiveSpinLockGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw recursive spinlock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &RawRecursiveSpinLock {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for RecursiveSpinLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        RecursiveSpinLock::new(T::default())
+    }
+}
+
+/// The RAII guard of a [`RecursiveSpinLock`], releasing it on drop.
+///
+/// [`RecursiveSpinLock`]: struct.RecursiveSpinLock.html
+pub struct RecursiveSpinLockGuard<'a, T: ?Sized> {
+    lock: &'a RecursiveSpinLock<T>,
+    // the outermost guard times the hold
+    #[cfg(feature = "lock_stat")]
+    since: Option<u64>,
+    // released on the thread which took it, as the owner is a thread id
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RecursiveSpinLockGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RecursiveSpinLockGuard<'a, T> {
+    fn new(lock: &'a RecursiveSpinLock<T>) -> Self {
+        RecursiveSpinLockGuard {
+            lock,
+            #[cfg(feature = "lock_stat")]
+            since: if unsafe { *lock.raw.count.get() } == 1 {
+                Some(get_tsc_cycles())
+            } else {
+                None
+            },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RecursiveSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RecursiveSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+
+        #[cfg(feature = "lock_stat")]
+        if let Some(since) = self.since {
+            self.lock.stat.release(since);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,14 +599,14 @@ mod tests {
     #[test]
     #[should_panic]
     fn spinlock_drop_when_locked() {
-        let lk = SpinLock::default();
+        let lk = RawSpinLock::default();
 
         lk.lock();
     }
 
     #[test]
     fn spinlock_lock_unlock() {
-        let lk = SpinLock::default();
+        let lk = RawSpinLock::default();
         assert!(!lk.is_locked());
 
         lk.lock();
@@ -235,7 +618,7 @@ mod tests {
 
     #[test]
     fn spinlock_trylock() {
-        let lk = SpinLock::default();
+        let lk = RawSpinLock::default();
         assert!(!lk.is_locked());
 
         assert!(lk.trylock());
@@ -250,16 +633,46 @@ mod tests {
     #[test]
     #[should_panic]
     fn recursive_spinlock_drop_when_locked() {
-        let lk = RecursiveSpinLock::default();
+        let lk = RawRecursiveSpinLock::default();
 
         lk.lock();
     }
 
     #[test]
     fn recursive_spinlock_lock_unlock() {
-        let lk = RecursiveSpinLock::default();
+        let lk = RawRecursiveSpinLock::default();
 
         lk.lock();
         lk.unlock();
     }
+
+    #[test]
+    fn spinlock_guard() {
+        let mut lk = SpinLock::new(0);
+
+        {
+            let mut guard = lk.lock();
+            *guard += 1;
+            assert!(lk.is_locked());
+            assert!(lk.try_lock().is_none());
+        }
+        assert!(!lk.is_locked());
+
+        *lk.try_lock().unwrap() += 1;
+        *lk.get_mut() += 1;
+        assert_eq!(lk.into_inner(), 3);
+    }
+
+    #[test]
+    fn recursive_spinlock_guard() {
+        let lk = RecursiveSpinLock::new(1);
+
+        let outer = lk.lock();
+        let inner = lk.try_lock().unwrap();
+        assert_eq!(*outer + *inner, 2);
+
+        drop(outer);
+        drop(inner);
+        assert!(unsafe { !lk.raw().lk.is_locked() });
+    }
 }
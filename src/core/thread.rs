@@ -21,16 +21,22 @@ impl Thread {
         ptr::null_mut()
     }
 
+    /// Spawns a native thread running `p` with at least `stack` bytes of stack.
+    ///
+    /// # Safety
+    ///
+    /// The closure is moved to another thread without any `Send` bound, the
+    /// caller must guarantee everything it captures is safe to be used there.
     pub unsafe fn new(stack: usize, p: Box<dyn FnOnce()>) -> io::Result<Thread> {
         let p = Box::new(p);
 
-        let mut attr: libc::pthread_attr_t = mem::uninitialized();
+        let mut attr: libc::pthread_attr_t = mem::zeroed();
         assert_eq!(libc::pthread_attr_init(&mut attr), 0);
 
         let stack_size = cmp::max(stack, DEFAULT_MIN_STACK_SIZE);
         assert_eq!(libc::pthread_attr_setstacksize(&mut attr, stack_size), 0);
 
-        let mut native: libc::pthread_t = mem::uninitialized();
+        let mut native: libc::pthread_t = 0;
         let ret = libc::pthread_create(
             &mut native,
             &attr,
@@ -39,12 +45,12 @@ impl Thread {
         );
         assert_eq!(libc::pthread_attr_destroy(&mut attr), 0);
 
-        return if ret != 0 {
+        if ret != 0 {
             Err(io::Error::from_raw_os_error(ret))
         } else {
             mem::forget(p); // ownership passed to pthread_create
             Ok(Thread { id: native })
-        };
+        }
     }
 
     pub fn set_name(s: &CStr) {
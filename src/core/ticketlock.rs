@@ -0,0 +1,582 @@
+//! A ticket lock & recursive ticket lock.
+//!
+//! Unlike a spinlock, a ticket lock is fair: the lcores waiting for it take it
+//! in the order they asked for it, no one starves under contention.
+//!
+//! The `TicketLock<T>&RecursiveTicketLock<T>` own the data they protect, and
+//! give access to it through RAII guards which release the lock when dropped.
+//!
+//! # Example
+//! ```
+//! use dpdk::core::ticketlock::TicketLock;
+//!
+//! let lk = TicketLock::new(0);
+//!
+//! *lk.lock() += 1;
+//!
+//! assert_eq!(*lk.lock(), 1);
+//! ```
+//!
+//! The raw `RawTicketLock&RawRecursiveTicketLock` are bare lock words, for the
+//! data living outside the lock. They provide the same interfaces:
+//!
+//! - `lock`
+//! - `unlock`
+//! - `trylock`
+//! - `is_locked`
+//!
+//! ```
+//! use dpdk::core::ticketlock::RawTicketLock;
+//!
+//! let lk = RawTicketLock::default();
+//! let mut val = 0;
+//!
+//! lk.lock();
+//! val += 1;
+//! lk.unlock();
+//!
+//! assert_eq!(val, 1);
+//! ```
+
+use super::gettid;
+use std::fmt;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread::panicking;
+
+// The ticket being served, in the low half of the lock word.
+const CURRENT_MASK: u32 = 0xffff;
+
+// The next ticket to hand out, in the high half of the lock word.
+const NEXT_ONE: u32 = 1 << 16;
+
+fn current(tickets: u32) -> u16 {
+    tickets as u16
+}
+
+fn next(tickets: u32) -> u16 {
+    (tickets >> 16) as u16
+}
+
+/// The raw ticket lock type
+pub struct RawTicketLock {
+    /// The lock state
+    // the ticket being served and the next ticket, so that `trylock` can
+    // compare both at once
+    tickets: AtomicU32,
+}
+
+impl Default for RawTicketLock {
+    /// Construct the ticket lock with unlocked state
+    fn default() -> Self {
+        RawTicketLock::new()
+    }
+}
+
+impl Drop for RawTicketLock {
+    fn drop(&mut self) {
+        if self.is_locked() && !panicking() {
+            panic!("ticketlock still locked");
+        }
+    }
+}
+
+impl RawTicketLock {
+    /// Construct the ticket lock with unlocked state
+    pub const fn new() -> Self {
+        RawTicketLock {
+            tickets: AtomicU32::new(0),
+        }
+    }
+
+    /// Take the ticket lock, after the lcores which asked for it before
+    pub fn lock(&self) {
+        let me = next(self.tickets.fetch_add(NEXT_ONE, Ordering::Relaxed));
+
+        while current(self.tickets.load(Ordering::Acquire)) != me {
+            spin_loop();
+        }
+    }
+
+    /// Release the ticket lock, serving the next ticket
+    pub fn unlock(&self) {
+        let mut tickets = self.tickets.load(Ordering::Relaxed);
+
+        // the next ticket may be handed out meanwhile
+        loop {
+            let served = (tickets & !CURRENT_MASK) | current(tickets).wrapping_add(1) as u32;
+
+            match self.tickets.compare_exchange_weak(
+                tickets,
+                served,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(t) => tickets = t,
+            }
+        }
+    }
+
+    /// Try to take the ticket lock, fails if anyone holds or waits for it
+    pub fn trylock(&self) -> bool {
+        let tickets = self.tickets.load(Ordering::Relaxed);
+        if current(tickets) != next(tickets) {
+            return false;
+        }
+
+        self.tickets
+            .compare_exchange(tickets, tickets.wrapping_add(NEXT_ONE), Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        let tickets = self.tickets.load(Ordering::Acquire);
+        current(tickets) != next(tickets)
+    }
+}
+
+/// The raw recursive ticket lock type
+pub struct RawRecursiveTicketLock {
+    /// The actual ticket lock
+    tl: RawTicketLock,
+    /// The thread id, -1 for unused
+    tid: UnsafeCell<i32>,
+    /// The count of times this lock has been called
+    count: UnsafeCell<usize>,
+}
+
+unsafe impl Sync for RawRecursiveTicketLock {}
+unsafe impl Send for RawRecursiveTicketLock {}
+
+impl Default for RawRecursiveTicketLock {
+    /// Construct the recursive ticket lock with unlocked state
+    fn default() -> Self {
+        RawRecursiveTicketLock::new()
+    }
+}
+
+impl RawRecursiveTicketLock {
+    /// Construct the recursive ticket lock with unlocked state
+    pub const fn new() -> Self {
+        RawRecursiveTicketLock {
+            tl: RawTicketLock::new(),
+            tid: UnsafeCell::new(-1),
+            count: UnsafeCell::new(0),
+        }
+    }
+
+    /// Take the recursive ticket lock
+    pub fn lock(&self) {
+        let id = gettid();
+
+        unsafe {
+            if *self.tid.get() != id {
+                self.tl.lock();
+                *self.tid.get() = id;
+            }
+
+            *self.count.get() += 1;
+        }
+    }
+
+    /// Release the recursive ticket lock
+    pub fn unlock(&self) {
+        unsafe {
+            *self.count.get() -= 1;
+
+            if *self.count.get() == 0 {
+                *self.tid.get() = -1;
+                self.tl.unlock();
+            }
+        }
+    }
+
+    /// Try to take the recursive ticket lock
+    pub fn trylock(&self) -> bool {
+        let id = gettid();
+
+        unsafe {
+            if *self.tid.get() != id {
+                if !self.tl.trylock() {
+                    return false;
+                }
+                *self.tid.get() = id;
+            }
+            *self.count.get() += 1;
+        }
+
+        true
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.tl.is_locked()
+    }
+}
+
+/// A ticket lock protecting a `T`
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use dpdk::core::ticketlock::TicketLock;
+///
+/// let lk = Arc::new(TicketLock::new(0));
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let lk = lk.clone();
+///         thread::spawn(move || *lk.lock() += 1)
+///     })
+///     .collect();
+///
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+///
+/// assert_eq!(*lk.lock(), 4);
+/// ```
+pub struct TicketLock<T: ?Sized> {
+    raw: RawTicketLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    /// Construct the ticket lock with unlocked state
+    pub const fn new(data: T) -> Self {
+        TicketLock {
+            raw: RawTicketLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the ticket lock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    /// Take the ticket lock, released when the guard is dropped
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        self.raw.lock();
+        TicketLockGuard::new(self)
+    }
+
+    /// Try to take the ticket lock
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        if self.raw.trylock() {
+            Some(TicketLockGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw ticket lock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &RawTicketLock {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for TicketLock<T> {
+    fn default() -> Self {
+        TicketLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TicketLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("TicketLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("TicketLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// The RAII guard of a [`TicketLock`], releasing it on drop.
+///
+/// [`TicketLock`]: struct.TicketLock.html
+pub struct TicketLockGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for TicketLockGuard<'_, T> {}
+
+impl<'a, T: ?Sized> TicketLockGuard<'a, T> {
+    fn new(lock: &'a TicketLock<T>) -> Self {
+        TicketLockGuard {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+    }
+}
+
+/// A recursive ticket lock protecting a `T`
+///
+/// The lock may be taken again by the thread holding it, so the guards only
+/// give shared access to the data. Use a `Cell` or `RefCell` to mutate it.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use dpdk::core::ticketlock::RecursiveTicketLock;
+///
+/// let lk = RecursiveTicketLock::new(Cell::new(0));
+///
+/// let outer = lk.lock();
+/// {
+///     let inner = lk.lock();
+///     inner.set(inner.get() + 1);
+/// }
+/// assert_eq!(outer.get(), 1);
+/// ```
+pub struct RecursiveTicketLock<T: ?Sized> {
+    raw: RawRecursiveTicketLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for RecursiveTicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RecursiveTicketLock<T> {}
+
+impl<T> RecursiveTicketLock<T> {
+    /// Construct the recursive ticket lock with unlocked state
+    pub const fn new(data: T) -> Self {
+        RecursiveTicketLock {
+            raw: RawRecursiveTicketLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the recursive ticket lock, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RecursiveTicketLock<T> {
+    /// Take the recursive ticket lock, released when the guard is dropped
+    pub fn lock(&self) -> RecursiveTicketLockGuard<'_, T> {
+        self.raw.lock();
+        RecursiveTicketLockGuard::new(self)
+    }
+
+    /// Try to take the recursive ticket lock
+    pub fn try_lock(&self) -> Option<RecursiveTicketLockGuard<'_, T>> {
+        if self.raw.trylock() {
+            Some(RecursiveTicketLockGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Test if the lock is taked
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Access the data without locking, the borrow guarantees exclusivity
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The underlying raw recursive ticket lock
+    ///
+    /// # Safety
+    ///
+    /// Taking or releasing it bypasses the guards.
+    pub unsafe fn raw(&self) -> &RawRecursiveTicketLock {
+        &self.raw
+    }
+}
+
+impl<T: Default> Default for RecursiveTicketLock<T> {
+    fn default() -> Self {
+        RecursiveTicketLock::new(T::default())
+    }
+}
+
+/// The RAII guard of a [`RecursiveTicketLock`], releasing it on drop.
+///
+/// [`RecursiveTicketLock`]: struct.RecursiveTicketLock.html
+pub struct RecursiveTicketLockGuard<'a, T: ?Sized> {
+    lock: &'a RecursiveTicketLock<T>,
+    // released on the thread which took it, as the owner is a thread id
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RecursiveTicketLockGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RecursiveTicketLockGuard<'a, T> {
+    fn new(lock: &'a RecursiveTicketLock<T>) -> Self {
+        RecursiveTicketLockGuard {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RecursiveTicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RecursiveTicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::lcore;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    #[should_panic]
+    fn ticketlock_drop_when_locked() {
+        let lk = RawTicketLock::default();
+
+        lk.lock();
+    }
+
+    #[test]
+    fn ticketlock_trylock() {
+        let lk = RawTicketLock::default();
+        assert!(!lk.is_locked());
+
+        assert!(lk.trylock());
+        assert!(lk.is_locked());
+        assert!(!lk.trylock());
+
+        lk.unlock();
+        assert!(!lk.is_locked());
+    }
+
+    #[test]
+    fn ticketlock_wraparound() {
+        let lk = RawTicketLock::default();
+
+        for _ in 0..(1 << 17) {
+            lk.lock();
+            lk.unlock();
+        }
+        assert!(lk.trylock());
+        lk.unlock();
+    }
+
+    #[test]
+    fn recursive_ticketlock_lock_unlock() {
+        let lk = RecursiveTicketLock::new(1);
+
+        let outer = lk.lock();
+        let inner = lk.try_lock().unwrap();
+        assert_eq!(*outer + *inner, 2);
+
+        drop(outer);
+        assert!(lk.is_locked());
+        drop(inner);
+        assert!(!lk.is_locked());
+    }
+
+    // Every lcore yields its cpu while holding the lock, so that the others
+    // queue up behind it: a fair lock then hands it over round-robin.
+    #[test]
+    fn ticketlock_fairness() {
+        const NLCORE: usize = 4;
+        const NACQUIRE: usize = 100 * NLCORE;
+
+        let ncpu = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } as usize;
+        let lk = Arc::new(TicketLock::new(0usize));
+        let ready = Arc::new(AtomicUsize::new(0));
+
+        let lcs: Vec<_> = (0..NLCORE)
+            .map(|i| lcore::Builder::new().affinity(&[i % ncpu]).spawn::<usize>().unwrap())
+            .collect();
+
+        let tasks: Vec<_> = lcs
+            .iter()
+            .map(|lc| {
+                let lk = lk.clone();
+                let ready = ready.clone();
+
+                lc.launch(move || {
+                    ready.fetch_add(1, Ordering::AcqRel);
+                    while ready.load(Ordering::Acquire) != NLCORE {
+                        spin_loop();
+                    }
+
+                    let mut mine = 0;
+                    loop {
+                        let mut total = lk.lock();
+                        if *total == NACQUIRE {
+                            return mine;
+                        }
+                        *total += 1;
+                        mine += 1;
+                        thread::yield_now();
+                    }
+                })
+                .unwrap()
+            })
+            .collect();
+
+        let counts: Vec<_> = tasks.iter().map(|t| t.wait().unwrap()).collect();
+
+        assert_eq!(counts.iter().sum::<usize>(), NACQUIRE);
+
+        let spread = counts.iter().max().unwrap() - counts.iter().min().unwrap();
+        assert!(spread <= NACQUIRE / NLCORE / 10, "unfair acquisitions: {:?}", counts);
+    }
+}
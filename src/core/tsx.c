@@ -13,8 +13,8 @@
 #define RTE_XABORT_NESTED       (1 << 5)
 #define RTE_XABORT_CODE(x)      (((x) >> 24) & 0xff)
 
-#define RTE_RTM_MAX_RETRIES (20)
 #define RTE_XABORT_LOCK_BUSY (0xff)
+#define RTE_XABORT_LOCK_UPGRADE (0xfe)
 
 #ifndef likely
 #define likely(x) __builtin_expect(!!(x), 1)
@@ -43,6 +43,12 @@ void rte_xend(void)
     asm volatile(".byte 0xc6,0xf8,%P0" :: "i" (status) : "memory"); \
 } while (0)
 
+/* abort an elided read lock being upgraded, it is retaken for real */
+void rte_xabort_upgrade(void)
+{
+    rte_xabort(RTE_XABORT_LOCK_UPGRADE);
+}
+
 static __attribute__((__always_inline__)) inline
 int rte_xtest(void)
 {
@@ -68,30 +74,97 @@ rte_rdtsc(void)
     return tsc.tsc_64;
 }
 
-int rte_try_tm(int32_t* lock)
+struct rte_tm_policy {
+    uint32_t max_retries;   /* transactions tried before taking the lock */
+    uint32_t backoff_base;  /* random pauses before the first retry */
+    uint32_t backoff_max;   /* pauses cap, doubling on each retry */
+};
+
+/* written by its thread only, read concurrently */
+struct rte_tm_stats {
+    uint64_t commits;
+    uint64_t aborts;
+    uint64_t conflict;
+    uint64_t capacity;
+    uint64_t lock_busy;
+    uint64_t explicit_;
+    uint64_t nested;
+    uint64_t debug;
+    uint64_t fallbacks;
+};
+
+#define RTE_TM_STAT_INC(s, f) do { \
+    if (s) \
+        __atomic_store_n(&(s)->f, (s)->f + 1, __ATOMIC_RELAXED); \
+} while (0)
+
+static void rte_tm_count_abort(struct rte_tm_stats *stats, unsigned int status)
+{
+    RTE_TM_STAT_INC(stats, aborts);
+
+    if (status & RTE_XABORT_CONFLICT)
+        RTE_TM_STAT_INC(stats, conflict);
+    if (status & RTE_XABORT_CAPACITY)
+        RTE_TM_STAT_INC(stats, capacity);
+    if (status & RTE_XABORT_EXPLICIT) {
+        if (RTE_XABORT_CODE(status) == RTE_XABORT_LOCK_BUSY)
+            RTE_TM_STAT_INC(stats, lock_busy);
+        else
+            RTE_TM_STAT_INC(stats, explicit_);
+    }
+    if (status & RTE_XABORT_NESTED)
+        RTE_TM_STAT_INC(stats, nested);
+    if (status & RTE_XABORT_DEBUG)
+        RTE_TM_STAT_INC(stats, debug);
+}
+
+/*
+ * is_locked tests the lock within the transaction, to abort it when the lock
+ * is taken for real. A try does not wait for the lock to be released.
+ */
+int rte_try_tm(const void* lock, int (*is_locked)(const void*), int is_try,
+               const struct rte_tm_policy* policy, struct rte_tm_stats* stats)
 {
-    int retries = RTE_RTM_MAX_RETRIES;
+    uint32_t try_count;
 
-    while (likely(retries--)) {
+    for (try_count = 0; likely(try_count < policy->max_retries); ++try_count) {
         const unsigned int status = rte_xbegin();
         if (likely(RTE_XBEGIN_STARTED == status)) {
-            if (unlikely(*lock)) {
+            if (unlikely(is_locked(lock))) {
                 rte_xabort(RTE_XABORT_LOCK_BUSY);
             }
             return 1;
         }
 
-        while (*lock) {
+        rte_tm_count_abort(stats, status);
+
+        if ((status & RTE_XABORT_EXPLICIT) &&
+            (RTE_XABORT_CODE(status) == RTE_XABORT_LOCK_UPGRADE)) {
+            break;
+        }
+
+        if (is_try && is_locked(lock)) {
+            break;
+        }
+
+        while (is_locked(lock)) {
             __builtin_ia32_pause();
         }
 
         if ((status & RTE_XABORT_CONFLICT) ||
             ((status & RTE_XABORT_EXPLICIT) &&
              (RTE_XABORT_CODE(status) == RTE_XABORT_LOCK_BUSY))) {
-            const int try_count = RTE_RTM_MAX_RETRIES - retries;
-            int pause_count = (rte_rdtsc() & 0x7) | 1;
-            pause_count <<= try_count;
-            for (int i = 0; i < pause_count; ++i) {
+            uint64_t pause_count = 0;
+
+            if (policy->backoff_base) {
+                pause_count = rte_rdtsc() % policy->backoff_base + 1;
+                pause_count = try_count < 32 ? pause_count << try_count
+                                             : policy->backoff_max;
+                if (pause_count > policy->backoff_max)
+                    pause_count = policy->backoff_max;
+            }
+
+            for (uint64_t i = 0; i < pause_count; ++i) {
                 __builtin_ia32_pause();
             }
             continue;
@@ -101,6 +174,7 @@ int rte_try_tm(int32_t* lock)
             break;
         }
     }
-    
+
+    RTE_TM_STAT_INC(stats, fallbacks);
     return 0;
 }
@@ -0,0 +1,456 @@
+//! Lock elision with Intel TSX.
+//!
+//! With the `tsx` feature, the [`Elided`] locks first try to run their
+//! critical section as a hardware transaction (RTM), only taking the lock for
+//! real when the transaction keeps aborting. The spinlocks and rwlocks owning
+//! their data are elided.
+//!
+//! `xbegin` faults on a CPU without RTM, and always aborts when TSX is
+//! disabled by microcode, so elision is only attempted when the CPU reports
+//! a working RTM at runtime. Otherwise the locks take the plain path.
+//!
+//! # Example
+//! ```
+//! use dpdk::core::tsx;
+//!
+//! if tsx::elision_enabled() {
+//!     println!("locks are elided");
+//! }
+//!
+//! assert!(!tsx::elision_enabled() || tsx::cpu_has_rtm());
+//! ```
+//!
+//! The transactions tried before taking a lock, and the backoff in between,
+//! are set by a global [`Policy`]. Each thread counts its commits and aborts
+//! by reason, summed up by [`stats`], to tell if elision pays off:
+//!
+//! ```
+//! use dpdk::core::{spinlock::SpinLock, tsx};
+//!
+//! tsx::set_policy(tsx::Policy { max_retries: 5, ..Default::default() });
+//!
+//! let lk = SpinLock::new(0);
+//! let before = tsx::stats();
+//! for _ in 0..100 {
+//!     *lk.lock() += 1;
+//! }
+//! let delta = tsx::stats() - before;
+//!
+//! println!("{} commits, {} aborts ({} conflicts)", delta.commits, delta.aborts, delta.conflict);
+//! # tsx::set_policy(tsx::Policy::default());
+//! ```
+//!
+//! [`Elided`]: struct.Elided.html
+//! [`Policy`]: struct.Policy.html
+//! [`stats`]: fn.stats.html
+
+use super::lock::{RawLock, RawSharedLock};
+use std::ops::Sub;
+use std::ptr;
+use std::arch::x86_64::__cpuid_count;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+extern "C" {
+    fn rte_try_tm(
+        lock: *const c_void,
+        is_locked: extern "C" fn(*const c_void) -> c_int,
+        is_try: c_int,
+        policy: *const Policy,
+        stats: *const Counters,
+    ) -> c_int;
+    fn rte_xend();
+    fn rte_xabort_upgrade();
+}
+
+// 0 for unknown, 1 for absent, 2 for present
+static RTM: AtomicU8 = AtomicU8::new(0);
+
+/// Test if the CPU supports the RTM instructions, and does not force them to
+/// abort.
+///
+/// The CPUID lookup is done once and cached.
+pub fn cpu_has_rtm() -> bool {
+    match RTM.load(Ordering::Relaxed) {
+        0 => {
+            // CPUID.(EAX=07H, ECX=0H):EBX.RTM[bit 11]
+            // CPUID.(EAX=07H, ECX=0H):EDX.RTM_ALWAYS_ABORT[bit 11]
+            let max_leaf = __cpuid_count(0, 0).eax;
+            let present = max_leaf >= 7 && {
+                let leaf = __cpuid_count(7, 0);
+                leaf.ebx & (1 << 11) != 0 && leaf.edx & (1 << 11) == 0
+            };
+
+            RTM.store(if present { 2 } else { 1 }, Ordering::Relaxed);
+            present
+        }
+        v => v == 2,
+    }
+}
+
+/// Test if the locks are elided: built with the `tsx` feature, and running on
+/// a CPU with RTM.
+#[inline]
+pub fn elision_enabled() -> bool {
+    cfg!(feature = "tsx") && cpu_has_rtm()
+}
+
+/// A lock elided with TSX
+///
+/// Any [`RawLock`] gains elision, which is attempted when
+/// [`elision_enabled`], the inner lock being taken for real otherwise, or
+/// when the transactions keep aborting. The spinlocks and rwlocks owning their
+/// data are elided this way.
+///
+/// A transaction must end on the thread which began it: a held `Elided` lock
+/// must be released by the thread which took it.
+///
+/// # Examples
+///
+/// ```
+/// use dpdk::core::lock::RawLock;
+/// use dpdk::core::ticketlock::RawTicketLock;
+/// use dpdk::core::tsx::Elided;
+///
+/// let lk = Elided::new(RawTicketLock::new());
+/// let mut val = 0;
+///
+/// lk.lock();
+/// val += 1;
+/// unsafe { lk.unlock() };
+///
+/// assert_eq!(val, 1);
+/// ```
+///
+/// [`RawLock`]: ../lock/trait.RawLock.html
+/// [`elision_enabled`]: fn.elision_enabled.html
+#[derive(Debug, Default)]
+pub struct Elided<L> {
+    inner: L,
+}
+
+impl<L> Elided<L> {
+    /// Construct the elided lock over `inner`
+    pub const fn new(inner: L) -> Self {
+        Elided { inner }
+    }
+
+    /// The inner lock
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// Consumes the elided lock, returning the inner one
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+}
+
+impl<L: RawLock> Elided<L> {
+    /// Test if the lock held by the caller is elided, i.e. the caller runs in
+    /// a transaction rather than holding the inner lock
+    pub fn is_elided(&self) -> bool {
+        elision_enabled() && !self.inner.is_locked()
+    }
+
+    // Try to run in a transaction, returns true when it began.
+    #[inline]
+    fn elide(&self, is_try: bool) -> bool {
+        if !elision_enabled() {
+            return false;
+        }
+
+        extern "C" fn is_locked<L: RawLock>(lock: *const c_void) -> c_int {
+            unsafe { (*(lock as *const L)).is_locked() as c_int }
+        }
+
+        let policy = policy();
+        let stats = LOCAL.try_with(|local| Arc::as_ptr(&local.0)).unwrap_or(ptr::null());
+        let lock = &self.inner as *const L as *const c_void;
+
+        unsafe { rte_try_tm(lock, is_locked::<L>, is_try as c_int, &policy, stats) == 1 }
+    }
+
+    // Run in a transaction as `lock` does, returns false if the inner lock
+    // must be taken instead.
+    #[cfg(feature = "lock_stat")]
+    #[inline]
+    pub(crate) fn elide_lock(&self) -> bool {
+        self.elide(false)
+    }
+
+    // End the transaction begun by `elide`.
+    #[inline]
+    fn xend(&self) {
+        unsafe { rte_xend() };
+
+        let _ = LOCAL.try_with(|local| local.0.commits.fetch_add(1, Ordering::Relaxed));
+    }
+}
+
+unsafe impl<L: RawLock> RawLock for Elided<L> {
+    fn lock(&self) {
+        if !self.elide(false) {
+            self.inner.lock();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.elide(true) || self.inner.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        if self.is_elided() {
+            self.xend();
+        } else {
+            self.inner.unlock();
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+unsafe impl<L: RawSharedLock> RawSharedLock for Elided<L> {
+    fn lock_shared(&self) {
+        if !self.elide(false) {
+            self.inner.lock_shared();
+        }
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.elide(true) || self.inner.try_lock_shared()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        if self.is_elided() {
+            self.xend();
+        } else {
+            self.inner.unlock_shared();
+        }
+    }
+}
+
+/// Abort the transaction of an elided read lock being upgraded, which is then
+/// taken for real.
+///
+/// # Safety
+///
+/// Must be in a transaction begun by an `Elided` lock, never returns.
+#[inline]
+pub(crate) unsafe fn xabort_upgrade() {
+    rte_xabort_upgrade();
+}
+
+/// The retry policy of the elided locks
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    /// The transactions tried before taking the lock for real
+    pub max_retries: u32,
+    /// Up to this many `pause`s, randomly, before retrying an aborted
+    /// transaction, 0 for no backoff
+    pub backoff_base: u32,
+    /// The backoff doubles on each retry, up to this many `pause`s
+    pub backoff_max: u32,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            max_retries: 20,
+            backoff_base: 8,
+            backoff_max: u32::MAX,
+        }
+    }
+}
+
+static MAX_RETRIES: AtomicU32 = AtomicU32::new(20);
+static BACKOFF_BASE: AtomicU32 = AtomicU32::new(8);
+static BACKOFF_MAX: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Set the retry policy of the elided locks, for all the threads
+pub fn set_policy(policy: Policy) {
+    MAX_RETRIES.store(policy.max_retries, Ordering::Relaxed);
+    BACKOFF_BASE.store(policy.backoff_base, Ordering::Relaxed);
+    BACKOFF_MAX.store(policy.backoff_max, Ordering::Relaxed);
+}
+
+/// The retry policy of the elided locks
+pub fn policy() -> Policy {
+    Policy {
+        max_retries: MAX_RETRIES.load(Ordering::Relaxed),
+        backoff_base: BACKOFF_BASE.load(Ordering::Relaxed),
+        backoff_max: BACKOFF_MAX.load(Ordering::Relaxed),
+    }
+}
+
+/// The transaction counters, an abort may count for several reasons
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Committed transactions
+    pub commits: u64,
+    /// Aborted transactions
+    pub aborts: u64,
+    /// Aborts by a memory conflict with another core
+    pub conflict: u64,
+    /// Aborts by overflowing the transactional buffers
+    pub capacity: u64,
+    /// Aborts by finding the lock taken
+    pub lock_busy: u64,
+    /// Aborts by another `xabort`, e.g. upgrading an elided read lock
+    pub explicit: u64,
+    /// Aborts in a nested transaction
+    pub nested: u64,
+    /// Aborts by a debug breakpoint
+    pub debug: u64,
+    /// Locks taken for real after giving up on the transactions
+    pub fallbacks: u64,
+}
+
+impl Sub for Stats {
+    type Output = Stats;
+
+    fn sub(self, rhs: Stats) -> Stats {
+        Stats {
+            commits: self.commits.wrapping_sub(rhs.commits),
+            aborts: self.aborts.wrapping_sub(rhs.aborts),
+            conflict: self.conflict.wrapping_sub(rhs.conflict),
+            capacity: self.capacity.wrapping_sub(rhs.capacity),
+            lock_busy: self.lock_busy.wrapping_sub(rhs.lock_busy),
+            explicit: self.explicit.wrapping_sub(rhs.explicit),
+            nested: self.nested.wrapping_sub(rhs.nested),
+            debug: self.debug.wrapping_sub(rhs.debug),
+            fallbacks: self.fallbacks.wrapping_sub(rhs.fallbacks),
+        }
+    }
+}
+
+/// The transaction counters of all the threads, the exited ones included
+pub fn stats() -> Stats {
+    let mut total = *RETIRED.lock().unwrap();
+
+    for counters in THREADS.lock().unwrap().iter() {
+        counters.add_to(&mut total);
+    }
+
+    total
+}
+
+// The counters of a thread, written by it only, laid out as `rte_tm_stats`.
+#[repr(C)]
+#[derive(Default)]
+struct Counters {
+    commits: AtomicU64,
+    aborts: AtomicU64,
+    conflict: AtomicU64,
+    capacity: AtomicU64,
+    lock_busy: AtomicU64,
+    explicit: AtomicU64,
+    nested: AtomicU64,
+    debug: AtomicU64,
+    fallbacks: AtomicU64,
+}
+
+impl Counters {
+    fn add_to(&self, total: &mut Stats) {
+        total.commits += self.commits.load(Ordering::Relaxed);
+        total.aborts += self.aborts.load(Ordering::Relaxed);
+        total.conflict += self.conflict.load(Ordering::Relaxed);
+        total.capacity += self.capacity.load(Ordering::Relaxed);
+        total.lock_busy += self.lock_busy.load(Ordering::Relaxed);
+        total.explicit += self.explicit.load(Ordering::Relaxed);
+        total.nested += self.nested.load(Ordering::Relaxed);
+        total.debug += self.debug.load(Ordering::Relaxed);
+        total.fallbacks += self.fallbacks.load(Ordering::Relaxed);
+    }
+}
+
+static THREADS: Mutex<Vec<Arc<Counters>>> = Mutex::new(Vec::new());
+static RETIRED: Mutex<Stats> = Mutex::new(Stats {
+    commits: 0,
+    aborts: 0,
+    conflict: 0,
+    capacity: 0,
+    lock_busy: 0,
+    explicit: 0,
+    nested: 0,
+    debug: 0,
+    fallbacks: 0,
+});
+
+// Registers the counters of the thread, folded into `RETIRED` on exit.
+struct Local(Arc<Counters>);
+
+impl Local {
+    fn register() -> Self {
+        let counters = Arc::new(Counters::default());
+        THREADS.lock().unwrap().push(counters.clone());
+
+        Local(counters)
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        let mut retired = RETIRED.lock().unwrap();
+        self.0.add_to(&mut retired);
+
+        THREADS.lock().unwrap().retain(|c| !Arc::ptr_eq(c, &self.0));
+    }
+}
+
+thread_local! {
+    static LOCAL: Local = Local::register();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::spinlock::SpinLock;
+
+    #[test]
+    fn rtm_detection() {
+        // cached, and consistent with the feature
+        assert_eq!(cpu_has_rtm(), cpu_has_rtm());
+        assert_eq!(elision_enabled(), cfg!(feature = "tsx") && cpu_has_rtm());
+
+        // whichever path is picked, the locks work
+        let lk = SpinLock::new(0);
+        *lk.lock() += 1;
+        assert_eq!(*lk.lock(), 1);
+    }
+
+    #[test]
+    fn policy_and_stats() {
+        let custom = Policy {
+            max_retries: 3,
+            backoff_base: 0,
+            backoff_max: 16,
+        };
+        set_policy(custom);
+        assert_eq!(policy(), custom);
+        set_policy(Policy::default());
+        assert_eq!(policy(), Policy::default());
+
+        let before = stats();
+        std::thread::spawn(|| {
+            let lk = SpinLock::new(0);
+            for _ in 0..100 {
+                *lk.lock() += 1;
+            }
+        })
+        .join()
+        .unwrap();
+        let delta = stats() - before;
+
+        // the counters of the exited thread are kept
+        if elision_enabled() {
+            assert!(delta.commits + delta.fallbacks >= 100);
+        } else {
+            assert_eq!(delta, Stats::default());
+        }
+    }
+}
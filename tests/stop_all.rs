@@ -0,0 +1,33 @@
+// request_stop_all cancels the tokens of the whole process, so it runs apart
+// from the unit tests.
+
+use dpdk::core::lcore::{self, Outcome};
+
+#[test]
+fn cancel_stop_all() {
+    let lcs: Vec<_> = (0..2).map(|_| lcore::spawn::<u64>()).collect();
+
+    let tasks: Vec<_> = lcs
+        .iter()
+        .map(|lc| {
+            lc.launch_cancellable(|token| {
+                let mut polls = 0;
+                while !token.is_cancelled() {
+                    polls += 1;
+                }
+                polls
+            })
+            .unwrap()
+        })
+        .collect();
+
+    lcore::request_stop_all();
+
+    for task in &tasks {
+        assert!(task.outcome().unwrap().is_cancelled());
+    }
+
+    // the stop only applies to the tasks launched before it
+    let task = lcs[0].launch_cancellable(|token| token.is_cancelled() as u64).unwrap();
+    assert_eq!(task.outcome().unwrap(), Outcome::Completed(0));
+}